@@ -19,17 +19,29 @@
 //! Memory allocation utilities.
 use super::primitives;
 use super::common;
+use super::block;
+use super::object;
 
 use enumflags2::BitFlags;
 
 pub use primitives::Protection;
 pub use primitives::MMapError;
 pub use primitives::Result;
+pub use primitives::Advice;
 
 use common::Address;
 use common::MiB;
+use block::BlockDescriptor;
+use object::{Object, ObjectDescriptor};
 
+use core::cell::Cell;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::marker::PhantomData;
+use core::ptr::NonNull;
 use core::iter::Map;
+use core::ops::Range;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
 
 /// Memory chunk.
 ///
@@ -44,29 +56,444 @@ use core::iter::Map;
 /// // memory is deallocated here
 /// # Ok::<(), MMapError>(())
 /// ```
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug)]
 pub struct MemoryChunk {
-    data: *mut u8,
+    data: NonNull<u8>,
     size: usize,
+    /// The size asked for by the caller, which `size` may exceed after rounding up.
+    requested: usize,
+    /// The address returned by the allocator, which is the one to free: `data` is offset from it
+    /// past the guard pages, if any.
+    base: *mut u8,
+    /// Size of the whole mapping starting at `base`, including guard pages and padding.
+    mapped_size: usize,
+    /// Whether the chunk is locked into RAM, so that it is unlocked on drop. Atomic, because
+    /// `lock` and `unlock` only borrow the chunk immutably, and the chunk is `Sync`.
+    locked: AtomicBool,
+    /// Whether the chunk maps a file, so that it is synchronized with the file on drop.
+    file_backed: bool,
 }
 
+// SAFETY: a `MemoryChunk` uniquely owns its mapping, like a `Box<[u8]>`, so moving it to another
+// thread is fine. Shared references only read the chunk, or go through the system calls and the
+// atomic `locked` flag; writing to the memory requires `&mut self` (see `AsMut`).
+unsafe impl Send for MemoryChunk {}
+unsafe impl Sync for MemoryChunk {}
+
+impl PartialEq for MemoryChunk {
+    fn eq(&self, other: &Self) -> bool {
+        self.data == other.data && self.size == other.size && self.requested == other.requested
+            && self.base == other.base
+            && self.mapped_size == other.mapped_size
+            && self.locked.load(Ordering::Relaxed) == other.locked.load(Ordering::Relaxed)
+            && self.file_backed == other.file_backed
+    }
+}
+
+impl Eq for MemoryChunk {}
+
 impl MemoryChunk {
     /// Allocate a memory chunk with the provided `alignment`, `size`, and `protection`.
     pub fn new(alignment: usize, size: usize, protection: BitFlags<Protection>) -> Result<Self> {
+        let data = unsafe {
+            primitives::aligned_allocate_chunk(
+                alignment, size, protection, BitFlags::empty())? as *mut u8
+        };
+        Self::from_raw(data, size)
+    }
+
+    /// Allocate a memory chunk like [`new`](#method.new), but round `size` up to a multiple of
+    /// `alignment` first, instead of leaving it to the caller.
+    ///
+    /// `size()` reports the rounded size, and `requested_size()` the original one.
+    ///
+    /// ```
+    /// use memory_manager::allocate::{MemoryChunk, Protection};
+    /// use memory_manager::primitives::get_minimum_alignment;
+    /// # use memory_manager::primitives::MMapError;
+    /// let a = get_minimum_alignment()?;
+    /// let chunk = MemoryChunk::new_rounded(a, a + 1, Protection::Read | Protection::Write)?;
+    /// assert_eq!(chunk.size(), 2 * a);
+    /// assert_eq!(chunk.requested_size(), a + 1);
+    /// # Ok::<(), MMapError>(())
+    /// ```
+    pub fn new_rounded(
+        alignment: usize, size: usize, protection: BitFlags<Protection>) -> Result<Self> {
+        if !alignment.is_power_of_two() { return Err(MMapError::InvalidArguments); }
+        if size > usize::MAX - (alignment - 1) { return Err(MMapError::LengthOverflow); }
+        let mut chunk = Self::new(alignment, common::align_up(size, alignment), protection)?;
+        chunk.requested = size;
+        Ok(chunk)
+    }
+
+    /// Take ownership of the `size` bytes just allocated at `data`, without guard pages.
+    fn from_raw(data: *mut u8, size: usize) -> Result<Self> {
+        // the allocation functions never return null on success
+        let data = NonNull::new(data).ok_or(MMapError::NoError)?;
+        let base = data.as_ptr();
         Ok(MemoryChunk {
-            data: unsafe {
-                primitives::aligned_allocate_chunk(
-                    alignment, size, protection)? as *mut u8
-            },
-            size,
+            data, size, requested: size, base, mapped_size: size,
+            locked: AtomicBool::new(false), file_backed: false,
         })
     }
 
+    /// A chunk of no memory at all, for mega-blocks in list tests.
+    #[cfg(test)]
+    fn empty() -> Self {
+        let data = NonNull::dangling();
+        let base = data.as_ptr();
+        MemoryChunk {
+            data, size: 0, requested: 0, base, mapped_size: 0,
+            locked: AtomicBool::new(false), file_backed: false,
+        }
+    }
+
+    /// Allocate a memory chunk surrounded by inaccessible guard pages, so that out-of-bounds
+    /// accesses fault instead of silently corrupting memory.
+    ///
+    /// Only the guarded middle is exposed via `data()` and `size()`, which is aligned to
+    /// `alignment`. The `size` must be a multiple of `PAGE_SIZE`, otherwise this function fails
+    /// with `InvalidArguments`.
+    ///
+    /// ```
+    /// use memory_manager::allocate::{MemoryChunk, Protection};
+    /// use memory_manager::primitives::{get_minimum_alignment, get_page_size};
+    /// # use memory_manager::primitives::MMapError;
+    /// let a = get_minimum_alignment()?;
+    /// let size = get_page_size()? * 2;
+    /// let chunk = MemoryChunk::new_guarded(a, size, Protection::Read | Protection::Write)?;
+    /// assert_eq!(chunk.size(), size);
+    /// # Ok::<(), MMapError>(())
+    /// ```
+    pub fn new_guarded(
+        alignment: usize, size: usize, protection: BitFlags<Protection>) -> Result<Self> {
+        let page_size = primitives::get_page_size()?;
+        if size == 0 || size & (page_size - 1) != 0 { return Err(MMapError::InvalidArguments); }
+        let front_guard = alignment.max(page_size);
+        let mask = front_guard - 1;
+        let total = (front_guard + size + page_size + mask) & !mask;
+        let base = unsafe {
+            primitives::aligned_allocate_chunk(
+                front_guard, total, Protection::NONE, BitFlags::empty())? as *mut u8
+        };
+        let chunk = MemoryChunk {
+            data: unsafe { NonNull::new_unchecked(base.add(front_guard)) },
+            size,
+            requested: size,
+            base,
+            mapped_size: total,
+            locked: AtomicBool::new(false),
+            file_backed: false,
+        };
+        unsafe { primitives::protect(chunk.data.as_ptr() as _, size, protection)?; }
+        Ok(chunk)
+    }
+
+    /// (UNIX-specific) Map the first `size` bytes of `file` as a memory chunk aligned to
+    /// `alignment`, see [`aligned_map_file`](../primitives/fn.aligned_map_file.html).
+    ///
+    /// The mapping is shared, so writes through the chunk reach the file. The chunk is
+    /// synchronized with the file before being unmapped on drop.
+    #[cfg(all(unix, feature = "std"))]
+    pub fn map_file(
+        alignment: usize, size: usize, file: &std::fs::File,
+        protection: BitFlags<Protection>) -> Result<Self> {
+        Self::map_file_at(alignment, size, file, 0, protection, true)
+    }
+
+    /// Map `size` bytes of `file` from `offset` like [`map_file`](#method.map_file). A private
+    /// mapping is copy-on-write, writes through it never reach the file.
+    #[cfg(all(unix, feature = "std"))]
+    fn map_file_at(
+        alignment: usize, size: usize, file: &std::fs::File, offset: usize,
+        protection: BitFlags<Protection>, shared: bool) -> Result<Self> {
+        use std::os::unix::io::AsRawFd;
+        let data = unsafe {
+            primitives::aligned_map_file(
+                alignment, file.as_raw_fd(), offset, size, protection, shared)? as *mut u8
+        };
+        let mut chunk = Self::from_raw(data, size)?;
+        chunk.file_backed = true;
+        Ok(chunk)
+    }
+
+    /// Reserve a memory chunk of address space with the provided `alignment` and `size`, without
+    /// committing memory to it. The whole chunk is inaccessible until parts of it are committed.
+    ///
+    /// ```
+    /// use memory_manager::allocate::{MemoryChunk, Protection};
+    /// use memory_manager::primitives::{get_minimum_alignment, get_page_size};
+    /// # use memory_manager::primitives::MMapError;
+    /// let mut chunk = MemoryChunk::reserve(get_minimum_alignment()?, 16 * get_page_size()?)?;
+    /// unsafe { chunk.commit(0, get_page_size()?, Protection::Read | Protection::Write)? };
+    /// # Ok::<(), MMapError>(())
+    /// ```
+    pub fn reserve(alignment: usize, size: usize) -> Result<Self> {
+        let data = unsafe { primitives::aligned_reserve_chunk(alignment, size)? as *mut u8 };
+        Self::from_raw(data, size)
+    }
+
+    /// Commit `len` bytes at `offset` into this chunk, making them accessible with `protection`.
+    /// Both `offset` and `len` should be multiples of `PAGE_SIZE`; a range outside the chunk
+    /// fails with `InvalidArguments`.
+    ///
+    /// # Safety
+    ///
+    /// The chunk must come from [`reserve`](#method.reserve).
+    pub unsafe fn commit(
+        &mut self, offset: usize, len: usize, protection: BitFlags<Protection>) -> Result<()> {
+        self.check_range(offset, len)?;
+        primitives::commit(self.data.as_ptr().add(offset) as _, len, protection)
+    }
+
+    /// Decommit `len` bytes at `offset` into this chunk, releasing the memory but keeping the
+    /// address space reserved.
+    ///
+    /// # Safety
+    ///
+    /// The contents of the range are lost, and no reference into it may be used afterwards.
+    pub unsafe fn decommit(&mut self, offset: usize, len: usize) -> Result<()> {
+        self.check_range(offset, len)?;
+        primitives::decommit(self.data.as_ptr().add(offset) as _, len)
+    }
+
+    /// Write the pages in `range` of this chunk (all of it for `None`) back to the file it maps,
+    /// see [`map_file`](#method.map_file), blocking until done. This is a no-op for chunks not
+    /// mapping a file.
+    ///
+    /// Fails with `InvalidArguments` if `range` is not inside the chunk.
+    pub fn flush(&self, range: Option<Range<usize>>) -> Result<()> {
+        let range = range.unwrap_or(0..self.size);
+        if range.start > range.end { return Err(MMapError::InvalidArguments); }
+        self.check_range(range.start, range.end - range.start)?;
+        #[cfg(unix)]
+        if self.file_backed && !range.is_empty() {
+            let page_mask = primitives::get_page_size()? - 1;
+            let start = (self.data.as_ptr() as usize + range.start) & !page_mask;
+            let end = self.data.as_ptr() as usize + range.end;
+            unsafe { primitives::sync(start as _, end - start)?; }
+        }
+        Ok(())
+    }
+
+    fn check_range(&self, offset: usize, len: usize) -> Result<()> {
+        match offset.checked_add(len) {
+            Some(end) if end <= self.size => Ok(()),
+            _ => Err(MMapError::InvalidArguments),
+        }
+    }
+
     /// Pointer to the starting address of this chunk.
-    pub unsafe fn data(&self) -> Address<'_> { Address::from(self.data) }
+    pub unsafe fn data(&self) -> Address<'_> { Address::from(self.data.as_ptr()) }
 
     /// Length of this chunk.
     pub fn size(&self) -> usize { self.size }
+
+    /// Length originally requested for this chunk, see [`new_rounded`](#method.new_rounded).
+    /// This is the same as `size()` for chunks allocated otherwise.
+    pub fn requested_size(&self) -> usize { self.requested }
+
+    /// Whether this chunk has guard pages around it, see [`new_guarded`](#method.new_guarded).
+    fn is_guarded(&self) -> bool {
+        self.base != self.data.as_ptr() || self.mapped_size != self.size
+    }
+
+    /// Resize this chunk to `new_size` bytes, keeping the contents up to the smaller size.
+    ///
+    /// On Linux, this is done by `mremap`, which may move the chunk. Elsewhere, a new chunk is
+    /// allocated as `Read | Write`, the contents are copied, and the old chunk is freed. Either
+    /// way, the new chunk is only guaranteed to be aligned to `get_minimum_alignment()`.
+    ///
+    /// # Errors
+    ///
+    /// Fails with `InvalidArguments` for a chunk with guard pages or mapping a file, or for a
+    /// `new_size` of zero.
+    ///
+    /// # Safety
+    ///
+    /// The chunk may move, no reference into it may be used afterwards. Off Linux, the chunk must
+    /// be readable.
+    pub unsafe fn resize(&mut self, new_size: usize) -> Result<()> {
+        if new_size == 0 || self.is_guarded() || self.file_backed {
+            return Err(MMapError::InvalidArguments);
+        }
+        #[cfg(target_os = "linux")]
+        let data = primitives::remap(self.data.as_ptr() as _, self.size, new_size)? as *mut u8;
+        #[cfg(not(target_os = "linux"))]
+        let data = {
+            let alignment = primitives::get_minimum_alignment()?;
+            let rw = Protection::Read | Protection::Write;
+            let data = primitives::aligned_allocate_chunk(
+                alignment, new_size, rw, BitFlags::empty())? as *mut u8;
+            core::ptr::copy_nonoverlapping(self.data.as_ptr(), data, self.size.min(new_size));
+            primitives::deallocate_chunk(self.data.as_ptr() as _, self.size)?;
+            self.locked.store(false, Ordering::Relaxed);
+            data
+        };
+        // the allocation functions never return null on success
+        self.data = NonNull::new_unchecked(data);
+        self.size = new_size;
+        self.requested = new_size;
+        self.base = data;
+        self.mapped_size = new_size;
+        Ok(())
+    }
+
+    /// Split this chunk at `offset` into two adjacent chunks, each freeing its own range on drop.
+    /// Guard pages, if any, go with the half they are adjacent to.
+    ///
+    /// # Errors
+    ///
+    /// Fails with `InvalidArguments` if `offset` is not a multiple of `PAGE_SIZE` strictly inside
+    /// the chunk. On Windows, this function always fails with `InvalidArguments`: `VirtualFree`
+    /// can only release a whole allocation from its original base address.
+    pub fn split_at(self, offset: usize) -> Result<(MemoryChunk, MemoryChunk)> {
+        let page_size = primitives::get_page_size()?;
+        if cfg!(windows) || offset == 0 || offset >= self.size || offset & (page_size - 1) != 0 {
+            return Err(MMapError::InvalidArguments);
+        }
+        let middle = unsafe { NonNull::new_unchecked(self.data.as_ptr().add(offset)) };
+        let front = MemoryChunk {
+            data: self.data,
+            size: offset,
+            requested: offset,
+            base: self.base,
+            mapped_size: middle.as_ptr() as usize - self.base as usize,
+            locked: AtomicBool::new(self.locked.load(Ordering::Relaxed)),
+            file_backed: self.file_backed,
+        };
+        let back = MemoryChunk {
+            data: middle,
+            size: self.size - offset,
+            requested: self.size - offset,
+            base: middle.as_ptr(),
+            mapped_size: self.mapped_size - front.mapped_size,
+            locked: AtomicBool::new(self.locked.load(Ordering::Relaxed)),
+            file_backed: self.file_backed,
+        };
+        primitives::registry::register(back.base as usize);
+        core::mem::forget(self);
+        Ok((front, back))
+    }
+
+    /// Get the elements in `range` of this chunk, viewed as a slice of `T`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `range` is out of bounds of the `size / size_of::<T>()` elements in this chunk,
+    /// or if the start of the range is not properly aligned for `T`.
+    pub fn slice<T>(&self, range: Range<usize>) -> &[T] {
+        let start = self.element_ptr::<T>(&range);
+        unsafe { core::slice::from_raw_parts(start, range.end - range.start) }
+    }
+
+    /// Get the elements in `range` of this chunk, viewed as a mutable slice of `T`.
+    ///
+    /// # Panics
+    ///
+    /// See [`slice`](#method.slice).
+    pub fn slice_mut<T>(&mut self, range: Range<usize>) -> &mut [T] {
+        let start = self.element_ptr::<T>(&range);
+        unsafe { core::slice::from_raw_parts_mut(start, range.end - range.start) }
+    }
+
+    fn element_ptr<T>(&self, range: &Range<usize>) -> *mut T {
+        let len = self.size / core::mem::size_of::<T>();
+        assert!(range.start <= range.end,
+                "slice index starts at {} but ends at {}", range.start, range.end);
+        assert!(range.end <= len,
+                "range end index {} out of range for chunk of {} elements", range.end, len);
+        let offset = range.start * core::mem::size_of::<T>();
+        common::assert_aligned(unsafe { self.data.as_ptr().add(offset) })
+    }
+
+    /// Overwrite every byte of the chunk with `byte`. The chunk must be writable, otherwise the
+    /// access faults.
+    pub fn fill(&mut self, byte: u8) {
+        unsafe { core::ptr::write_bytes(self.data.as_ptr(), byte, self.size) }
+    }
+
+    /// Overwrite every byte of the chunk with zero, e.g. before reusing freed memory.
+    pub fn zero(&mut self) { self.fill(0) }
+
+    /// Hint the system about how the whole chunk will be accessed, see
+    /// [`Advice`](../primitives/enum.Advice.html).
+    ///
+    /// # Safety
+    ///
+    /// After `Advice::DontNeed`, the contents of the chunk are lost.
+    pub unsafe fn advise(&self, advice: Advice) -> Result<()> {
+        primitives::advise(self.data.as_ptr() as _, self.size, advice)
+    }
+
+    /// Change the protection of the whole chunk, e.g. to flip a JIT buffer from writable to
+    /// executable. Guard pages, if any, are left inaccessible.
+    ///
+    /// # Safety
+    ///
+    /// No reference into the chunk may be used against the new protection.
+    pub unsafe fn protect(&mut self, protection: BitFlags<Protection>) -> Result<()> {
+        primitives::protect(self.data.as_ptr() as _, self.size, protection)
+    }
+
+    /// Make the whole chunk read-only, e.g. to take a copy-on-write snapshot: writes fault from
+    /// now on, so that a handler can save the original contents before restoring write access
+    /// with [`protect`](#method.protect).
+    ///
+    /// # Safety
+    ///
+    /// No write to the chunk may happen until it is writable again, unless faults are handled.
+    pub unsafe fn make_readonly(&mut self) -> Result<()> {
+        self.protect(Protection::Read.into())
+    }
+
+    /// Lock the whole chunk into RAM, so that it is never swapped out, e.g. for key material.
+    /// The chunk is unlocked again on drop, if not already unlocked by [`unlock`](#method.unlock).
+    ///
+    /// # Errors
+    ///
+    /// Fails with `LockLimit` if the process may not lock that much memory: on UNIX-like systems,
+    /// see `RLIMIT_MEMLOCK`; on Windows, the minimum working set size of the process.
+    ///
+    /// ```
+    /// use memory_manager::allocate::{MemoryChunk, Protection};
+    /// use memory_manager::primitives::{get_minimum_alignment, get_page_size, MMapError};
+    /// let rw = Protection::Read | Protection::Write;
+    /// let chunk = MemoryChunk::new(get_minimum_alignment()?, get_page_size()?, rw)?;
+    /// match chunk.lock() {
+    ///     Ok(()) => chunk.unlock()?,
+    ///     Err(MMapError::LockLimit) => {}
+    ///     Err(e) => return Err(e),
+    /// }
+    /// # Ok::<(), MMapError>(())
+    /// ```
+    pub fn lock(&self) -> Result<()> {
+        unsafe { primitives::lock(self.data.as_ptr() as _, self.size)? };
+        self.locked.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Unlock the whole chunk, so that it may be swapped out again.
+    pub fn unlock(&self) -> Result<()> {
+        unsafe { primitives::unlock(self.data.as_ptr() as _, self.size)? };
+        self.locked.store(false, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Number of pages of this chunk currently backed by physical memory, as opposed to only
+    /// reserved, or not yet touched. Guard pages are not counted.
+    ///
+    /// ```
+    /// use memory_manager::allocate::MemoryChunk;
+    /// use memory_manager::primitives::{get_minimum_alignment, get_page_size};
+    /// # use memory_manager::primitives::MMapError;
+    /// let chunk = MemoryChunk::reserve(get_minimum_alignment()?, 16 * get_page_size()?)?;
+    /// assert_eq!(chunk.resident_pages()?, 0);
+    /// # Ok::<(), MMapError>(())
+    /// ```
+    pub fn resident_pages(&self) -> Result<usize> {
+        primitives::resident_pages(self.data.as_ptr() as _, self.size)
+    }
 }
 
 impl<T> AsRef<[T]> for MemoryChunk {
@@ -78,7 +505,7 @@ impl<T> AsRef<[T]> for MemoryChunk {
     fn as_ref(&self) -> &[T] {
         unsafe {
             core::ptr::slice_from_raw_parts(
-                self.data as _, self.size).as_ref().unwrap()
+                self.data.as_ptr() as _, self.size).as_ref().unwrap()
         }
     }
 }
@@ -92,15 +519,22 @@ impl<T> AsMut<[T]> for MemoryChunk {
     fn as_mut(&mut self) -> &mut [T] {
         unsafe {
             core::ptr::slice_from_raw_parts_mut(
-                self.data as _, self.size).as_mut().unwrap()
+                self.data.as_ptr() as _, self.size).as_mut().unwrap()
         }
     }
 }
 
 impl Drop for MemoryChunk {
     fn drop(&mut self) {
+        if self.mapped_size == 0 { return; }
+        if self.locked.load(Ordering::Relaxed) {
+            // best effort: unmapping the pages unlocks them anyway
+            let _ = self.unlock();
+        }
+        // best effort: the system writes the pages back eventually anyway
+        let _ = self.flush(None);
         unsafe {
-            primitives::deallocate_chunk(self.data as _, self.size)
+            primitives::deallocate_chunk(self.base as _, self.mapped_size)
                 .expect("failed to deallocate memory: ")
         }
     }
@@ -108,14 +542,36 @@ impl Drop for MemoryChunk {
 
 /// Mega-blocks: allocation units, we reserve `Block`s from `MegaBlock`s.
 ///
-/// Mega-blocks are managed in a global doubly-linked list.
+/// Mega-blocks are managed in doubly-linked lists, see [`MegaBlockList`].
+///
+/// [`MegaBlockList`]: struct.MegaBlockList.html
 pub struct MegaBlock {
-    /// The previous mega-block in the global list.
-    pub previous: MegaBlockList,
-    /// The next mega-block in the global list.
-    pub next: MegaBlockList,
+    /// The previous mega-block in the owning list, null at the front or if not in a list.
+    previous: *mut MegaBlock,
+    /// The next mega-block in the owning list, null at the back or if not in a list.
+    next: *mut MegaBlock,
     /// The allocated memory chunk for this mega-block.
     pub chunk: MemoryChunk,
+    /// The `BlockDescriptor`s of the blocks in `chunk`, in a mapping of their own: the chunk may
+    /// well be inaccessible or read-only, but `free` pointers are updated on every allocation.
+    table: MemoryChunk,
+    pins: Cell<usize>,
+    #[cfg(test)]
+    _probe: tests::DropProbe,
+}
+
+/// A pin on a mega-block, preventing [`release_empty`] from giving its memory back to the system
+/// and the collectors from moving or reclaiming its objects until dropped. See
+/// [`MegaBlock::pin`].
+///
+/// [`release_empty`]: struct.MegaBlockList.html#method.release_empty
+/// [`MegaBlock::pin`]: struct.MegaBlock.html#method.pin
+pub struct MegaBlockPin<'a>(&'a MegaBlock);
+
+impl<'a> Drop for MegaBlockPin<'a> {
+    fn drop(&mut self) {
+        self.0.pins.set(self.0.pins.get() - 1);
+    }
 }
 
 impl MegaBlock {
@@ -125,17 +581,617 @@ impl MegaBlock {
     /// Size of a `MegaBlock` in `Word`s (`usize`s).
     pub const SIZE_IN_WORDS: usize = Self::SIZE / core::mem::size_of::<usize>();
 
-    /// Constructor for `MegaBlock`.
+    /// Constructor for `MegaBlock`, of the default size `SIZE`.
     pub fn new(protection: BitFlags<Protection>) -> Result<Self> {
+        Self::with_size(Self::SIZE, protection)
+    }
+
+    /// Constructor for `MegaBlock` of a custom size, which must be a multiple of
+    /// `get_minimum_alignment()`, otherwise this function fails with `InvalidArguments`.
+    ///
+    /// The mega-block is aligned to its size if that is a power of 2, like the default ones.
+    pub fn with_size(size: usize, protection: BitFlags<Protection>) -> Result<Self> {
+        let minimum_alignment = primitives::get_minimum_alignment()?;
+        if size == 0 || size & (minimum_alignment - 1) != 0 {
+            return Err(MMapError::InvalidArguments);
+        }
+        let alignment = if size.is_power_of_two() { size } else { minimum_alignment };
+        Self::from_chunk(MemoryChunk::new(alignment, size, protection)?)
+    }
+
+    /// (UNIX-specific) Constructor for `MegaBlock` of the default size `SIZE`, backed by the file
+    /// at `path`, so that its contents survive the process. See
+    /// [`MemoryChunk::map_file`](struct.MemoryChunk.html#method.map_file).
+    ///
+    /// With `Protection::Write`, the file is created if missing, and extended to `SIZE` if
+    /// shorter. Otherwise, it must already be at least `SIZE` long, or this function fails with
+    /// `InvalidArguments`.
+    ///
+    /// The block descriptors are stored in the file as well, after the `SIZE` bytes of the
+    /// mega-block, so that the blocks keep their objects when the file is mapped again. Without
+    /// `Protection::Write`, they are mapped copy-on-write, and allocations never reach the file.
+    /// Pointers stored in the file are only meaningful if the file is mapped at the same address
+    /// again.
+    #[cfg(all(unix, feature = "std"))]
+    pub fn new_file_backed(
+        path: impl AsRef<std::path::Path>, protection: BitFlags<Protection>) -> Result<Self> {
+        let io_error = |e: std::io::Error| {
+            e.raw_os_error().map_or(MMapError::InvalidArguments, MMapError::from_errno)
+        };
+        let writable = protection.contains(Protection::Write);
+        let file = std::fs::OpenOptions::new()
+            .read(true).write(writable).create(writable)
+            .open(path).map_err(io_error)?;
+        let page_size = primitives::get_page_size()?;
+        let table_size = common::align_up(Self::table_size(Self::SIZE), page_size);
+        let file_size = (Self::SIZE + table_size) as u64;
+        if file.metadata().map_err(io_error)?.len() < file_size {
+            if !writable { return Err(MMapError::InvalidArguments); }
+            file.set_len(file_size).map_err(io_error)?;
+        }
+        let chunk = MemoryChunk::map_file(Self::SIZE, Self::SIZE, &file, protection)?;
+        let table = MemoryChunk::map_file_at(
+            page_size, table_size, &file, Self::SIZE,
+            Protection::Read | Protection::Write, writable)?;
+        Self::with_table(chunk, table)
+    }
+
+    /// Size of the descriptor table of a mega-block of `size` bytes.
+    fn table_size(size: usize) -> usize {
+        size / BlockDescriptor::SIZE * core::mem::size_of::<BlockDescriptor>()
+    }
+
+    /// Carve blocks out of `chunk`.
+    fn from_chunk(chunk: MemoryChunk) -> Result<Self> {
+        let table = MemoryChunk::new_rounded(
+            primitives::get_minimum_alignment()?,
+            Self::table_size(chunk.size()),
+            Protection::Read | Protection::Write)?;
+        Self::with_table(chunk, table)
+    }
+
+    /// Carve blocks out of `chunk`, with their descriptors in `table`.
+    ///
+    /// Descriptors already in `table` (e.g. from an earlier mapping of a file) keep their used
+    /// bytes, rebased onto `chunk`. A zeroed table makes all the blocks empty.
+    fn with_table(chunk: MemoryChunk, table: MemoryChunk) -> Result<Self> {
+        let data = chunk.data.as_ptr();
+        let count = chunk.size() / BlockDescriptor::SIZE;
+        let descriptors = table.data.as_ptr() as *mut BlockDescriptor;
+        for i in 0..count {
+            let start = unsafe { data.add(i * BlockDescriptor::SIZE) };
+            let old = unsafe { descriptors.add(i).read() };
+            let used = (old.free as usize).wrapping_sub(old.start as usize);
+            if used > BlockDescriptor::SIZE { return Err(MMapError::InvalidArguments); }
+            let mut block = BlockDescriptor::new(start);
+            block.free = unsafe { start.add(used) };
+            unsafe { descriptors.add(i).write(block); }
+        }
         Ok(MegaBlock {
-            previous: MegaBlockList::new(),
-            next: MegaBlockList::new(),
-            chunk: MemoryChunk::new(Self::SIZE, Self::SIZE, protection)?,
+            previous: core::ptr::null_mut(),
+            next: core::ptr::null_mut(),
+            chunk,
+            table,
+            pins: Cell::new(0),
+            #[cfg(test)]
+            _probe: tests::DropProbe,
         })
     }
+
+    /// Number of whole blocks in this mega-block.
+    fn block_count(&self) -> usize { self.chunk.size() / BlockDescriptor::SIZE }
+
+    fn descriptors(&self) -> &[BlockDescriptor<'_>] {
+        let count = self.block_count();
+        if count == 0 { return &[]; }
+        let descriptors = self.table.data.as_ptr() as *const BlockDescriptor;
+        unsafe { core::slice::from_raw_parts(descriptors, count) }
+    }
+
+    /// Size of this mega-block.
+    pub fn size(&self) -> usize { self.chunk.size() }
+
+    /// Size of this mega-block in `Word`s (`usize`s).
+    pub fn size_in_words(&self) -> usize { self.size() / core::mem::size_of::<usize>() }
+
+    /// The `Block`s carved out of this mega-block, in address order.
+    ///
+    /// Blocks of a fresh mega-block are empty, i.e. `start == free`. Only whole blocks inside
+    /// the chunk are produced.
+    pub fn blocks(&self) -> impl Iterator<Item = BlockDescriptor<'_>> {
+        self.descriptors().iter().copied()
+    }
+
+    /// The `Block`s of this mega-block, for allocating objects and updating `free` pointers.
+    ///
+    /// Objects allocated from these blocks borrow the mega-block, so they cannot outlive its
+    /// memory.
+    pub fn blocks_mut(&mut self) -> &mut [BlockDescriptor<'_>] {
+        let count = self.block_count();
+        if count == 0 { return &mut []; }
+        let descriptors = self.table.data.as_ptr() as *mut BlockDescriptor;
+        unsafe { core::slice::from_raw_parts_mut(descriptors, count) }
+    }
+
+    /// Give back the physical pages of the block at `block_index` to the system, keeping its
+    /// address range reserved. The block is reset, and becomes inaccessible until recommitted
+    /// with [`commit_block`](#method.commit_block).
+    ///
+    /// Fails with `InvalidArguments` if pages are larger than blocks, since a block is then not
+    /// made of whole pages.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `block_index` is out of range.
+    pub fn decommit_block(&mut self, block_index: usize) -> Result<()> {
+        let block = &mut self.blocks_mut()[block_index];
+        if primitives::get_page_size()? > BlockDescriptor::SIZE {
+            return Err(MMapError::InvalidArguments);
+        }
+        unsafe { primitives::decommit(block.start as _, BlockDescriptor::SIZE)?; }
+        block.reset();
+        Ok(())
+    }
+
+    /// Make the block at `block_index` accessible again with `protection`, after
+    /// [`decommit_block`](#method.decommit_block). Its pages are faulted in zero-filled.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `block_index` is out of range.
+    pub fn commit_block(
+        &mut self, block_index: usize, protection: BitFlags<Protection>) -> Result<()> {
+        let block = &self.descriptors()[block_index];
+        if primitives::get_page_size()? > BlockDescriptor::SIZE {
+            return Err(MMapError::InvalidArguments);
+        }
+        unsafe { primitives::commit(block.start as _, BlockDescriptor::SIZE, protection) }
+    }
+
+    /// Whether every block of this mega-block is empty, i.e. `start == free`.
+    pub fn is_empty(&self) -> bool {
+        self.blocks().all(|block| block.start == block.free)
+    }
+
+    /// Pin this mega-block, e.g. for an allocation buffer still carving memory out of one of its
+    /// blocks, so that its memory is kept even if all its blocks are empty. The collectors of
+    /// [`gc`](../gc/index.html) keep all the objects of a pinned mega-block in place, and trace
+    /// them as additional roots.
+    pub fn pin(&self) -> MegaBlockPin<'_> {
+        self.pins.set(self.pins.get() + 1);
+        MegaBlockPin(self)
+    }
+
+    /// Whether this mega-block is pinned, see [`pin`](#method.pin).
+    pub fn is_pinned(&self) -> bool { self.pins.get() > 0 }
+
+    /// Allocate a zero-initialized byte buffer of `size` bytes in the first block with room for
+    /// it, pinning this mega-block until the buffer is dropped, e.g. to hand the buffer to
+    /// foreign code holding on to the pointer.
+    ///
+    /// Fails with `InvalidArguments` if the buffer does not fit in a block, or with `NoMemory` if
+    /// no block has room left for it.
+    ///
+    /// ```
+    /// use memory_manager::allocate::{MegaBlock, Protection};
+    /// let mut mega_block = MegaBlock::new(Protection::Read | Protection::Write)?;
+    /// let mut buffer = mega_block.alloc_pinned_buffer(16)?;
+    /// buffer[..5].copy_from_slice(b"hello");
+    /// assert_eq!(&buffer[..6], b"hello\0");
+    /// drop(buffer);
+    /// assert!(!mega_block.is_pinned());
+    /// # Ok::<(), memory_manager::primitives::MMapError>(())
+    /// ```
+    pub fn alloc_pinned_buffer(&mut self, size: usize) -> Result<PinnedBuffer<'_>> {
+        let word = core::mem::size_of::<usize>();
+        let words = size.checked_add(word - 1).ok_or(MMapError::InvalidArguments)? / word;
+        if words >= BlockDescriptor::SIZE_IN_WORDS { return Err(MMapError::InvalidArguments); }
+        let descriptor = block::buffer_descriptor(words);
+        let object = self.blocks_mut().iter_mut()
+            .find_map(|block| block.allocate(descriptor))
+            .ok_or(MMapError::NoMemory)?
+            .start_address().as_ptr::<u8>();
+        let mega_block: &MegaBlock = self;
+        Ok(PinnedBuffer { object: Address::from(object), len: size, _pin: mega_block.pin() })
+    }
+}
+
+/// A zero-initialized byte buffer with a stable address, see
+/// [`MegaBlock::alloc_pinned_buffer`](struct.MegaBlock.html#method.alloc_pinned_buffer).
+///
+/// The buffer is an object of its mega-block (see
+/// [`buffer_descriptor`](../block/fn.buffer_descriptor.html)), which stays pinned while the buffer
+/// lives: the moving collectors leave the objects of pinned mega-blocks in place. Pointers to its
+/// [`object_address`](#method.object_address) keep the buffer alive like any other object, once
+/// dropped an unreachable buffer is reclaimed by the next collection.
+pub struct PinnedBuffer<'a> {
+    object: Address<'a>,
+    len: usize,
+    _pin: MegaBlockPin<'a>,
+}
+
+impl<'a> PinnedBuffer<'a> {
+    /// Address of the object holding this buffer, e.g. to register it as a root.
+    pub fn object_address(&self) -> Address<'a> { self.object }
+
+    /// Address of this buffer, stable until the buffer is dropped.
+    pub fn as_ptr(&self) -> *const u8 {
+        self.object.as_ptr::<u8>().wrapping_add(core::mem::size_of::<usize>())
+    }
+
+    /// Mutable address of this buffer, stable until the buffer is dropped.
+    pub fn as_mut_ptr(&mut self) -> *mut u8 {
+        self.object.as_ptr::<u8>().wrapping_add(core::mem::size_of::<usize>())
+    }
+}
+
+impl<'a> core::ops::Deref for PinnedBuffer<'a> {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.as_ptr(), self.len) }
+    }
+}
+
+impl<'a> core::ops::DerefMut for PinnedBuffer<'a> {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.as_mut_ptr(), self.len) }
+    }
+}
+
+/// Free-block lists: singly-linked list of reclaimed `Block`s.
+///
+/// The link to the next free block is stored in the first word of each block, so this list
+/// needs no extra allocation. Apart from that word, the block memory is left untouched.
+pub struct FreeBlockList<'a> {
+    head: *mut u8,
+    phantom: core::marker::PhantomData<&'a ()>,
+}
+
+impl<'a> Default for FreeBlockList<'a> {
+    fn default() -> Self { Self::new() }
+}
+
+impl<'a> FreeBlockList<'a> {
+    /// Constructor for `FreeBlockList`.
+    pub fn new() -> Self {
+        FreeBlockList { head: core::ptr::null_mut(), phantom: core::marker::PhantomData }
+    }
+
+    /// Whether there is no free block in this list.
+    pub fn is_empty(&self) -> bool { self.head.is_null() }
+
+    /// Park a block for reuse. Objects in the block are forgotten.
+    pub fn push(&mut self, block: BlockDescriptor<'a>) {
+        unsafe { *Address::from(block.start).as_ptr::<*mut u8>() = self.head; }
+        self.head = block.start;
+    }
+
+    /// Take the most recently pushed block, which is empty, i.e. `free == start`.
+    pub fn pop(&mut self) -> Option<BlockDescriptor<'a>> {
+        if self.head.is_null() { return None; }
+        let start = self.head;
+        self.head = unsafe { *Address::from(start).as_ptr::<*mut u8>() };
+        Some(BlockDescriptor::new(start))
+    }
+}
+
+/// Number of size classes of [`SizeClassAllocator`](struct.SizeClassAllocator.html): one per
+/// word count up to 32 words, then 4 per doubling up to a whole `Block`.
+pub const SIZE_CLASS_COUNT: usize =
+    32 + 4 * (BlockDescriptor::SIZE_IN_WORDS / 32).trailing_zeros() as usize;
+
+/// Cell sizes in words of every size class, in increasing order.
+static SIZE_CLASSES: [usize; SIZE_CLASS_COUNT] = size_classes();
+
+const fn size_classes() -> [usize; SIZE_CLASS_COUNT] {
+    let mut classes = [0; SIZE_CLASS_COUNT];
+    let mut i = 0;
+    while i < 32 {
+        classes[i] = i + 1;
+        i += 1;
+    }
+    let mut base = 32;
+    while i < SIZE_CLASS_COUNT {
+        let mut step = 1;
+        while step <= 4 {
+            classes[i] = base + step * base / 4;
+            i += 1;
+            step += 1;
+        }
+        base *= 2;
+    }
+    classes
+}
+
+/// Segregated free lists: cells of a few fixed sizes (size classes), for fast allocation of
+/// small objects.
+///
+/// Freed cells are kept in one singly-linked list per size class, with the link stored in the
+/// first word of each cell, and reused before any new cell is carved out of the blocks given by
+/// [`add_block`](#method.add_block). A request is served by the smallest class that fits, see
+/// [`size_class`](#method.size_class).
+pub struct SizeClassAllocator<'a> {
+    free_lists: [*mut u8; SIZE_CLASS_COUNT],
+    current: Option<BlockDescriptor<'a>>,
+    blocks: FreeBlockList<'a>,
+}
+
+impl<'a> Default for SizeClassAllocator<'a> {
+    fn default() -> Self { Self::new() }
+}
+
+impl<'a> SizeClassAllocator<'a> {
+    /// Constructor for `SizeClassAllocator`, with no block to allocate from yet.
+    pub fn new() -> Self {
+        SizeClassAllocator {
+            free_lists: [core::ptr::null_mut(); SIZE_CLASS_COUNT],
+            current: None,
+            blocks: FreeBlockList::new(),
+        }
+    }
+
+    /// The size class serving requests of `words` words, or `None` if `words` is 0 or larger
+    /// than a `Block`.
+    ///
+    /// ```
+    /// use memory_manager::allocate::SizeClassAllocator;
+    /// let class = SizeClassAllocator::size_class(33).unwrap();
+    /// assert_eq!(SizeClassAllocator::class_size(class), 40);
+    /// assert_eq!(SizeClassAllocator::size_class(0), None);
+    /// ```
+    pub fn size_class(words: usize) -> Option<usize> {
+        if words == 0 { return None; }
+        let class = SIZE_CLASSES.partition_point(|&size| size < words);
+        if class < SIZE_CLASS_COUNT { Some(class) } else { None }
+    }
+
+    /// Size in words of the cells of size class `class`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `class` is not less than `SIZE_CLASS_COUNT`.
+    pub fn class_size(class: usize) -> usize { SIZE_CLASSES[class] }
+
+    /// Give an empty block to carve new cells out of, once the current one is exhausted.
+    pub fn add_block(&mut self, block: BlockDescriptor<'a>) {
+        self.blocks.push(block);
+    }
+
+    /// Allocate a cell of at least `words` words, the most recently freed one of its size class
+    /// if any. Returns `None` if `words` has no size class, or if the blocks are exhausted.
+    pub fn allocate(&mut self, words: usize) -> Option<Address<'a>> {
+        let class = Self::size_class(words)?;
+        let head = self.free_lists[class];
+        if !head.is_null() {
+            self.free_lists[class] = unsafe { *Address::from(head).as_ptr::<*mut u8>() };
+            return Some(Address::from(head));
+        }
+        let bytes = SIZE_CLASSES[class] * core::mem::size_of::<usize>();
+        loop {
+            if let Some(block) = &mut self.current {
+                if block.remaining_bytes() >= bytes {
+                    let cell = block.free;
+                    block.free = unsafe { cell.add(bytes) };
+                    return Some(Address::from(cell));
+                }
+            }
+            self.current = Some(self.blocks.pop()?);
+        }
+    }
+
+    /// Give back a cell from [`allocate`](#method.allocate), for reuse by later requests of the
+    /// same size class.
+    ///
+    /// # Safety
+    ///
+    /// `cell` must be allocated from this allocator with the same `words`, and not freed yet.
+    pub unsafe fn free(&mut self, cell: Address<'a>, words: usize) {
+        let class = Self::size_class(words).expect("no size class for the freed cell");
+        *cell.as_ptr::<*mut u8>() = self.free_lists[class];
+        self.free_lists[class] = cell.as_ptr();
+    }
+}
+
+/// Slabs: a block holding objects of a single descriptor only, so that every cell has the same
+/// size and a dead cell can be reused by the very next allocation.
+///
+/// Freed cells are kept in a singly-linked list, with the link stored in the descriptor slot of
+/// each dead cell. The backing block is thus not iterable while some cells are free.
+pub struct Slab<'a> {
+    descriptor: &'a ObjectDescriptor,
+    cell_bytes: usize,
+    backing: BlockDescriptor<'a>,
+    free_cells: *mut u8,
+}
+
+impl<'a> Slab<'a> {
+    /// Constructor for `Slab`, carving cells for objects of `descriptor` out of `backing`.
+    pub fn new(descriptor: &'a ObjectDescriptor, backing: BlockDescriptor<'a>) -> Self {
+        let cell_bytes = descriptor.total_size() * core::mem::size_of::<usize>();
+        Slab { descriptor, cell_bytes, backing, free_cells: core::ptr::null_mut() }
+    }
+
+    /// Size in bytes of every cell of this slab.
+    pub fn cell_bytes(&self) -> usize { self.cell_bytes }
+
+    /// Allocate an object, in the most recently freed cell if any. The fields of the object are
+    /// zero-initialized. Returns `None` if the backing block is full.
+    pub fn alloc(&mut self) -> Option<Object<'a>> {
+        if self.free_cells.is_null() { return self.backing.allocate(self.descriptor); }
+        let cell = Address::from(self.free_cells);
+        unsafe {
+            let slots = cell.as_ptr::<usize>();
+            self.free_cells = *slots as *mut u8;
+            slots.write(self.descriptor as *const _ as usize);
+            core::ptr::write_bytes(slots.add(1), 0, self.descriptor.total_size() - 1);
+        }
+        Some(Object::from(cell))
+    }
+
+    /// Give back the cell of `object` for reuse.
+    ///
+    /// # Safety
+    ///
+    /// `object` must be allocated from this slab and not freed yet, and no reference to it may
+    /// be used afterwards.
+    pub unsafe fn free(&mut self, object: Object<'a>) {
+        let cell = object.start_address().as_ptr::<*mut u8>();
+        *cell = self.free_cells;
+        self.free_cells = cell as *mut u8;
+    }
+}
+
+/// Buddy allocator: power-of-two ranges of a `MegaBlock`, from one `Block` up to the whole
+/// mega-block, for objects too large to share a block with others.
+///
+/// A range of order `n` spans `BlockDescriptor::SIZE << n` bytes, and is aligned to its size
+/// relative to the start of the mega-block. Allocating splits a larger free range in halves
+/// (buddies) as needed; freeing merges a range with its buddy again whenever both are free.
+///
+/// Free ranges are kept in one doubly-linked list per order, with the links stored in the first
+/// two words of each free range, so the memory of the mega-block must be writable. The order of
+/// a free range is kept in the descriptor of its first block, whose `free` pointer is then
+/// `order + 1` bytes past its `start`. The allocator allocates no memory of its own.
+pub struct BuddyAllocator {
+    mega_block: MegaBlock,
+    /// The first free range of each order up to `max_order`, or null.
+    heads: [*mut u8; MAX_ORDERS],
+    max_order: usize,
+}
+
+/// Bound on the number of orders of a `BuddyAllocator`: more than any mega-block could have.
+const MAX_ORDERS: usize = core::mem::size_of::<usize>() * 8;
+
+impl BuddyAllocator {
+    /// Manage the whole of `mega_block` as a single free range of the top order. Objects in its
+    /// blocks are forgotten.
+    ///
+    /// Fails with `InvalidArguments` if the size of `mega_block` is not `BlockDescriptor::SIZE`
+    /// times a power of 2.
+    ///
+    /// ```
+    /// use memory_manager::allocate::{BuddyAllocator, MegaBlock, Protection};
+    /// # use memory_manager::allocate::MMapError;
+    /// let mega_block = MegaBlock::new(Protection::Read | Protection::Write)?;
+    /// let mut buddies = BuddyAllocator::new(mega_block)?;
+    /// let range = buddies.allocate(2).unwrap();
+    /// unsafe { buddies.free(range, 2) };
+    /// assert_eq!(buddies.free_count(buddies.max_order()), 1);
+    /// # Ok::<(), MMapError>(())
+    /// ```
+    pub fn new(mut mega_block: MegaBlock) -> Result<Self> {
+        let blocks = mega_block.size() / BlockDescriptor::SIZE;
+        if mega_block.size() & (BlockDescriptor::SIZE - 1) != 0 || !blocks.is_power_of_two() {
+            return Err(MMapError::InvalidArguments);
+        }
+        for block in mega_block.blocks_mut() { block.reset(); }
+        let mut buddies = BuddyAllocator {
+            mega_block,
+            heads: [core::ptr::null_mut(); MAX_ORDERS],
+            max_order: blocks.trailing_zeros() as usize,
+        };
+        let start = buddies.start();
+        unsafe { buddies.push(start, buddies.max_order); }
+        Ok(buddies)
+    }
+
+    /// The largest order, whose single range is the whole mega-block.
+    pub fn max_order(&self) -> usize { self.max_order }
+
+    /// The mega-block managed by this allocator.
+    pub fn mega_block(&self) -> &MegaBlock { &self.mega_block }
+
+    /// Allocate a range of `BlockDescriptor::SIZE << order` bytes, splitting a larger free range
+    /// if needed. Returns `None` if no range is free at `order` or above.
+    pub fn allocate(&mut self, order: usize) -> Option<*mut u8> {
+        let found = (order..=self.max_order).find(|&o| !self.heads[o].is_null())?;
+        let range = self.heads[found];
+        unsafe {
+            self.remove(range, found);
+            for lower in (order..found).rev() {
+                self.push(range.add(BlockDescriptor::SIZE << lower), lower);
+            }
+        }
+        Some(range)
+    }
+
+    /// Give back a range from [`allocate`](#method.allocate), merging it with its buddy
+    /// repeatedly while the buddy is free as well.
+    ///
+    /// # Safety
+    ///
+    /// `range` must be allocated from this allocator with the same `order`, and not freed yet.
+    pub unsafe fn free(&mut self, mut range: *mut u8, mut order: usize) {
+        let start = self.start();
+        while order < self.max_order() {
+            let offset = range as usize - start as usize;
+            let buddy = start.add(offset ^ (BlockDescriptor::SIZE << order));
+            if self.free_order(buddy) != Some(order) { break; }
+            self.remove(buddy, order);
+            range = range.min(buddy);
+            order += 1;
+        }
+        self.push(range, order);
+    }
+
+    /// Number of free ranges of exactly `order`, counted by walking the free list.
+    pub fn free_count(&self, order: usize) -> usize {
+        let mut count = 0;
+        let mut current = self.heads[order];
+        while !current.is_null() {
+            count += 1;
+            current = unsafe { (*Self::links(current))[0] };
+        }
+        count
+    }
+
+    fn start(&self) -> *mut u8 { self.mega_block.chunk.data.as_ptr() }
+
+    /// The descriptor of the first block of `range`.
+    fn head_block(&mut self, range: *mut u8) -> &mut BlockDescriptor<'_> {
+        let index = (range as usize - self.start() as usize) / BlockDescriptor::SIZE;
+        &mut self.mega_block.blocks_mut()[index]
+    }
+
+    /// The order of `range` if it is free, see the type-level documentation.
+    fn free_order(&mut self, range: *mut u8) -> Option<usize> {
+        self.head_block(range).used_bytes().checked_sub(1)
+    }
+
+    fn set_free_order(&mut self, range: *mut u8, order: Option<usize>) {
+        let block = self.head_block(range);
+        block.free = block.start.wrapping_add(order.map_or(0, |order| order + 1));
+    }
+
+    /// The `[next, previous]` links of a free range.
+    fn links(range: *mut u8) -> *mut [*mut u8; 2] {
+        Address::from(range).as_ptr()
+    }
+
+    unsafe fn push(&mut self, range: *mut u8, order: usize) {
+        let next = self.heads[order];
+        *Self::links(range) = [next, core::ptr::null_mut()];
+        if !next.is_null() { (*Self::links(next))[1] = range; }
+        self.heads[order] = range;
+        self.set_free_order(range, Some(order));
+    }
+
+    unsafe fn remove(&mut self, range: *mut u8, order: usize) {
+        let [next, previous] = *Self::links(range);
+        if previous.is_null() {
+            self.heads[order] = next;
+        } else {
+            (*Self::links(previous))[0] = next;
+        }
+        if !next.is_null() { (*Self::links(next))[1] = previous; }
+        self.set_free_order(range, None);
+    }
 }
 
 /// Mega-block lists: doubly-linked list of mega-blocks.
+///
+/// The list owns its nodes: mega-blocks are moved in by [`push_front`](#method.push_front), and
+/// freed along with their memory when the list is dropped, unless taken out again before.
 pub struct MegaBlockList(*mut MegaBlock);
 
 impl MegaBlockList {
@@ -144,6 +1200,16 @@ impl MegaBlockList {
         MegaBlockList(core::ptr::null_mut())
     }
 
+    /// Number of mega-blocks in this list, counted by walking the list.
+    pub fn len(&self) -> usize {
+        self.iter().count()
+    }
+
+    /// Whether this list has no mega-block at all.
+    pub fn is_empty(&self) -> bool {
+        self.0.is_null()
+    }
+
     /// The first node of this list, if existing.
     pub fn head(&self) -> Option<&MegaBlock> {
         Some(unsafe { self.0.as_ref()? })
@@ -153,29 +1219,136 @@ impl MegaBlockList {
     pub fn head_mut(&mut self) -> Option<&mut MegaBlock> {
         Some(unsafe { self.0.as_mut()? })
     }
+
+    /// Insert a mega-block at the front of this list, which takes ownership of it. Returns the
+    /// mega-block in its new place.
+    pub fn push_front(&mut self, block: Box<MegaBlock>) -> &mut MegaBlock {
+        let block = Box::into_raw(block);
+        unsafe {
+            (*block).previous = core::ptr::null_mut();
+            (*block).next = self.0;
+            if let Some(head) = self.0.as_mut() { head.previous = block; }
+        }
+        self.0 = block;
+        unsafe { &mut *block }
+    }
+
+    /// Unlink the mega-block at `index` from the front of this list, giving back the ownership of
+    /// it.
+    ///
+    /// The links of the removed block are reset, so that it can be inserted elsewhere.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index` is out of bounds.
+    pub fn remove(&mut self, index: usize) -> Box<MegaBlock> {
+        let block: *mut MegaBlock = self.iter_mut().nth(index)
+            .expect("mega-block index out of bounds");
+        unsafe { self.unlink(block) }
+    }
+
+    /// Unlink a mega-block from this list, giving back the ownership of it.
+    ///
+    /// # Safety
+    ///
+    /// `block` must point to a member of this list, and no reference to it may be in use.
+    unsafe fn unlink(&mut self, block: *mut MegaBlock) -> Box<MegaBlock> {
+        let previous = core::mem::replace(&mut (*block).previous, core::ptr::null_mut());
+        let next = core::mem::replace(&mut (*block).next, core::ptr::null_mut());
+        if let Some(next) = next.as_mut() { next.previous = previous; }
+        match previous.as_mut() {
+            Some(previous) => previous.next = next,
+            None => self.0 = next,
+        }
+        Box::from_raw(block)
+    }
+
+    /// Unlink the first mega-block of this list, giving back the ownership of it.
+    pub fn pop_front(&mut self) -> Option<Box<MegaBlock>> {
+        if self.0.is_null() { return None; }
+        Some(unsafe { self.unlink(self.0) })
+    }
+}
+
+impl Drop for MegaBlockList {
+    fn drop(&mut self) {
+        while self.pop_front().is_some() {}
+    }
+}
+
+/// The nodes of a mega-block list not visited yet by an iterator, from both ends.
+///
+/// `front` is null once every node is visited. `back` is null until the first `next_back`, which
+/// walks to the tail of the list: iterating forward only never pays for locating the tail.
+struct Cursors {
+    front: *mut MegaBlock,
+    back: *mut MegaBlock,
+}
+
+impl Cursors {
+    fn new(head: *mut MegaBlock) -> Self {
+        Cursors { front: head, back: core::ptr::null_mut() }
+    }
+
+    /// Nodes are only read through raw pointers here, before they are handed out.
+    unsafe fn next(&mut self) -> Option<*mut MegaBlock> {
+        let me = self.front;
+        if me.is_null() { return None; }
+        self.front = if me == self.back { core::ptr::null_mut() } else { (*me).next };
+        Some(me)
+    }
+
+    unsafe fn next_back(&mut self) -> Option<*mut MegaBlock> {
+        if self.front.is_null() { return None; }
+        if self.back.is_null() {
+            let mut tail = self.front;
+            while !(*tail).next.is_null() { tail = (*tail).next; }
+            self.back = tail;
+        }
+        let me = self.back;
+        if me == self.front {
+            self.front = core::ptr::null_mut();
+        } else {
+            self.back = (*me).previous;
+        }
+        Some(me)
+    }
 }
 
 /// Mutable iterator for mega-blocks.
-pub struct MegaBlockIteratorMut<'a>(Option<&'a mut MegaBlock>);
+///
+/// The iterator advances through the raw links only: a node becomes a `&mut MegaBlock` when it is
+/// handed out and never again, so no two live mutable references alias.
+pub struct MegaBlockIteratorMut<'a>(Cursors, PhantomData<&'a mut MegaBlock>);
 
 impl<'a> Iterator for MegaBlockIteratorMut<'a> {
     type Item = &'a mut MegaBlock;
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.0.as_mut().and_then(|me|
-            Some(core::mem::replace(me, unsafe { me.next.0.as_mut()? })))
+        unsafe { Some(&mut *self.0.next()?) }
+    }
+}
+
+impl<'a> DoubleEndedIterator for MegaBlockIteratorMut<'a> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        unsafe { Some(&mut *self.0.next_back()?) }
     }
 }
 
 /// Const iterator for mega-blocks.
-pub struct MegaBlockIterator<'a>(Option<&'a MegaBlock>);
+pub struct MegaBlockIterator<'a>(Cursors, PhantomData<&'a MegaBlock>);
 
 impl<'a> Iterator for MegaBlockIterator<'a> {
     type Item = &'a MegaBlock;
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.0.as_mut().and_then(|me|
-            Some(core::mem::replace(me, unsafe { me.next.0.as_ref()? })))
+        unsafe { Some(&*self.0.next()?) }
+    }
+}
+
+impl<'a> DoubleEndedIterator for MegaBlockIterator<'a> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        unsafe { Some(&*self.0.next_back()?) }
     }
 }
 
@@ -188,12 +1361,12 @@ pub type ChunkIterator<'a> = Map<MegaBlockIterator<'a>, fn(&MegaBlock) -> &Memor
 impl MegaBlockList {
     /// Const iterator for traversing the mega-block list.
     pub fn iter(&self) -> MegaBlockIterator {
-        MegaBlockIterator(unsafe { self.0.as_ref() })
+        MegaBlockIterator(Cursors::new(self.0), PhantomData)
     }
 
     /// Mutable iterator for traversing the mega-block list.
     pub fn iter_mut(&mut self) -> MegaBlockIteratorMut {
-        MegaBlockIteratorMut(unsafe { self.0.as_mut() })
+        MegaBlockIteratorMut(Cursors::new(self.0), PhantomData)
     }
 
     /// Iterating memory chunks.
@@ -205,4 +1378,887 @@ impl MegaBlockList {
     pub fn chunks_mut(&mut self) -> ChunkIteratorMut {
         self.iter_mut().map(|x| &mut x.chunk)
     }
+
+    /// Iterate on every object in this list, block by block, in each mega-block in list order.
+    /// The space before `start` and after `free` of each block is skipped.
+    pub fn objects(&self) -> impl Iterator<Item = Object<'_>> {
+        self.iter().flat_map(MegaBlock::blocks).flat_map(|block| block.objects())
+    }
+
+    /// Free the mega-blocks whose every block is empty, giving their memory back to the system.
+    /// Returns the number of mega-blocks released. Pinned mega-blocks are kept.
+    pub fn release_empty(&mut self) -> usize {
+        let mut released = 0;
+        let mut node = self.0;
+        while !node.is_null() {
+            let mega_block = node;
+            let releasable = unsafe {
+                node = (*mega_block).next;
+                (*mega_block).is_empty() && !(*mega_block).is_pinned()
+            };
+            if releasable {
+                drop(unsafe { self.unlink(mega_block) });
+                released += 1;
+            }
+        }
+        released
+    }
+
+    /// Find the mega-block in this list containing `address`.
+    ///
+    /// Mega-blocks of the default size are `SIZE`-aligned, so the only candidate base address is
+    /// `address` rounded down to `SIZE`, and checking a node is a single comparison. Mega-blocks
+    /// of custom sizes are checked against their whole address range.
+    pub fn find_containing(&self, address: Address) -> Option<&MegaBlock> {
+        let address = address.as_ptr::<u8>() as usize;
+        let candidate = address & !(MegaBlock::SIZE - 1);
+        self.iter().find(|mega_block| {
+            let start = mega_block.chunk.data.as_ptr() as usize;
+            if mega_block.size() == MegaBlock::SIZE {
+                start == candidate
+            } else {
+                start <= address && address - start < mega_block.size()
+            }
+        })
+    }
+}
+
+/// Large object space: objects too big for a `Block`, each in a dedicated memory chunk.
+///
+/// Every object starts at the beginning of its own page-aligned `MemoryChunk`, the chunk is
+/// freed as a whole when the object is dropped from this space.
+///
+/// ```
+/// use memory_manager::allocate::LargeObjectSpace;
+/// use memory_manager::object::ObjectDescriptor;
+/// # use memory_manager::primitives::MMapError;
+/// static HUGE: ObjectDescriptor =
+///     ObjectDescriptor { unpacked_field_count: 8191, pointer_count: 0, field_map: None };
+/// assert!(LargeObjectSpace::is_large(&HUGE));
+/// let mut large = LargeObjectSpace::new();
+/// let object = large.allocate(&HUGE)?;
+/// assert_eq!(object.unpacked.len(), 8191);
+/// assert_eq!(large.len(), 1);
+/// # Ok::<(), MMapError>(())
+/// ```
+#[derive(Debug, Default)]
+pub struct LargeObjectSpace(Vec<MemoryChunk>);
+
+impl LargeObjectSpace {
+    /// Constructor for `LargeObjectSpace`.
+    pub fn new() -> Self {
+        LargeObjectSpace(Vec::new())
+    }
+
+    /// Whether objects described by `descriptor` are too big for a `Block`, and should be
+    /// allocated in a large object space instead.
+    pub fn is_large(descriptor: &ObjectDescriptor) -> bool {
+        descriptor.checked_total_size()
+            .map_or(true, |words| words > BlockDescriptor::SIZE_IN_WORDS)
+    }
+
+    /// Allocate an object described by `descriptor` in a new chunk.
+    ///
+    /// The descriptor pointer is written at the start of the new object, and all its fields are
+    /// zero-initialized. Fails with `LengthOverflow` if the object size overflows.
+    ///
+    /// The object borrows this space, so that it cannot outlive its chunk, which is freed by
+    /// [`retain`](#method.retain) or when the space is dropped:
+    ///
+    /// ```compile_fail
+    /// use memory_manager::allocate::LargeObjectSpace;
+    /// use memory_manager::object::ObjectDescriptor;
+    /// static HUGE: ObjectDescriptor =
+    ///     ObjectDescriptor { unpacked_field_count: 8191, pointer_count: 0, field_map: None };
+    /// let object = LargeObjectSpace::new().allocate(&HUGE).unwrap();
+    /// object.unpacked[0] = 42;
+    /// ```
+    pub fn allocate<'s>(&'s mut self, descriptor: &'s ObjectDescriptor) -> Result<Object<'s>> {
+        let word = core::mem::size_of::<usize>();
+        let page_size = primitives::get_page_size()?;
+        if !descriptor.validate(usize::MAX) { return Err(MMapError::LengthOverflow); }
+        let size = (descriptor.total_size() * word).checked_add(page_size - 1)
+            .ok_or(MMapError::LengthOverflow)? & !(page_size - 1);
+        let mut chunk = MemoryChunk::new(page_size, size, Protection::Read | Protection::Write)?;
+        // freshly mapped memory is already zero-filled
+        let header = (descriptor as *const ObjectDescriptor as usize).to_ne_bytes();
+        AsMut::<[u8]>::as_mut(&mut chunk)[..word].copy_from_slice(&header);
+        let address = Address::from(chunk.data.as_ptr());
+        self.0.push(chunk);
+        Ok(Object::from(address))
+    }
+
+    /// Number of objects in this space.
+    pub fn len(&self) -> usize { self.0.len() }
+
+    /// Whether there is no object in this space.
+    pub fn is_empty(&self) -> bool { self.0.is_empty() }
+
+    /// Whether `address` is the starting address of an object in this space.
+    pub fn contains(&self, address: Address) -> bool {
+        self.object_addresses().any(|start| start == address)
+    }
+
+    /// Iterate on the starting addresses of the objects in this space.
+    pub fn object_addresses(&self) -> impl Iterator<Item = Address<'_>> {
+        self.0.iter().map(|chunk| Address::from(chunk.data.as_ptr()))
+    }
+
+    /// Iterate on the chunks holding the objects in this space.
+    pub fn chunks(&self) -> core::slice::Iter<'_, MemoryChunk> {
+        self.0.iter()
+    }
+
+    /// Keep only the objects for which `keep` returns `true`, the chunks of all the other
+    /// objects are freed.
+    pub fn retain(&mut self, mut keep: impl FnMut(Address) -> bool) {
+        self.0.retain(|chunk| keep(Address::from(chunk.data.as_ptr())));
+    }
+}
+
+/// Allocate an object described by `descriptor`, routing it by size: into `block` if it fits in
+/// a `Block`, into a dedicated chunk of `large` otherwise.
+///
+/// Returns `Ok(None)` if the object belongs in `block` but the rest of `block` cannot hold it.
+pub fn allocate_object<'a>(
+    block: &mut BlockDescriptor<'a>, large: &'a mut LargeObjectSpace,
+    descriptor: &'a ObjectDescriptor) -> Result<Option<Object<'a>>> {
+    if LargeObjectSpace::is_large(descriptor) {
+        large.allocate(descriptor).map(Some)
+    } else {
+        Ok(block.allocate(descriptor))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::{MemoryChunk, MegaBlock, MegaBlockList, FreeBlockList, Protection, Advice};
+    use super::BuddyAllocator;
+    use super::{SizeClassAllocator, SIZE_CLASS_COUNT};
+    use super::Slab;
+    use super::{primitives, MMapError, Ordering};
+    use super::BlockDescriptor;
+    use super::{Address, MiB};
+    use std::boxed::Box;
+    use std::vec::Vec;
+
+    std::thread_local! {
+        /// Number of mega-blocks dropped on this thread.
+        static DROPPED: core::cell::Cell<usize> = const { core::cell::Cell::new(0) };
+    }
+
+    /// A field of every mega-block in tests, counting the mega-blocks dropped in `DROPPED`, so
+    /// that tests can tell that lists free their nodes.
+    pub(super) struct DropProbe;
+
+    impl Drop for DropProbe {
+        fn drop(&mut self) {
+            let _ = DROPPED.try_with(|dropped| dropped.set(dropped.get() + 1));
+        }
+    }
+
+    fn as_ptr(block: &MegaBlock) -> *mut MegaBlock {
+        block as *const _ as *mut _
+    }
+
+    /// Check that `previous` and `next` links agree with each other.
+    fn assert_consistent(list: &MegaBlockList) {
+        let nodes: Vec<_> = list.iter().map(as_ptr).collect();
+        for (i, node) in list.iter().enumerate() {
+            let previous = if i == 0 { core::ptr::null_mut() } else { nodes[i - 1] };
+            let next = nodes.get(i + 1).copied().unwrap_or(core::ptr::null_mut());
+            assert_eq!(node.previous, previous);
+            assert_eq!(node.next, next);
+        }
+    }
+
+    #[test]
+    fn test_send() {
+        let size = primitives::get_page_size().unwrap();
+        let alignment = primitives::get_minimum_alignment().unwrap();
+        let rw = Protection::Read | Protection::Write;
+        let chunk = MemoryChunk::new(alignment, size, rw).unwrap();
+        let shared = std::sync::Arc::new(chunk);
+        let reader = {
+            let shared = shared.clone();
+            std::thread::spawn(move || AsRef::<[u8]>::as_ref(&*shared)[0])
+        };
+        assert_eq!(reader.join().unwrap(), 0);
+        let mut chunk = std::sync::Arc::try_unwrap(shared).unwrap();
+        chunk.as_mut()[1] = 1u8;
+        let writer = std::thread::spawn(move || {
+            let bytes: &mut [u8] = chunk.as_mut();
+            bytes[0] = 42;
+            chunk
+        });
+        let chunk = writer.join().unwrap();
+        assert_eq!(AsRef::<[u8]>::as_ref(&chunk)[..2], [42, 1]);
+    }
+
+    #[test]
+    fn test_new_rounded() {
+        let rw = Protection::Read | Protection::Write;
+        let mut chunk = MemoryChunk::new_rounded(4096, 4097, rw).unwrap();
+        assert_eq!(chunk.size(), 8192);
+        assert_eq!(chunk.requested_size(), 4097);
+        AsMut::<[u8]>::as_mut(&mut chunk)[8191] = 1;
+        assert_eq!(MemoryChunk::new_rounded(4096, 4096, rw).unwrap().size(), 4096);
+        assert_eq!(MemoryChunk::new_rounded(3, 4096, rw), Err(MMapError::InvalidArguments));
+        assert_eq!(MemoryChunk::new_rounded(4096, usize::MAX, rw), Err(MMapError::LengthOverflow));
+    }
+
+    #[test]
+    fn test_option_size() {
+        use core::mem::size_of;
+        assert_eq!(size_of::<Option<MemoryChunk>>(), size_of::<MemoryChunk>());
+    }
+
+    #[test]
+    fn test_guarded_chunk() {
+        let page_size = primitives::get_page_size().unwrap();
+        let alignment = primitives::get_minimum_alignment().unwrap();
+        let size = page_size * 4;
+        let mut chunk = MemoryChunk::new_guarded(
+            alignment, size, Protection::Read | Protection::Write).unwrap();
+        assert_eq!(chunk.size(), size);
+        assert_eq!(unsafe { chunk.data() }.as_ptr::<u8>() as usize & (alignment - 1), 0);
+        let bytes: &mut [u8] = chunk.as_mut();
+        for (i, b) in bytes.iter_mut().enumerate() { *b = i as u8; }
+        assert_eq!(bytes[size - 1], (size - 1) as u8);
+        let odd = MemoryChunk::new_guarded(alignment, page_size + 1, Protection::Read.into());
+        assert!(odd.is_err());
+    }
+
+    #[test]
+    fn test_large_alignment() {
+        let alignment = MegaBlock::SIZE * 4;
+        let size = primitives::get_minimum_alignment().unwrap() * 4;
+        let rw = Protection::Read | Protection::Write;
+        for chunk in [
+            MemoryChunk::new(alignment, size, rw).unwrap(),
+            MemoryChunk::new_guarded(alignment, size, rw).unwrap(),
+        ] {
+            let data = unsafe { chunk.data() }.as_ptr::<u8>();
+            assert_eq!(data as usize & (alignment - 1), 0);
+            unsafe { core::ptr::write_bytes(data, 0xAA, size) };
+            drop(chunk);
+        }
+    }
+
+    #[test]
+    fn test_protect() {
+        let size = primitives::get_page_size().unwrap();
+        let alignment = primitives::get_minimum_alignment().unwrap();
+        let mut chunk = MemoryChunk::new(
+            alignment, size, Protection::Read | Protection::Write).unwrap();
+        chunk.as_mut()[0] = 42u8;
+        assert_eq!(unsafe { chunk.protect(Protection::Read.into()) }, Ok(()));
+        assert_eq!(AsRef::<[u8]>::as_ref(&chunk)[0], 42);
+        assert_eq!(unsafe { chunk.protect(Protection::Read | Protection::Write) }, Ok(()));
+        chunk.as_mut()[0] = 43u8;
+        assert_eq!(AsRef::<[u8]>::as_ref(&chunk)[0], 43);
+    }
+
+    #[test]
+    fn test_make_readonly() {
+        let size = primitives::get_page_size().unwrap();
+        let alignment = primitives::get_minimum_alignment().unwrap();
+        let rw = Protection::Read | Protection::Write;
+        let mut chunk = MemoryChunk::new(alignment, size, rw).unwrap();
+        chunk.as_mut()[0] = 42u8;
+        assert_eq!(unsafe { chunk.make_readonly() }, Ok(()));
+        #[cfg(any(windows, target_os = "linux"))]
+        assert_eq!(primitives::query(unsafe { chunk.data() }.as_ptr(), size),
+                   Ok(Protection::Read.into()));
+        assert_eq!(AsRef::<[u8]>::as_ref(&chunk)[0], 42);
+        assert_eq!(unsafe { chunk.protect(rw) }, Ok(()));
+        chunk.as_mut()[0] = 43u8;
+        assert_eq!(AsRef::<[u8]>::as_ref(&chunk)[0], 43);
+    }
+
+    #[test]
+    fn test_advise() {
+        let size = primitives::get_page_size().unwrap() * 4;
+        let alignment = primitives::get_minimum_alignment().unwrap();
+        let chunk = MemoryChunk::new(
+            alignment, size, Protection::Read | Protection::Write).unwrap();
+        assert_eq!(unsafe { chunk.advise(Advice::Sequential) }, Ok(()));
+    }
+
+    #[test]
+    fn test_lock() {
+        let size = primitives::get_page_size().unwrap();
+        let alignment = primitives::get_minimum_alignment().unwrap();
+        let rw = Protection::Read | Protection::Write;
+        let mut chunk = MemoryChunk::new(alignment, size, rw).unwrap();
+        match chunk.lock() {
+            // unprivileged, or the limit is already used up by other tests
+            Err(MMapError::LockLimit) => return,
+            result => assert_eq!(result, Ok(())),
+        }
+        assert!(chunk.locked.load(Ordering::Relaxed));
+        chunk.as_mut()[0] = 42u8;
+        assert_eq!(chunk.unlock(), Ok(()));
+        assert!(!chunk.locked.load(Ordering::Relaxed));
+        // dropping a locked chunk unlocks it
+        let locked = MemoryChunk::new(alignment, size, rw).unwrap();
+        assert_eq!(locked.lock(), Ok(()));
+        drop(locked);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_resident_pages() {
+        let page_size = primitives::get_page_size().unwrap();
+        let pages = 64;
+        let rw = Protection::Read | Protection::Write;
+        let mut chunk = MemoryChunk::new(page_size, pages * page_size, rw).unwrap();
+        let bytes: &mut [u8] = chunk.as_mut();
+        for page in 0..pages / 2 { bytes[page * page_size] = 1; }
+        // roughly: the kernel may fault around, or reclaim under pressure
+        let resident = chunk.resident_pages().unwrap();
+        assert!((pages / 4..=pages * 3 / 4).contains(&resident), "{} pages resident", resident);
+    }
+
+    #[test]
+    fn test_reserve_commit() {
+        let page_size = primitives::get_page_size().unwrap();
+        let alignment = primitives::get_minimum_alignment().unwrap();
+        let mut chunk = MemoryChunk::reserve(alignment, page_size * 16).unwrap();
+        let rw = Protection::Read | Protection::Write;
+        assert_eq!(unsafe { chunk.commit(page_size * 3, page_size, rw) }, Ok(()));
+        let page = unsafe { chunk.data().offset(3 * page_size as isize).as_ptr::<u8>() };
+        unsafe { page.write(42) };
+        assert_eq!(unsafe { page.read() }, 42);
+        assert_eq!(unsafe { chunk.decommit(page_size * 3, page_size) }, Ok(()));
+        assert_eq!(unsafe { chunk.commit(page_size * 3, page_size, rw) }, Ok(()));
+        assert_eq!(unsafe { page.read() }, 0);
+        let outside = unsafe { chunk.commit(page_size * 16, page_size, rw) };
+        assert_eq!(outside, Err(super::MMapError::InvalidArguments));
+    }
+
+    #[test]
+    fn test_fill_zero() {
+        let size = primitives::get_page_size().unwrap() * 2;
+        let alignment = primitives::get_minimum_alignment().unwrap();
+        let mut chunk = MemoryChunk::new(
+            alignment, size, Protection::Read | Protection::Write).unwrap();
+        chunk.fill(0xAA);
+        assert!(AsRef::<[u8]>::as_ref(&chunk).iter().all(|b| *b == 0xAA));
+        chunk.zero();
+        assert!(AsRef::<[u8]>::as_ref(&chunk).iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn test_resize() {
+        let page_size = primitives::get_page_size().unwrap();
+        let alignment = primitives::get_minimum_alignment().unwrap();
+        let rw = Protection::Read | Protection::Write;
+        let mut chunk = MemoryChunk::new(alignment, page_size, rw).unwrap();
+        chunk.slice_mut::<u8>(0..page_size).iter_mut().enumerate().for_each(|(i, b)| *b = i as u8);
+        assert_eq!(unsafe { chunk.resize(page_size * 4) }, Ok(()));
+        assert_eq!(chunk.size(), page_size * 4);
+        assert!(chunk.slice::<u8>(0..page_size).iter().enumerate().all(|(i, b)| *b == i as u8));
+        chunk.fill(0xAA);
+        assert_eq!(unsafe { chunk.resize(0) }, Err(super::MMapError::InvalidArguments));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_split_at() {
+        let page_size = primitives::get_page_size().unwrap();
+        let protection = Protection::Read | Protection::Write;
+        let chunk = MemoryChunk::new(page_size, page_size * 2, protection).unwrap();
+        assert_eq!(chunk.split_at(1).unwrap_err(), super::MMapError::InvalidArguments);
+        let chunk = MemoryChunk::new(page_size, page_size * 2, protection).unwrap();
+        let base = unsafe { chunk.data().as_ptr::<u8>() };
+        let (mut front, mut back) = chunk.split_at(page_size).unwrap();
+        assert_eq!((front.size(), back.size()), (page_size, page_size));
+        assert_eq!(unsafe { back.data().as_ptr::<u8>() }, unsafe { base.add(page_size) });
+        front.fill(1);
+        back.fill(2);
+        drop(front);
+        assert!(AsRef::<[u8]>::as_ref(&back).iter().all(|b| *b == 2));
+    }
+
+    #[test]
+    fn test_slice() {
+        let size = primitives::get_page_size().unwrap();
+        let alignment = primitives::get_minimum_alignment().unwrap();
+        let mut chunk = MemoryChunk::new(
+            alignment, size, Protection::Read | Protection::Write).unwrap();
+        let words = size / core::mem::size_of::<usize>();
+        chunk.slice_mut::<usize>(0..words).iter_mut().enumerate().for_each(|(i, w)| *w = i);
+        assert_eq!(chunk.slice::<usize>(2..5), &[2, 3, 4]);
+        assert_eq!(chunk.slice::<usize>(words..words), &[] as &[usize]);
+    }
+
+    #[test]
+    #[should_panic(expected = "out of range")]
+    fn test_slice_out_of_bounds() {
+        let size = primitives::get_page_size().unwrap();
+        let alignment = primitives::get_minimum_alignment().unwrap();
+        let chunk = MemoryChunk::new(alignment, size, Protection::Read.into()).unwrap();
+        let words = size / core::mem::size_of::<usize>();
+        chunk.slice::<usize>(0..words + 1);
+    }
+
+    #[test]
+    fn test_with_size() {
+        for size in [8 * MiB, 16 * MiB].iter().copied() {
+            let mega_block = MegaBlock::with_size(size, Protection::NONE).unwrap();
+            assert_eq!(mega_block.chunk.size(), size);
+            assert_eq!(mega_block.size_in_words(), size / core::mem::size_of::<usize>());
+            assert_eq!(mega_block.blocks().count(), size / BlockDescriptor::SIZE);
+            assert_eq!(unsafe { mega_block.chunk.data() }.as_ptr::<u8>() as usize % size, 0);
+        }
+        let odd = MegaBlock::with_size(MiB + 1, Protection::NONE);
+        assert!(matches!(odd, Err(super::MMapError::InvalidArguments)));
+    }
+
+    /// Push `count` new mega-blocks made by `make`, returning them in list order.
+    fn push_all(
+        list: &mut MegaBlockList, count: usize,
+        mut make: impl FnMut() -> MegaBlock) -> Vec<*mut MegaBlock> {
+        let mut nodes: Vec<_> = (0..count)
+            .map(|_| as_ptr(list.push_front(Box::new(make()))))
+            .collect();
+        nodes.reverse();
+        nodes
+    }
+
+    fn nodes(list: &MegaBlockList) -> Vec<*mut MegaBlock> {
+        list.iter().map(as_ptr).collect()
+    }
+
+    #[test]
+    fn test_push_front() {
+        let mut list = MegaBlockList::new();
+        let pushed = push_all(&mut list, 3, || MegaBlock::new(Protection::NONE).unwrap());
+        assert_eq!(nodes(&list), pushed);
+        assert_consistent(&list);
+    }
+
+    /// A mega-block without memory, for list operations only (they need no system calls).
+    fn detached() -> MegaBlock {
+        MegaBlock {
+            previous: core::ptr::null_mut(),
+            next: core::ptr::null_mut(),
+            chunk: MemoryChunk::empty(),
+            table: MemoryChunk::empty(),
+            pins: core::cell::Cell::new(0),
+            _probe: DropProbe,
+        }
+    }
+
+    #[test]
+    fn test_len() {
+        let mut list = MegaBlockList::new();
+        assert!(list.is_empty());
+        assert_eq!(list.len(), 0);
+        list.push_front(Box::new(detached()));
+        assert!(!list.is_empty());
+        assert_eq!(list.len(), 1);
+        push_all(&mut list, 2, detached);
+        assert!(!list.is_empty());
+        assert_eq!(list.len(), 3);
+    }
+
+    #[test]
+    fn test_drop() {
+        let dropped = || DROPPED.with(|dropped| dropped.get());
+        let before = dropped();
+        let mut list = MegaBlockList::new();
+        push_all(&mut list, 3, detached);
+        let popped = list.pop_front().unwrap();
+        assert_eq!(dropped(), before);
+        drop(list);
+        assert_eq!(dropped(), before + 2);
+        drop(popped);
+        assert_eq!(dropped(), before + 3);
+        // the memory goes with the mega-blocks
+        let mut list = MegaBlockList::new();
+        push_all(&mut list, 2, || MegaBlock::new(Protection::NONE).unwrap());
+        list.push_front(Box::new(MegaBlock::new(Protection::NONE).unwrap()));
+        drop(list);
+        assert_eq!(dropped(), before + 6);
+    }
+
+    /// Also meant to run under Miri, checking the iterator against the aliasing rules.
+    #[test]
+    fn test_iter_mut() {
+        let mut list = MegaBlockList::new();
+        push_all(&mut list, 3, detached);
+        let mut held = Vec::new();
+        for (i, mega_block) in list.iter_mut().enumerate() {
+            mega_block.pins.set(i);
+            held.push(mega_block);
+        }
+        // every reference handed out is still usable after the iteration
+        for (i, mega_block) in held.into_iter().enumerate() {
+            assert_eq!(mega_block.pins.get(), i);
+            mega_block.pins.set(0);
+        }
+        for mega_block in list.iter_mut().rev() { mega_block.pins.set(0); }
+        assert!(list.iter().all(|b| !b.is_pinned()));
+        assert_consistent(&list);
+    }
+
+    #[test]
+    fn test_rev() {
+        let mut list = MegaBlockList::new();
+        assert!(list.iter().next_back().is_none());
+        push_all(&mut list, 4, || MegaBlock::new(Protection::NONE).unwrap());
+        let forward = nodes(&list);
+        let mut backward: Vec<_> = list.iter().rev().map(as_ptr).collect();
+        backward.reverse();
+        assert_eq!(backward, forward);
+        let mut backward_mut: Vec<_> = list.iter_mut().rev().map(|m| as_ptr(m)).collect();
+        backward_mut.reverse();
+        assert_eq!(backward_mut, forward);
+        // both ends meet in the middle, every node is visited exactly once
+        let mut iter = list.iter();
+        let ends = [iter.next(), iter.next_back(), iter.next_back(), iter.next(), iter.next()];
+        let ends: Vec<_> = ends.iter().map(|m| m.map(as_ptr)).collect();
+        let expected = [0, 3, 2, 1].iter().map(|&i| Some(forward[i]));
+        assert_eq!(ends, expected.chain(core::iter::once(None)).collect::<Vec<_>>());
+        assert!(iter.next_back().is_none());
+    }
+
+    #[test]
+    fn test_remove() {
+        let mut list = MegaBlockList::new();
+        let pushed = push_all(&mut list, 5, || MegaBlock::new(Protection::NONE).unwrap());
+        // remove the head, a middle node, then the tail
+        for &(index, i) in &[(0, 0), (1, 2), (2, 4)] {
+            let removed = list.remove(index);
+            assert_eq!(as_ptr(&removed), pushed[i]);
+            assert!(removed.previous.is_null() && removed.next.is_null());
+            assert_consistent(&list);
+        }
+        assert_eq!(nodes(&list), [pushed[1], pushed[3]]);
+        let head = list.pop_front().unwrap();
+        assert_eq!(as_ptr(&head), pushed[1]);
+        assert_consistent(&list);
+        // removed mega-blocks can be inserted elsewhere
+        let mut other = MegaBlockList::new();
+        other.push_front(head);
+        assert_eq!(nodes(&other), [pushed[1]]);
+    }
+
+    #[test]
+    #[should_panic(expected = "out of bounds")]
+    fn test_remove_out_of_bounds() {
+        let mut list = MegaBlockList::new();
+        push_all(&mut list, 2, detached);
+        list.remove(2);
+    }
+
+    #[test]
+    fn test_find_containing() {
+        let mut list = MegaBlockList::new();
+        push_all(&mut list, 3, || MegaBlock::new(Protection::NONE).unwrap());
+        list.push_front(Box::new(MegaBlock::with_size(3 * MiB, Protection::NONE).unwrap()));
+        for block in list.iter() {
+            let inside = block.blocks().nth(5).unwrap().start.wrapping_add(24);
+            let found = list.find_containing(Address::from(inside)).map(as_ptr);
+            assert_eq!(found, Some(as_ptr(block)));
+            let last = block.chunk.data.as_ptr().wrapping_add(block.size() - 1);
+            assert_eq!(list.find_containing(Address::from(last)).map(as_ptr), Some(as_ptr(block)));
+        }
+        let outside = 0usize;
+        assert!(list.find_containing(Address::from(&outside as *const _ as *mut usize)).is_none());
+    }
+
+    #[test]
+    fn test_flush_anonymous() {
+        let size = primitives::get_page_size().unwrap();
+        let alignment = primitives::get_minimum_alignment().unwrap();
+        let rw = Protection::Read | Protection::Write;
+        let chunk = MemoryChunk::new(alignment, size, rw).unwrap();
+        assert_eq!(chunk.flush(None), Ok(()));
+        assert_eq!(chunk.flush(Some(1..2)), Ok(()));
+        assert_eq!(chunk.flush(Some(0..size + 1)), Err(MMapError::InvalidArguments));
+        let (start, end) = (2, 1);
+        assert_eq!(chunk.flush(Some(start..end)), Err(MMapError::InvalidArguments));
+    }
+
+    #[cfg(all(unix, feature = "std"))]
+    #[test]
+    fn test_flush_file() {
+        let page_size = primitives::get_page_size().unwrap();
+        let size = page_size * 2;
+        let path = std::env::temp_dir().join(std::format!("mm-flush-{}", std::process::id()));
+        let file = std::fs::OpenOptions::new()
+            .read(true).write(true).create(true).truncate(true)
+            .open(&path).unwrap();
+        file.set_len(size as u64).unwrap();
+        let rw = Protection::Read | Protection::Write;
+        let mut chunk = MemoryChunk::map_file(page_size, size, &file, rw).unwrap();
+        AsMut::<[u8]>::as_mut(&mut chunk)[page_size + 3..page_size + 8].copy_from_slice(b"hello");
+        assert_eq!(chunk.flush(Some(page_size + 3..page_size + 8)), Ok(()));
+        let contents = std::fs::read(&path).unwrap();
+        assert_eq!(&contents[page_size + 3..page_size + 8], b"hello");
+        assert_eq!(chunk.flush(None), Ok(()));
+        drop(chunk);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(all(unix, feature = "std"))]
+    #[test]
+    fn test_file_backed() {
+        static PAIR: super::ObjectDescriptor =
+            super::ObjectDescriptor { unpacked_field_count: 2, pointer_count: 0, field_map: None };
+        let path = std::env::temp_dir()
+            .join(std::format!("mm-file-backed-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(MegaBlock::new_file_backed(&path, Protection::Read.into()).err(),
+                   Some(MMapError::from_errno(libc::ENOENT)));
+        let used = {
+            let mut mega = MegaBlock::new_file_backed(&path, Protection::Read | Protection::Write)
+                .unwrap();
+            assert_eq!(unsafe { mega.chunk.data() }.as_ptr::<u8>() as usize % MegaBlock::SIZE, 0);
+            let block = &mut mega.blocks_mut()[1];
+            for n in 0..4 { block.allocate(&PAIR).unwrap().unpacked[1] = 100 + n; }
+            block.used_bytes()
+        };
+        assert!(std::fs::metadata(&path).unwrap().len() > MegaBlock::SIZE as u64);
+
+        let mega = MegaBlock::new_file_backed(&path, Protection::Read.into()).unwrap();
+        let block = mega.blocks().nth(1).unwrap();
+        assert_eq!(block.used_bytes(), used);
+        assert_eq!(mega.blocks().filter(|block| block.used_bytes() > 0).count(), 1);
+        let tags: Vec<_> = block.objects().map(|object| object.unpacked[1]).collect();
+        assert_eq!(tags, [100, 101, 102, 103]);
+        drop(mega);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_objects() {
+        static PAIR: super::ObjectDescriptor =
+            super::ObjectDescriptor { unpacked_field_count: 2, pointer_count: 0, field_map: None };
+        let mut list = MegaBlockList::new();
+        push_all(&mut list, 2, || MegaBlock::new(Protection::Read | Protection::Write).unwrap());
+        for (i, block) in list.iter_mut().enumerate() {
+            let descriptors = block.blocks_mut();
+            for n in 0..3 + i { descriptors[0].allocate(&PAIR).unwrap().unpacked[0] = n; }
+            for n in 0..5 { descriptors[7].allocate(&PAIR).unwrap().unpacked[0] = n; }
+        }
+        let per_block: usize = list.iter()
+            .flat_map(MegaBlock::blocks)
+            .map(|block| block.objects().count())
+            .sum();
+        assert_eq!(per_block, 3 + 5 + 4 + 5);
+        assert_eq!(list.objects().count(), per_block);
+        let tags: Vec<_> = list.objects().map(|object| object.unpacked[0]).collect();
+        assert_eq!(tags, [0, 1, 2, 0, 1, 2, 3, 4, 0, 1, 2, 3, 0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn test_release_empty() {
+        static PAIR: super::ObjectDescriptor =
+            super::ObjectDescriptor { unpacked_field_count: 2, pointer_count: 0, field_map: None };
+        let rw = Protection::Read | Protection::Write;
+        let mut list = MegaBlockList::new();
+        let pushed = push_all(&mut list, 4, || MegaBlock::new(rw).unwrap());
+        for block in list.iter_mut() {
+            for b in block.blocks_mut().iter_mut().take(3) { b.allocate(&PAIR).unwrap(); }
+        }
+        assert_eq!(list.release_empty(), 0);
+        // empty all the mega-blocks but the last one, and pin the third one
+        for block in list.iter_mut().take(3) {
+            for b in block.blocks_mut() { b.reset(); }
+        }
+        let dropped = || DROPPED.with(|dropped| dropped.get());
+        let before = dropped();
+        let pin = unsafe { &*pushed[2] }.pin();
+        assert!(unsafe { &*pushed[2] }.is_pinned());
+        assert_eq!(list.release_empty(), 2);
+        assert_eq!(dropped(), before + 2);
+        assert_eq!(nodes(&list), [pushed[2], pushed[3]]);
+        assert_consistent(&list);
+        drop(pin);
+        assert_eq!(list.release_empty(), 1);
+        assert_eq!(nodes(&list), [pushed[3]]);
+    }
+
+    #[test]
+    fn test_decommit_block() {
+        if primitives::get_page_size().unwrap() > BlockDescriptor::SIZE { return; }
+        let rw = Protection::Read | Protection::Write;
+        let mut mega = MegaBlock::new(rw).unwrap();
+        let words = BlockDescriptor::SIZE / core::mem::size_of::<usize>();
+        let block = |mega: &MegaBlock, i: usize| unsafe {
+            core::slice::from_raw_parts_mut(
+                mega.blocks().nth(i).unwrap().start as *mut usize, words)
+        };
+        block(&mega, 1).fill(usize::MAX);
+        block(&mega, 2).fill(usize::MAX);
+        mega.blocks_mut()[1].free = mega.blocks_mut()[1].start.wrapping_add(64);
+        mega.decommit_block(1).unwrap();
+        assert_eq!(mega.blocks_mut()[1].used_bytes(), 0);
+        mega.commit_block(1, rw).unwrap();
+        assert!(block(&mega, 1).iter().all(|&w| w == 0));
+        assert!(block(&mega, 2).iter().all(|&w| w == usize::MAX));
+    }
+
+    #[test]
+    fn test_blocks() {
+        let mega = MegaBlock::new(Protection::NONE).unwrap();
+        let base = mega.chunk.data.as_ptr() as usize;
+        let mut count = 0;
+        for (i, block) in mega.blocks().enumerate() {
+            assert_eq!(block.start as usize, base + i * BlockDescriptor::SIZE);
+            assert_eq!(block.free, block.start);
+            count += 1;
+        }
+        assert_eq!(count, MegaBlock::SIZE / BlockDescriptor::SIZE);
+    }
+
+    #[test]
+    fn test_free_block_list() {
+        let word = core::mem::size_of::<usize>();
+        let mega = MegaBlock::new(Protection::Read | Protection::Write).unwrap();
+        let blocks: Vec<_> = mega.blocks().take(3).collect();
+        for (i, block) in blocks.iter().enumerate() {
+            unsafe { *(block.start.add(word) as *mut usize) = i; }
+        }
+        let mut list = FreeBlockList::new();
+        assert!(list.pop().is_none());
+        for block in blocks.iter() { list.push(*block); }
+        for (i, block) in blocks.iter().enumerate().rev() {
+            let popped = list.pop().unwrap();
+            assert_eq!(popped.start, block.start);
+            assert_eq!(popped.free, popped.start);
+            assert_eq!(unsafe { *(popped.start.add(word) as *const usize) }, i);
+        }
+        assert!(list.is_empty());
+    }
+
+    #[test]
+    fn test_buddy_allocator() {
+        let mega = MegaBlock::new(Protection::Read | Protection::Write).unwrap();
+        let mut buddies = BuddyAllocator::new(mega).unwrap();
+        let top = buddies.max_order();
+        assert_eq!(BlockDescriptor::SIZE << top, MegaBlock::SIZE);
+        let start = unsafe { buddies.mega_block().chunk.data() }.as_ptr::<u8>() as usize;
+
+        let orders = [0, 3, 1, 0, 5, 2, 0, 4];
+        let ranges: Vec<_> = orders.iter().map(|&order| {
+            let range = buddies.allocate(order).unwrap();
+            let size = BlockDescriptor::SIZE << order;
+            assert_eq!((range as usize - start) & (size - 1), 0);
+            unsafe { core::ptr::write_bytes(range, order as u8, size) };
+            (range, order)
+        }).collect();
+        // no two ranges overlap: each still holds its own fill pattern
+        for &(range, order) in &ranges {
+            let size = BlockDescriptor::SIZE << order;
+            let bytes = unsafe { core::slice::from_raw_parts(range, size) };
+            assert!(bytes.iter().all(|b| *b as usize == order));
+        }
+        assert_eq!(buddies.free_count(top), 0);
+
+        for &(range, order) in ranges.iter().rev().step_by(2).chain(ranges.iter().step_by(2)) {
+            unsafe { buddies.free(range, order) };
+        }
+        for order in 0..top { assert_eq!(buddies.free_count(order), 0); }
+        assert_eq!(buddies.free_count(top), 1);
+        // the order of the free range is kept in the descriptor of its first block
+        let used: Vec<_> = buddies.mega_block().blocks().map(|block| block.used_bytes()).collect();
+        assert_eq!(used[0], top + 1);
+        assert!(used[1..].iter().all(|used| *used == 0));
+        assert_eq!(buddies.allocate(top).map(|range| range as usize), Some(start));
+        assert_eq!(buddies.allocate(0), None);
+    }
+
+    #[test]
+    fn test_buddy_allocator_size() {
+        let rw = Protection::Read | Protection::Write;
+        let alignment = primitives::get_minimum_alignment().unwrap();
+        let odd = MegaBlock::with_size(alignment * 3, rw).unwrap();
+        assert!(BuddyAllocator::new(odd).is_err());
+    }
+
+    #[test]
+    fn test_size_classes() {
+        let last = SIZE_CLASS_COUNT - 1;
+        assert_eq!(SizeClassAllocator::class_size(last), BlockDescriptor::SIZE_IN_WORDS);
+        for class in 0..last {
+            let size = SizeClassAllocator::class_size(class);
+            assert!(size < SizeClassAllocator::class_size(class + 1));
+            assert_eq!(SizeClassAllocator::size_class(size), Some(class));
+            assert_eq!(SizeClassAllocator::size_class(size + 1), Some(class + 1));
+        }
+        assert_eq!(SizeClassAllocator::size_class(1), Some(0));
+        assert_eq!(SizeClassAllocator::size_class(32), Some(31));
+        assert_eq!(SizeClassAllocator::size_class(0), None);
+        assert_eq!(SizeClassAllocator::size_class(BlockDescriptor::SIZE_IN_WORDS + 1), None);
+    }
+
+    #[test]
+    fn test_size_class_allocator() {
+        let mut mega = MegaBlock::new(Protection::Read | Protection::Write).unwrap();
+        let mut cells = SizeClassAllocator::new();
+        assert_eq!(cells.allocate(1), None);
+        for block in mega.blocks_mut().iter().take(8) { cells.add_block(*block); }
+        let sizes = [1, 2, 3, 7, 32, 33, 100, 1, 2, 64, 200];
+        let allocated: Vec<_> = (0..5).flat_map(|_| sizes.iter()).map(|&words| {
+            let cell = cells.allocate(words).unwrap();
+            unsafe { core::ptr::write_bytes(cell.as_ptr::<usize>(), 0xAA, words) };
+            (cell, words)
+        }).collect();
+        let mut distinct: Vec<_> = allocated.iter().map(|(cell, _)| *cell).collect();
+        distinct.sort();
+        distinct.dedup();
+        assert_eq!(distinct.len(), allocated.len());
+
+        // freed cells are reused, most recent first, by requests of the same class
+        for &(cell, words) in &allocated { unsafe { cells.free(cell, words) }; }
+        for &(cell, words) in allocated.iter().rev() {
+            assert_eq!(cells.allocate(words), Some(cell));
+        }
+        // sizes sharing a class share cells
+        let (cell, words) = allocated[5];
+        assert_eq!(words, 33);
+        unsafe { cells.free(cell, words) };
+        assert_eq!(cells.allocate(40), Some(cell));
+        // the blocks are exhausted eventually
+        while cells.allocate(BlockDescriptor::SIZE_IN_WORDS).is_some() {}
+        assert_eq!(cells.allocate(BlockDescriptor::SIZE_IN_WORDS), None);
+    }
+
+    #[test]
+    fn test_slab() {
+        static TRIPLE: super::ObjectDescriptor =
+            super::ObjectDescriptor { unpacked_field_count: 2, pointer_count: 1, field_map: None };
+        let mut mega = MegaBlock::new(Protection::Read | Protection::Write).unwrap();
+        let mut slab = Slab::new(&TRIPLE, mega.blocks_mut()[0]);
+        assert_eq!(slab.cell_bytes(), 4 * core::mem::size_of::<usize>());
+        let mut objects = Vec::new();
+        while let Some(object) = slab.alloc() {
+            object.unpacked[0] = objects.len();
+            objects.push(object);
+        }
+        assert_eq!(objects.len(), BlockDescriptor::SIZE / slab.cell_bytes());
+
+        let mut freed = Vec::new();
+        for (i, object) in objects.into_iter().enumerate() {
+            if i % 2 == 1 {
+                freed.push(object.start_address());
+                unsafe { slab.free(object) };
+            }
+        }
+        let mut reused = Vec::new();
+        while let Some(object) = slab.alloc() {
+            assert_eq!(object.unpacked, [0, 0]);
+            assert!(core::ptr::eq(*object.descriptor, &TRIPLE));
+            reused.push(object.start_address());
+        }
+        freed.reverse();
+        assert_eq!(reused, freed);
+    }
 }
@@ -20,6 +20,7 @@
 use super::common;
 use super::object;
 use core::marker;
+use core::sync::atomic::{AtomicPtr, Ordering};
 use common::KiB;
 
 /// Memory block: collection of objects.
@@ -51,21 +52,45 @@ pub struct BlockDescriptor<'a> {
     phantom: marker::PhantomData<&'a ()>,
 }
 
-/// Iterator for `Object`s.
-pub struct ObjectIterator<'a> {
-    current: object::Object<'a>,
+/// Iterator for the starting addresses of `Object`s.
+///
+/// Only the descriptor pointer of each object is read to find the next one, the fields are never
+/// sliced. Iteration stops early at a corrupt descriptor, whose object would overflow or reach
+/// past the boundary.
+///
+/// Use [`Object::from`](../object/struct.Object.html) to materialize an object on demand.
+pub struct ObjectAddressIterator<'a> {
+    current: common::Address<'a>,
     boundary: common::Address<'a>,
 }
 
+impl<'a> Iterator for ObjectAddressIterator<'a> {
+    type Item = common::Address<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.current >= self.boundary { return None; }
+        let this_addr = self.current;
+        let descriptor = unsafe { *this_addr.as_ptr::<&object::ObjectDescriptor>() };
+        let word = core::mem::size_of::<usize>();
+        let remaining = unsafe { self.boundary.word_offset_from(this_addr) } as usize;
+        if !descriptor.validate(remaining) {
+            self.current = self.boundary;
+            return None;
+        }
+        let this_size = descriptor.total_size() * word;
+        self.current = unsafe { this_addr.offset(this_size as isize) };
+        Some(this_addr)
+    }
+}
+
+/// Iterator for `Object`s.
+pub struct ObjectIterator<'a>(ObjectAddressIterator<'a>);
+
 impl<'a> Iterator for ObjectIterator<'a> {
     type Item = object::Object<'a>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let this_addr = self.current.start_address();
-        let this_size = self.current.total_size();
-        let next_addr = unsafe { this_addr.offset(this_size as isize) };
-        if next_addr >= self.boundary { return None; }
-        Some(core::mem::replace(&mut self.current, object::Object::from(next_addr)))
+        self.0.next().map(object::Object::from)
     }
 }
 
@@ -81,11 +106,353 @@ impl<'a> BlockDescriptor<'a> {
         BlockDescriptor { start, free: start, phantom: marker::PhantomData }
     }
 
+    /// Number of bytes already allocated in this block.
+    pub fn used_bytes(&self) -> usize {
+        self.free as usize - self.start as usize
+    }
+
+    /// Number of bytes still available for allocation in this block.
+    pub fn remaining_bytes(&self) -> usize {
+        Self::SIZE - self.used_bytes()
+    }
+
+    /// Mark the whole block as empty, so that it can be reused without re-mapping.
+    ///
+    /// The memory contents are left untouched, objects previously in this block are simply
+    /// forgotten.
+    pub fn reset(&mut self) {
+        self.free = self.start;
+    }
+
+    /// Allocate an object described by `descriptor` by bumping the `free` pointer.
+    ///
+    /// The descriptor pointer is written at the start of the new object, and all its fields are
+    /// zero-initialized. Returns `None` if the rest of this block cannot hold the object, in
+    /// which case `free` is left untouched.
+    pub fn allocate(
+        &mut self, descriptor: &'a object::ObjectDescriptor) -> Option<object::Object<'a>> {
+        let word = core::mem::size_of::<usize>();
+        if !descriptor.validate(self.remaining_bytes() / word) { return None; }
+        let words = descriptor.total_size();
+        let bytes = words * word;
+        let address = common::Address::from(self.free);
+        unsafe {
+            let slots = address.as_ptr::<usize>();
+            slots.write(descriptor as *const _ as usize);
+            core::ptr::write_bytes(slots.add(1), 0, words - 1);
+            self.free = self.free.add(bytes);
+        }
+        Some(object::Object::from(address))
+    }
+
+    /// Iterate on the starting addresses of the objects in this block.
+    pub fn object_addresses(&self) -> ObjectAddressIterator<'a> {
+        ObjectAddressIterator {
+            current: common::Address::from(self.start),
+            boundary: common::Address::from(self.free),
+        }
+    }
+
     /// Iterate on the objects in this block.
     pub fn objects(&self) -> ObjectIterator<'a> {
-        ObjectIterator {
-            current: object::Object::from(common::Address::from(self.start)),
-            boundary: common::Address::from(self.free),
+        ObjectIterator(self.object_addresses())
+    }
+}
+
+/// Descriptors of filler objects of every size up to a whole `Block`, the one at index `i`
+/// describes fillers of `i + 1` words.
+static FILLERS: [object::ObjectDescriptor; BlockDescriptor::SIZE_IN_WORDS] = opaque();
+
+/// Descriptors of buffer objects, see [`buffer_descriptor`](fn.buffer_descriptor.html).
+static BUFFERS: [object::ObjectDescriptor; BlockDescriptor::SIZE_IN_WORDS] = opaque();
+
+/// Descriptors without pointers of every size up to a whole `Block`, the one at index `i` has `i`
+/// unpacked fields.
+const fn opaque() -> [object::ObjectDescriptor; BlockDescriptor::SIZE_IN_WORDS] {
+    const EMPTY: object::ObjectDescriptor =
+        object::ObjectDescriptor { unpacked_field_count: 0, pointer_count: 0, field_map: None };
+    let mut descriptors = [EMPTY; BlockDescriptor::SIZE_IN_WORDS];
+    let mut i = 0;
+    while i < descriptors.len() {
+        descriptors[i].unpacked_field_count = i;
+        i += 1;
+    }
+    descriptors
+}
+
+/// Turn the `words` words at `address` into a single filler object, i.e. an object with no
+/// pointers spanning the whole range, so that a block with holes in it stays iterable.
+///
+/// Only the descriptor pointer is written, the rest of the range is left untouched.
+///
+/// ```
+/// use memory_manager::block::{make_filler, is_filler, BlockDescriptor};
+/// let mut mem = [0usize; 8];
+/// let mut block = BlockDescriptor::new(mem.as_mut_ptr() as *mut u8);
+/// unsafe { make_filler(memory_manager::common::Address::from(block.start), 8) };
+/// block.free = unsafe { block.start.add(8 * core::mem::size_of::<usize>()) };
+/// let object = block.objects().next().unwrap();
+/// assert!(is_filler(object.descriptor));
+/// assert_eq!(object.total_size(), 8);
+/// ```
+///
+/// # Safety
+///
+/// The `words` words at `address` must be valid for writes, and hold no object still in use.
+///
+/// # Panics
+///
+/// Panics if `words` is 0 or larger than a `Block`.
+pub unsafe fn make_filler(address: common::Address, words: usize) {
+    assert!(0 < words && words <= BlockDescriptor::SIZE_IN_WORDS,
+            "filler of {} words does not fit in a block", words);
+    *address.as_ptr::<&object::ObjectDescriptor>() = &FILLERS[words - 1];
+}
+
+/// Whether `descriptor` describes a filler object made by [`make_filler`](fn.make_filler.html).
+pub fn is_filler(descriptor: &object::ObjectDescriptor) -> bool {
+    FILLERS.as_ptr_range().contains(&(descriptor as *const _))
+}
+
+/// The descriptor of a buffer object holding `words` words of raw data after its header. Unlike
+/// a filler, a buffer is an ordinary object, kept alive by pointers to it.
+///
+/// ```
+/// use memory_manager::block::{buffer_descriptor, is_filler};
+/// let descriptor = buffer_descriptor(4);
+/// assert_eq!((descriptor.total_size(), descriptor.pointer_count), (5, 0));
+/// assert!(!is_filler(descriptor));
+/// ```
+///
+/// # Panics
+///
+/// Panics if the object does not fit in a `Block`, i.e. if `words` is not below
+/// `BlockDescriptor::SIZE_IN_WORDS`.
+pub fn buffer_descriptor(words: usize) -> &'static object::ObjectDescriptor {
+    assert!(words < BlockDescriptor::SIZE_IN_WORDS,
+            "buffer of {} words does not fit in a block", words);
+    &BUFFERS[words]
+}
+
+/// A `Block` shared by several threads, allocated from with an atomic bump pointer.
+///
+/// Allocation performs a compare-and-swap loop on the `free` pointer, so no external locking is
+/// needed. Objects are only initialized after the space has been claimed, they must not be read
+/// by other threads before being published through some other synchronization.
+///
+/// ```
+/// use memory_manager::block::{AtomicBlock, BlockDescriptor};
+/// use memory_manager::object::ObjectDescriptor;
+/// static PAIR: ObjectDescriptor =
+///     ObjectDescriptor { unpacked_field_count: 2, pointer_count: 0, field_map: None };
+/// let mut mem = [0usize; BlockDescriptor::SIZE_IN_WORDS];
+/// let block = AtomicBlock::from(BlockDescriptor::new(mem.as_mut_ptr() as *mut u8));
+/// let object = block.allocate(&PAIR).unwrap();
+/// assert_eq!(block.used_bytes(), 3 * core::mem::size_of::<usize>());
+/// ```
+pub struct AtomicBlock<'a> {
+    start: *mut u8,
+    free: AtomicPtr<u8>,
+    phantom: marker::PhantomData<&'a ()>,
+}
+
+unsafe impl<'a> Sync for AtomicBlock<'a> {}
+unsafe impl<'a> Send for AtomicBlock<'a> {}
+
+impl<'a> From<BlockDescriptor<'a>> for AtomicBlock<'a> {
+    fn from(block: BlockDescriptor<'a>) -> Self {
+        AtomicBlock { start: block.start, free: AtomicPtr::new(block.free), phantom: block.phantom }
+    }
+}
+
+impl<'a> AtomicBlock<'a> {
+    /// Number of bytes already allocated in this block.
+    pub fn used_bytes(&self) -> usize {
+        self.free.load(Ordering::Acquire) as usize - self.start as usize
+    }
+
+    /// Number of bytes still available for allocation in this block.
+    pub fn remaining_bytes(&self) -> usize {
+        BlockDescriptor::SIZE - self.used_bytes()
+    }
+
+    /// Allocate an object described by `descriptor` by atomically bumping the `free` pointer,
+    /// retrying if another thread bumped it first.
+    ///
+    /// The descriptor pointer is written at the start of the new object, and all its fields are
+    /// zero-initialized. Returns `None` if the rest of this block cannot hold the object.
+    pub fn allocate(
+        &self, descriptor: &'a object::ObjectDescriptor) -> Option<object::Object<'a>> {
+        let word = core::mem::size_of::<usize>();
+        let end = self.start as usize + BlockDescriptor::SIZE;
+        let mut free = self.free.load(Ordering::Relaxed);
+        loop {
+            if !descriptor.validate((end - free as usize) / word) { return None; }
+            let next = unsafe { free.add(descriptor.total_size() * word) };
+            match self.free.compare_exchange_weak(
+                free, next, Ordering::AcqRel, Ordering::Relaxed) {
+                Ok(_) => break,
+                Err(current) => free = current,
+            }
+        }
+        let address = common::Address::from(free);
+        unsafe {
+            let slots = address.as_ptr::<usize>();
+            slots.write(descriptor as *const _ as usize);
+            core::ptr::write_bytes(slots.add(1), 0, descriptor.total_size() - 1);
+        }
+        Some(object::Object::from(address))
+    }
+
+    /// Turn back into a plain `BlockDescriptor`, once no other thread allocates any more.
+    pub fn into_inner(self) -> BlockDescriptor<'a> {
+        BlockDescriptor { start: self.start, free: self.free.into_inner(), phantom: self.phantom }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::{AtomicBlock, BlockDescriptor, make_filler, is_filler};
+    use super::common::Address;
+    use super::object::ObjectDescriptor;
+    use std::vec::Vec;
+
+    static SMALL: ObjectDescriptor =
+        ObjectDescriptor { unpacked_field_count: 1, pointer_count: 0, field_map: None };
+    static LARGE: ObjectDescriptor =
+        ObjectDescriptor { unpacked_field_count: 2, pointer_count: 3, field_map: None };
+
+    /// Lay out objects alternating between `SMALL` and `LARGE`, return the number of objects.
+    fn fill(mem: &mut [usize]) -> (BlockDescriptor<'_>, usize) {
+        let mut block = BlockDescriptor::new(mem.as_mut_ptr() as *mut u8);
+        let mut words = 0;
+        let mut count = 0;
+        loop {
+            let descriptor = if count % 2 == 0 { &SMALL } else { &LARGE };
+            let size = descriptor.total_size();
+            if words + size > mem.len() { break; }
+            mem[words] = descriptor as *const _ as usize;
+            words += size;
+            count += 1;
+        }
+        block.free = unsafe { block.start.add(words * core::mem::size_of::<usize>()) };
+        (block, count)
+    }
+
+    #[test]
+    fn test_object_iteration() {
+        let mut mem = [0usize; BlockDescriptor::SIZE_IN_WORDS];
+        let (block, count) = fill(&mut mem);
+        assert_eq!(block.object_addresses().count(), count);
+        assert_eq!(block.objects().count(), count);
+        let total: usize = block.objects().map(|o| o.total_size()).sum();
+        let used = block.free as usize - block.start as usize;
+        assert_eq!(total * core::mem::size_of::<usize>(), used);
+    }
+
+    #[test]
+    fn test_corrupt_descriptor() {
+        static HUGE: ObjectDescriptor =
+            ObjectDescriptor {
+                unpacked_field_count: usize::MAX, pointer_count: usize::MAX, field_map: None };
+        let mut mem = [0usize; BlockDescriptor::SIZE_IN_WORDS];
+        let (mut block, _) = fill(&mut mem);
+        let first = block.object_addresses().next().unwrap();
+        assert!(block.allocate(&HUGE).is_none());
+        unsafe { *first.as_ptr::<usize>() = &HUGE as *const _ as usize };
+        assert_eq!(block.object_addresses().count(), 0);
+        assert_eq!(block.objects().count(), 0);
+    }
+
+    #[test]
+    fn test_allocate_to_capacity() {
+        let mut mem = [usize::MAX; BlockDescriptor::SIZE_IN_WORDS];
+        let mut block = BlockDescriptor::new(mem.as_mut_ptr() as *mut u8);
+        let capacity = BlockDescriptor::SIZE_IN_WORDS / LARGE.total_size();
+        for _ in 0..capacity {
+            let object = block.allocate(&LARGE).unwrap();
+            assert_eq!(object.unpacked, &[0, 0]);
+            assert_eq!(object.pointers.len(), 3);
+            let start = object.unpacked.as_ptr() as usize - core::mem::size_of::<usize>();
+            assert!(start >= block.start as usize && start < block.free as usize);
         }
+        let free = block.free;
+        assert!(block.allocate(&LARGE).is_none());
+        assert_eq!(block.free, free);
+        assert!(block.free as usize <= block.start as usize + BlockDescriptor::SIZE);
+        assert_eq!(block.objects().count(), capacity);
+    }
+
+    #[test]
+    fn test_reset() {
+        let mut mem = [0usize; BlockDescriptor::SIZE_IN_WORDS];
+        let (mut block, _) = fill(&mut mem);
+        assert_eq!(block.used_bytes() + block.remaining_bytes(), BlockDescriptor::SIZE);
+        block.reset();
+        assert_eq!(block.objects().count(), 0);
+        assert_eq!(block.used_bytes(), 0);
+        assert_eq!(block.remaining_bytes(), BlockDescriptor::SIZE);
+        let first = unsafe { *(block.start as *const usize) };
+        assert_eq!(first, &SMALL as *const _ as usize);
+    }
+
+    #[test]
+    fn test_empty_block() {
+        let mut mem = [0usize; 4];
+        let block = BlockDescriptor::new(mem.as_mut_ptr() as *mut u8);
+        assert_eq!(block.objects().count(), 0);
+    }
+
+    #[test]
+    fn test_atomic_allocate() {
+        let size = SMALL.total_size() * core::mem::size_of::<usize>();
+        let mut mem = [usize::MAX; BlockDescriptor::SIZE_IN_WORDS];
+        let block = AtomicBlock::from(BlockDescriptor::new(mem.as_mut_ptr() as *mut u8));
+        let mut starts: Vec<usize> = std::thread::scope(|scope| {
+            let threads: Vec<_> = (0..16).map(|_| scope.spawn(|| {
+                let mut starts = Vec::new();
+                while let Some(object) = block.allocate(&SMALL) {
+                    assert_eq!(object.unpacked, &[0]);
+                    starts.push(object.start_address().as_ptr::<u8>() as usize);
+                }
+                starts
+            })).collect();
+            threads.into_iter().flat_map(|t| t.join().unwrap()).collect()
+        });
+        assert_eq!(starts.len() * size, BlockDescriptor::SIZE);
+        starts.sort();
+        starts.dedup();
+        assert_eq!(starts.len() * size, BlockDescriptor::SIZE);
+        let block = block.into_inner();
+        assert_eq!(block.remaining_bytes(), 0);
+        assert_eq!(block.objects().count(), starts.len());
+    }
+
+    #[test]
+    fn test_filler() {
+        let mut mem = [0usize; BlockDescriptor::SIZE_IN_WORDS];
+        let (block, count) = fill(&mut mem);
+        let addresses: Vec<_> = block.object_addresses().collect();
+        // merge objects 1, 2 and 3 into one
+        let words = SMALL.total_size() + 2 * LARGE.total_size();
+        unsafe { make_filler(addresses[1], words) };
+        let objects: Vec<_> = block.objects().collect();
+        assert_eq!(objects.len(), count - 2);
+        assert!(!is_filler(objects[0].descriptor) && !is_filler(objects[2].descriptor));
+        assert!(is_filler(objects[1].descriptor));
+        assert_eq!(objects[1].total_size(), words);
+        assert_eq!(objects[1].pointers.len(), 0);
+        assert_eq!(objects[2].start_address(), addresses[4]);
+        unsafe { make_filler(Address::from(block.start), BlockDescriptor::SIZE_IN_WORDS) };
+        assert_eq!(block.objects().count(), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "does not fit in a block")]
+    fn test_filler_too_large() {
+        let mut mem = [0usize; 1];
+        unsafe { make_filler(Address::from(mem.as_mut_ptr()), BlockDescriptor::SIZE_IN_WORDS + 1) };
     }
 }
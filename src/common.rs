@@ -21,6 +21,8 @@ use core::ptr;
 use core::mem;
 use core::marker;
 use core::fmt;
+use core::hash::{Hash, Hasher};
+use core::convert::TryFrom;
 
 /// Memory address with a valid lifetime.
 ///
@@ -44,6 +46,17 @@ use core::fmt;
 /// # let addr = Address::from(raw_p);
 /// assert_eq!(format!("{:?}", addr), "Address(0xdeadbeef)");
 /// ```
+///
+/// # Ordering and hashing
+///
+/// Addresses are compared and hashed by their numeric value, so they can be used as keys in
+/// ordered maps as well as hash maps (e.g. for side tables of mark bits or forwarding pointers).
+///
+/// ```
+/// # use memory_manager::common::Address;
+/// assert!(Address::from(0x1000 as *mut ()) < Address::from(0x1008 as *mut ()));
+/// ```
+#[repr(transparent)]
 #[derive(Ord, PartialOrd, Eq, PartialEq, Copy, Clone)]
 pub struct Address<'a> {
     address: *mut u8,
@@ -56,13 +69,59 @@ impl<'a> fmt::Debug for Address<'a> {
     }
 }
 
+impl<'a> Hash for Address<'a> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        (self.address as usize).hash(state)
+    }
+}
+
 impl<'a, T> From<*mut T> for Address<'a> {
     fn from(address: *mut T) -> Self {
         Address { address: address as *mut u8, phantom: marker::PhantomData }
     }
 }
 
+/// Error for raw addresses not properly aligned for the target type.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Misaligned {
+    /// The offending raw address.
+    pub address: usize,
+    /// The required alignment.
+    pub alignment: usize,
+}
+
+impl<'a> TryFrom<usize> for Address<'a> {
+    type Error = Misaligned;
+
+    /// Convert a raw address to an `Address`, which must be aligned to a `Word` (i.e. `usize`).
+    ///
+    /// See also [`try_from_addr_for`](struct.Address.html#method.try_from_addr_for).
+    fn try_from(raw: usize) -> Result<Self, Misaligned> {
+        Address::try_from_addr_for::<usize>(raw)
+    }
+}
+
 impl<'a> Address<'a> {
+    /// Convert a raw address to an `Address`, validating that it is properly aligned for `T`.
+    ///
+    /// ```
+    /// use memory_manager::common::{Address, Misaligned};
+    /// assert_eq!(
+    ///     Address::try_from_addr_for::<u32>(0x1004),
+    ///     Ok(Address::from(0x1004 as *mut ())));
+    /// assert_eq!(
+    ///     Address::try_from_addr_for::<u32>(0x1006),
+    ///     Err(Misaligned { address: 0x1006, alignment: 4 }));
+    /// ```
+    pub fn try_from_addr_for<T>(raw: usize) -> Result<Self, Misaligned> {
+        let alignment = mem::align_of::<T>();
+        if raw & (alignment - 1) == 0 {
+            Ok(Address::from(raw as *mut u8))
+        } else {
+            Err(Misaligned { address: raw, alignment })
+        }
+    }
+
     /// Convert an `Address` to a raw pointer of some type `T`.
     ///
     /// Note that raw pointers do not have lifetime attached, so the lifetime is dropped after
@@ -82,7 +141,27 @@ impl<'a> Address<'a> {
     /// let raw_p = addr.as_ptr::<usize>();
     /// ```
     pub fn as_ptr<T>(&self) -> *mut T {
-        assert_aligned(self.address)
+        self.try_as_ptr().unwrap_or_else(|| assert_aligned(self.address))
+    }
+
+    /// Convert an `Address` to a raw pointer of some type `T`, or `None` if the memory address
+    /// is not properly aligned for `T`.
+    ///
+    /// Unlike [`as_ptr`](#method.as_ptr), this never panics, which suits probing addresses that
+    /// may not be valid at all (e.g. conservative roots).
+    ///
+    /// ```
+    /// use memory_manager::common::Address;
+    /// let addr = Address::from(0xDEAD_BEEF as *mut ());
+    /// assert_eq!(addr.try_as_ptr::<usize>(), None);
+    /// assert_eq!(addr.try_as_ptr::<u8>(), Some(0xDEAD_BEEF as *mut u8));
+    /// ```
+    pub fn try_as_ptr<T>(&self) -> Option<*mut T> {
+        if self.address as usize & (mem::align_of::<T>() - 1) == 0 {
+            Some(self.address as *mut T)
+        } else {
+            None
+        }
     }
 
     /// Add an offset to an `Address`.
@@ -97,6 +176,150 @@ impl<'a> Address<'a> {
     pub unsafe fn offset(&self, count: isize) -> Self {
         Address::from(self.address.offset(count))
     }
+
+    /// Add an offset to an `Address`, wrapping around the address space.
+    ///
+    /// This method is analogous to `*mut T::wrapping_offset`, and is safe to use on arbitrary
+    /// values (e.g. words found while scanning a stack conservatively).
+    ///
+    /// ```
+    /// use memory_manager::common::Address;
+    /// let addr = Address::from(usize::MAX as *mut ());
+    /// assert_eq!(addr.wrapping_offset(1), Address::null());
+    /// assert_eq!(Address::null().wrapping_offset(-1), addr);
+    /// ```
+    pub fn wrapping_offset(&self, count: isize) -> Self {
+        Address::from(self.address.wrapping_offset(count))
+    }
+
+    /// Create a null `Address`.
+    ///
+    /// ```
+    /// use memory_manager::common::Address;
+    /// assert_eq!(Address::null(), Address::from(core::ptr::null_mut::<u8>()));
+    /// ```
+    pub fn null() -> Self {
+        Address::from(ptr::null_mut::<u8>())
+    }
+
+    /// Check whether this `Address` is null.
+    ///
+    /// ```
+    /// use memory_manager::common::Address;
+    /// assert!(Address::null().is_null());
+    /// assert!(!Address::from(0x1000 as *mut ()).is_null());
+    /// ```
+    pub fn is_null(&self) -> bool {
+        self.address.is_null()
+    }
+
+    /// Check whether this `Address` is a multiple of `alignment`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `alignment` is not a power of 2.
+    ///
+    /// ```
+    /// use memory_manager::common::Address;
+    /// assert!(Address::from(0x1000 as *mut ()).is_aligned(0x1000));
+    /// assert!(!Address::from(0x1008 as *mut ()).is_aligned(16));
+    /// ```
+    pub fn is_aligned(&self, alignment: usize) -> bool {
+        self.address as usize & alignment_mask(alignment) == 0
+    }
+
+    /// Round this `Address` up to the next multiple of `alignment`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `alignment` is not a power of 2.
+    ///
+    /// ```
+    /// use memory_manager::common::Address;
+    /// let addr = |raw: usize| Address::from(raw as *mut ());
+    /// assert_eq!(addr(0x1000).align_up(0x1000), addr(0x1000));
+    /// assert_eq!(addr(0x1001).align_up(0x1000), addr(0x2000));
+    /// ```
+    pub fn align_up(&self, alignment: usize) -> Self {
+        let mask = alignment_mask(alignment);
+        let padding = (alignment - (self.address as usize & mask)) & mask;
+        Address::from(self.address.wrapping_add(padding))
+    }
+
+    /// Round this `Address` down to the previous multiple of `alignment`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `alignment` is not a power of 2.
+    ///
+    /// ```
+    /// use memory_manager::common::Address;
+    /// let addr = |raw: usize| Address::from(raw as *mut ());
+    /// assert_eq!(addr(0x1000).align_down(0x1000), addr(0x1000));
+    /// assert_eq!(addr(0x1fff).align_down(0x1000), addr(0x1000));
+    /// ```
+    pub fn align_down(&self, alignment: usize) -> Self {
+        let excess = self.address as usize & alignment_mask(alignment);
+        Address::from(self.address.wrapping_sub(excess))
+    }
+
+    /// Calculate the distance from `origin` to this `Address` in bytes.
+    ///
+    /// This method is analogous to `*mut T::offset_from`.
+    ///
+    /// # Safety
+    ///
+    /// Both addresses must be within (or one past the end of) the same allocation.
+    ///
+    /// ```
+    /// use memory_manager::common::Address;
+    /// let mut words = [0usize; 4];
+    /// let a = Address::from(words.as_mut_ptr());
+    /// assert_eq!(unsafe { a.offset(8).byte_offset_from(a) }, 8);
+    /// assert_eq!(unsafe { a.byte_offset_from(a.offset(8)) }, -8);
+    /// ```
+    pub unsafe fn byte_offset_from(&self, origin: Address) -> isize {
+        self.address.offset_from(origin.address)
+    }
+
+    /// Calculate the distance from `origin` to this `Address` in words (i.e. `usize`), rounding
+    /// towards zero.
+    ///
+    /// # Safety
+    ///
+    /// See [`byte_offset_from`](#method.byte_offset_from).
+    ///
+    /// ```
+    /// use memory_manager::common::Address;
+    /// let mut words = [0usize; 4];
+    /// let a = Address::from(words.as_mut_ptr());
+    /// let word = core::mem::size_of::<usize>() as isize;
+    /// assert_eq!(unsafe { a.offset(3 * word).word_offset_from(a) }, 3);
+    /// ```
+    pub unsafe fn word_offset_from(&self, origin: Address) -> isize {
+        self.byte_offset_from(origin) / mem::size_of::<usize>() as isize
+    }
+}
+
+/// Round `value` up to the next multiple of `alignment`.
+///
+/// # Panics
+///
+/// Panics if `alignment` is not a power of 2, or if the result overflows.
+///
+/// ```
+/// use memory_manager::common::align_up;
+/// assert_eq!(align_up(4096, 4096), 4096);
+/// assert_eq!(align_up(4097, 4096), 8192);
+/// ```
+pub fn align_up(value: usize, alignment: usize) -> usize {
+    let mask = alignment_mask(alignment);
+    value.checked_add(mask).expect("size overflow in align_up") & !mask
+}
+
+fn alignment_mask(alignment: usize) -> usize {
+    assert!(alignment.is_power_of_two(), "alignment {} is not a power of 2", alignment);
+    alignment - 1
 }
 
 /// Assert that some memory is properly aligned.
@@ -176,3 +399,38 @@ pub const MiB: usize = 1024 * KiB;
 /// size in Gibibytes, as defined in IEC 60027-2
 #[allow(non_upper_case_globals)]
 pub const GiB: usize = 1024 * MiB;
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::{Address, Misaligned};
+    use core::convert::TryFrom;
+
+    #[test]
+    fn test_try_from_usize() {
+        let word = core::mem::size_of::<usize>();
+        assert_eq!(Address::try_from(0x1000), Ok(Address::from(0x1000 as *mut u8)));
+        assert_eq!(Address::try_from(0x1001), Err(Misaligned { address: 0x1001, alignment: word }));
+        assert!(Address::try_from_addr_for::<u8>(0x1001).is_ok());
+        assert!(Address::try_from_addr_for::<u16>(0x1001).is_err());
+    }
+
+    #[test]
+    fn test_try_as_ptr() {
+        assert_eq!(Address::from(0xDEAD_BEEF as *mut u8).try_as_ptr::<usize>(), None);
+        let aligned = Address::from(0xDEAD_BEE0usize as *mut u8);
+        assert_eq!(aligned.try_as_ptr::<usize>(), Some(0xDEAD_BEE0usize as *mut usize));
+    }
+
+    #[test]
+    fn test_hash() {
+        use std::collections::HashSet;
+        let addr = |raw: usize| Address::from(raw as *mut u8);
+        let set: HashSet<_> = [0x1000, 0x1008, 0x2000, 0x1000].iter().map(|&a| addr(a)).collect();
+        assert_eq!(set.len(), 3);
+        assert!(set.contains(&addr(0x1008)));
+        assert!(set.contains(&Address::from(0x2000 as *mut u32)));
+        assert!(!set.contains(&addr(0x1010)));
+    }
+}
@@ -0,0 +1,2080 @@
+/*
+ * garbage-collected memory manager in Rust
+ * Copyright (C) 2020  Xie Ruifeng
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Garbage collection.
+use super::common::Address;
+use super::object::{Object, ObjectDescriptor, FieldKind, WriteBarrier};
+use super::allocate::{MegaBlockList, LargeObjectSpace};
+use super::block::{BlockDescriptor, make_filler};
+
+use alloc::collections::{BTreeMap, BTreeSet, VecDeque};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::cell::{Cell, RefCell};
+use core::convert::TryFrom;
+use core::fmt;
+use core::sync::atomic::{AtomicU8, Ordering};
+
+const WORD: usize = core::mem::size_of::<usize>();
+const BITS_PER_WORD: usize = WORD * 8;
+
+fn raw(address: Address) -> usize {
+    address.as_ptr::<u8>() as usize
+}
+
+/// Address ranges of all the chunks in a mega-block list.
+fn ranges(blocks: &MegaBlockList) -> Vec<(usize, usize)> {
+    blocks.chunks().map(|chunk| {
+        let start = raw(unsafe { chunk.data() });
+        (start, start + chunk.size())
+    }).collect()
+}
+
+/// Address ranges of the chunks of the unpinned mega-blocks in a list.
+fn unpinned_ranges(blocks: &MegaBlockList) -> Vec<(usize, usize)> {
+    blocks.iter().filter(|mega_block| !mega_block.is_pinned()).map(|mega_block| {
+        let start = raw(unsafe { mega_block.chunk.data() });
+        (start, start + mega_block.chunk.size())
+    }).collect()
+}
+
+/// The objects of the pinned mega-blocks in a list (see [`MegaBlock::pin`]), which every
+/// collection keeps in place and traces as additional roots: their memory may still be in use,
+/// e.g. by foreign code holding a [`PinnedBuffer`].
+///
+/// [`MegaBlock::pin`]: ../allocate/struct.MegaBlock.html#method.pin
+/// [`PinnedBuffer`]: ../allocate/struct.PinnedBuffer.html
+fn pinned_objects<'a>(blocks: &MegaBlockList) -> Vec<Address<'a>> {
+    blocks.iter().filter(|mega_block| mega_block.is_pinned())
+        .flat_map(|mega_block| mega_block.blocks())
+        .flat_map(|block| block.object_addresses())
+        .map(|address| Address::from(address.as_ptr::<u8>()))
+        .collect()
+}
+
+/// Root set: addresses of the objects the collectors start tracing from.
+///
+/// Roots are registered through [`register`](#method.register), which returns a [`Handle`]
+/// unregistering the root when dropped. Moving collectors update the registered addresses, use
+/// [`Handle::address`] to find the current location of a root object.
+///
+/// A root set is thus a table of indirection slots: the mutator holds handles (indices into the
+/// table) instead of raw pointers, and [`scavenge`](fn.scavenge.html) or
+/// [`mark_compact`](fn.mark_compact.html) fix the slots up in one place after moving objects.
+///
+/// ```
+/// use memory_manager::common::Address;
+/// use memory_manager::gc::RootSet;
+/// let roots = RootSet::new();
+/// {
+///     let handle = roots.register(Address::from(0x1000 as *mut ()));
+///     assert_eq!(roots.len(), 1);
+/// }
+/// assert!(roots.is_empty());
+/// ```
+///
+/// [`Handle`]: struct.Handle.html
+/// [`Handle::address`]: struct.Handle.html#method.address
+#[derive(Default)]
+pub struct RootSet<'a> {
+    slots: RefCell<Vec<Option<Address<'a>>>>,
+    vacant: RefCell<Vec<usize>>,
+    count: Cell<usize>,
+}
+
+impl<'a> RootSet<'a> {
+    /// Constructor for `RootSet`.
+    pub fn new() -> Self {
+        RootSet::default()
+    }
+
+    /// Register the object at `address` as a root, until the returned `Handle` is dropped.
+    ///
+    /// Registering the same address several times is fine, every registration is independent.
+    pub fn register(&self, address: Address<'a>) -> Handle<'_, 'a> {
+        let mut slots = self.slots.borrow_mut();
+        let index = match self.vacant.borrow_mut().pop() {
+            Some(index) => { slots[index] = Some(address); index }
+            None => { slots.push(Some(address)); slots.len() - 1 }
+        };
+        self.count.set(self.count.get() + 1);
+        Handle { roots: self, index }
+    }
+
+    /// Number of registered roots.
+    pub fn len(&self) -> usize { self.count.get() }
+
+    /// Whether no root is registered.
+    pub fn is_empty(&self) -> bool { self.len() == 0 }
+
+    fn unregister(&self, index: usize) {
+        self.slots.borrow_mut()[index] = None;
+        self.vacant.borrow_mut().push(index);
+        self.count.set(self.count.get() - 1);
+    }
+
+    /// Apply `f` to every registered root address, possibly updating it.
+    fn for_each_mut(&self, f: impl FnMut(&mut Address<'a>)) {
+        self.slots.borrow_mut().iter_mut().flatten().for_each(f);
+    }
+}
+
+/// Handle to a registered root, unregisters the root when dropped.
+pub struct Handle<'r, 'a> {
+    roots: &'r RootSet<'a>,
+    index: usize,
+}
+
+impl<'r, 'a> Handle<'r, 'a> {
+    /// The current address of the root object.
+    pub fn address(&self) -> Address<'a> {
+        self.roots.slots.borrow()[self.index].unwrap()
+    }
+
+    /// The root object.
+    pub fn object(&self) -> Object<'a> {
+        Object::from(self.address())
+    }
+}
+
+impl<'r, 'a> Drop for Handle<'r, 'a> {
+    fn drop(&mut self) {
+        self.roots.unregister(self.index)
+    }
+}
+
+/// Mark bits for all the words in a memory chunk.
+struct ChunkMarks {
+    start: usize,
+    end: usize,
+    bits: Vec<usize>,
+}
+
+/// Side bitmap of mark bits, one bit per word of every mega-block (and large object chunk) in
+/// the heap.
+///
+/// Marking never writes to the objects themselves.
+struct MarkBitmap(Vec<ChunkMarks>);
+
+impl MarkBitmap {
+    fn new(blocks: &MegaBlockList, large: &LargeObjectSpace) -> Self {
+        let large = large.chunks().map(|chunk| {
+            let start = raw(unsafe { chunk.data() });
+            (start, start + chunk.size())
+        });
+        MarkBitmap(ranges(blocks).into_iter().chain(large).map(|(start, end)| ChunkMarks {
+            start,
+            end,
+            bits: vec![0; ((end - start) / WORD + BITS_PER_WORD - 1) / BITS_PER_WORD],
+        }).collect())
+    }
+
+    /// The chunk and word index for `address`, or `None` if it is outside the heap.
+    fn locate(&self, address: usize) -> Option<(usize, usize)> {
+        let i = self.0.iter().position(|c| c.start <= address && address < c.end)?;
+        Some((i, (address - self.0[i].start) / WORD))
+    }
+
+    fn is_marked(&self, address: usize) -> bool {
+        match self.locate(address) {
+            Some((i, word)) => {
+                let mask = 1 << (word % BITS_PER_WORD);
+                self.0[i].bits[word / BITS_PER_WORD] & mask != 0
+            }
+            None => false,
+        }
+    }
+
+    /// Set the mark bit for `address`, returns `true` if it was not marked before.
+    ///
+    /// Addresses outside the heap are never marked.
+    fn mark(&mut self, address: usize) -> bool {
+        match self.locate(address) {
+            Some((i, word)) => {
+                let bits = &mut self.0[i].bits[word / BITS_PER_WORD];
+                let mask = 1 << (word % BITS_PER_WORD);
+                let fresh = *bits & mask == 0;
+                *bits |= mask;
+                fresh
+            }
+            None => false,
+        }
+    }
+}
+
+/// Statistics of a garbage collection.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct GcStats {
+    /// Bytes occupied by the surviving objects.
+    pub bytes_live: usize,
+    /// Bytes made available for allocation again.
+    pub bytes_reclaimed: usize,
+    /// Number of objects whose pointer fields were traced.
+    pub objects_traced: usize,
+    /// Number of blocks holding objects before the collection and none after it.
+    pub blocks_freed: usize,
+}
+
+/// A one-line summary of a collection.
+///
+/// ```
+/// use memory_manager::gc::GcStats;
+/// let stats = GcStats { bytes_live: 64, bytes_reclaimed: 32, objects_traced: 2, blocks_freed: 1 };
+/// assert_eq!(
+///     stats.to_string(),
+///     "traced 2 objects, 64 bytes live, 32 bytes reclaimed, 1 blocks freed");
+/// ```
+impl fmt::Display for GcStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "traced {} objects, {} bytes live, {} bytes reclaimed, {} blocks freed",
+               self.objects_traced, self.bytes_live, self.bytes_reclaimed, self.blocks_freed)
+    }
+}
+
+/// A phase of a garbage collection, see [`GcObserver`](trait.GcObserver.html).
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Phase {
+    /// Tracing the live objects from the roots, without moving them.
+    Mark,
+    /// Reclaiming the unmarked objects in place.
+    Sweep,
+    /// Sliding the marked objects together and updating the pointers to them.
+    Compact,
+    /// Copying the live objects out of the collected space while tracing them.
+    Evacuate,
+}
+
+/// Hooks invoked by the collectors around each of their phases, e.g. for logging or metrics.
+///
+/// Every `*_with_observer` collector reports its phases in order, each phase starting after the
+/// previous one ended. The statistics passed at the end of a phase are accumulated since the start
+/// of the collection, those at the end of the last phase are the ones returned.
+pub trait GcObserver {
+    /// Called before `phase` starts.
+    fn on_phase_start(&mut self, phase: Phase);
+    /// Called after `phase` ends, with the statistics of the collection so far.
+    fn on_phase_end(&mut self, phase: Phase, stats: GcStats);
+}
+
+/// The observer ignoring all the phases, used by the collectors taking no observer.
+pub struct NoObserver;
+
+impl GcObserver for NoObserver {
+    fn on_phase_start(&mut self, _: Phase) {}
+    fn on_phase_end(&mut self, _: Phase, _: GcStats) {}
+}
+
+/// Mark-sweep garbage collection.
+///
+/// Objects reachable from `roots` (transitively through their pointer fields) survive, all other
+/// objects in `blocks` are reclaimed. Pointers out of `blocks` are never followed, except for the
+/// roots themselves. All the objects of pinned mega-blocks (see [`MegaBlock::pin`]) survive, and
+/// serve as additional roots.
+///
+/// Sweeping does not move objects: the `free` pointer of every block is lowered to the end of its
+/// last surviving object, so that blocks with no surviving objects are empty again. Every run of
+/// dead objects before that is merged into a single filler object (see
+/// [`make_filler`](../block/fn.make_filler.html)), keeping the block iterable. Only the space
+/// after the last surviving object of a block counts as reclaimed.
+///
+/// [`MegaBlock::pin`]: ../allocate/struct.MegaBlock.html#method.pin
+pub fn mark_sweep(roots: &RootSet, blocks: &mut MegaBlockList) -> GcStats {
+    mark_sweep_with_large(roots, blocks, &mut LargeObjectSpace::new())
+}
+
+/// Mark-sweep garbage collection, also collecting the objects in a large object space.
+///
+/// Works like [`mark_sweep`](fn.mark_sweep.html), except that pointers into `large` are followed
+/// as well, and the chunk of every unreachable large object is freed.
+pub fn mark_sweep_with_large(
+    roots: &RootSet, blocks: &mut MegaBlockList, large: &mut LargeObjectSpace) -> GcStats {
+    mark_sweep_with_options(roots, blocks, large, SweepOptions::default())
+}
+
+/// Byte pattern written over the bodies of reclaimed objects, see `SweepOptions`.
+pub const POISON: u8 = 0xDD;
+
+/// Options for the sweep phase of [`mark_sweep_with_options`](fn.mark_sweep_with_options.html).
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct SweepOptions {
+    /// Fill the bodies of reclaimed objects with [`POISON`](constant.POISON.html), to catch
+    /// uses after free. Object headers are left untouched, so that blocks stay iterable.
+    ///
+    /// Enabled by default in debug builds only.
+    pub poison_on_free: bool,
+}
+
+impl Default for SweepOptions {
+    fn default() -> Self {
+        SweepOptions { poison_on_free: cfg!(debug_assertions) }
+    }
+}
+
+/// Mark-sweep garbage collection with explicit sweep `options`.
+///
+/// See [`mark_sweep_with_large`](fn.mark_sweep_with_large.html), which uses the default options.
+pub fn mark_sweep_with_options(
+    roots: &RootSet, blocks: &mut MegaBlockList, large: &mut LargeObjectSpace,
+    options: SweepOptions) -> GcStats {
+    mark_sweep_with_observer(roots, blocks, large, options, &mut NoObserver)
+}
+
+/// Mark-sweep garbage collection, reporting the `Mark` and `Sweep` phases to `observer`.
+///
+/// See [`mark_sweep_with_options`](fn.mark_sweep_with_options.html).
+pub fn mark_sweep_with_observer(
+    roots: &RootSet, blocks: &mut MegaBlockList, large: &mut LargeObjectSpace,
+    options: SweepOptions, observer: &mut dyn GcObserver) -> GcStats {
+    let mut marks = MarkBitmap::new(blocks, large);
+    let mut stats = GcStats::default();
+    observer.on_phase_start(Phase::Mark);
+    mark(roots, pinned_objects(blocks), &mut marks, &mut stats);
+    observer.on_phase_end(Phase::Mark, stats);
+    observer.on_phase_start(Phase::Sweep);
+    sweep(blocks, &marks, options, &mut stats);
+    large.retain(|address| {
+        let size = Object::from(address).total_size() * WORD;
+        let live = marks.is_marked(raw(address));
+        if live { stats.bytes_live += size; } else { stats.bytes_reclaimed += size; }
+        live
+    });
+    observer.on_phase_end(Phase::Sweep, stats);
+    stats
+}
+
+fn mark(roots: &RootSet, pinned: Vec<Address>, marks: &mut MarkBitmap, stats: &mut GcStats) {
+    let mut pending = pinned;
+    roots.for_each_mut(|root| {
+        marks.mark(raw(*root));
+        pending.extend(Object::from(*root).trace_pointers_mut().map(|p| *p));
+        stats.objects_traced += 1;
+    });
+    while let Some(address) = pending.pop() {
+        if marks.mark(raw(address)) {
+            pending.extend(Object::from(address).trace_pointers_mut().map(|p| *p));
+            stats.objects_traced += 1;
+        }
+    }
+}
+
+fn sweep(
+    blocks: &mut MegaBlockList, marks: &MarkBitmap, options: SweepOptions, stats: &mut GcStats) {
+    for mega_block in blocks.iter_mut() {
+        for block in mega_block.blocks_mut() {
+            sweep_block(block, marks, options, stats);
+        }
+    }
+}
+
+fn sweep_block(
+    block: &mut BlockDescriptor, marks: &MarkBitmap, options: SweepOptions, stats: &mut GcStats) {
+    let mut live_end = block.start;
+    let mut dead_run = None;
+    for address in block.object_addresses() {
+        if marks.is_marked(raw(address)) {
+            if let Some(start) = dead_run.take() {
+                unsafe { make_filler(start, address.word_offset_from(start) as usize) };
+            }
+            let size = Object::from(address).total_size() * WORD;
+            live_end = unsafe { address.offset(size as isize) }.as_ptr();
+            stats.bytes_live += size;
+        } else {
+            if options.poison_on_free { poison(address); }
+            if dead_run.is_none() { dead_run = Some(address); }
+        }
+    }
+    let used = block.used_bytes();
+    block.free = live_end;
+    stats.bytes_reclaimed += used - block.used_bytes();
+    if used > 0 && live_end == block.start { stats.blocks_freed += 1; }
+}
+
+/// Lazy mark-sweep garbage collection.
+///
+/// Objects are marked like with [`mark_sweep`](fn.mark_sweep.html), but no block is swept yet:
+/// the returned [`LazySweep`] sweeps them one by one as allocation needs space, so that there is
+/// no separate sweep pause.
+///
+/// ```
+/// use memory_manager::allocate::{MegaBlock, MegaBlockList, Protection};
+/// use memory_manager::gc::{mark_sweep_lazy, RootSet};
+/// use memory_manager::object::ObjectDescriptor;
+/// static LEAF: ObjectDescriptor =
+///     ObjectDescriptor { unpacked_field_count: 1, pointer_count: 0, field_map: None };
+/// let mut mega_block = MegaBlock::new(Protection::Read | Protection::Write)?;
+/// let leaf = mega_block.blocks_mut()[0].allocate(&LEAF).unwrap();
+/// let garbage = leaf.start_address().as_ptr::<u8>();
+/// let mut blocks = MegaBlockList::new();
+/// blocks.push_front(Box::new(mega_block));
+/// let roots = RootSet::new();
+/// let mut sweep = mark_sweep_lazy(&roots, &mut blocks);
+/// assert_eq!(sweep.stats().bytes_reclaimed, 0);
+/// let object = sweep.allocate(&LEAF).unwrap();
+/// assert_eq!(object.start_address().as_ptr::<u8>(), garbage);
+/// assert_eq!(sweep.stats().bytes_reclaimed, 2 * core::mem::size_of::<usize>());
+/// # Ok::<(), memory_manager::primitives::MMapError>(())
+/// ```
+///
+/// [`LazySweep`]: struct.LazySweep.html
+pub fn mark_sweep_lazy<'l>(roots: &RootSet, blocks: &'l mut MegaBlockList) -> LazySweep<'l> {
+    let mut marks = MarkBitmap::new(blocks, &LargeObjectSpace::new());
+    let mut stats = GcStats::default();
+    mark(roots, pinned_objects(blocks), &mut marks, &mut stats);
+    let blocks = blocks.iter_mut().flat_map(|m| m.blocks_mut().iter_mut()).collect();
+    LazySweep { blocks, swept: 0, marks, stats }
+}
+
+/// The sweep phase of a lazy mark-sweep collection, see
+/// [`mark_sweep_lazy`](fn.mark_sweep_lazy.html).
+///
+/// A sweep cursor goes over the blocks of the marked mega-blocks in order, each one is swept like
+/// with [`mark_sweep`](fn.mark_sweep.html) once allocation runs out of space in the blocks swept
+/// before it. New objects only go to swept blocks, after their last surviving object, so they
+/// are never mistaken for dead ones. Once every block is swept, the statistics are the same as
+/// the ones of an eager `mark_sweep` with the default options.
+///
+/// Dropping a `LazySweep` leaves the dead objects of the blocks not swept yet in place, until the
+/// next collection.
+pub struct LazySweep<'l> {
+    blocks: Vec<&'l mut BlockDescriptor<'l>>,
+    swept: usize,
+    marks: MarkBitmap,
+    stats: GcStats,
+}
+
+impl<'l> LazySweep<'l> {
+    /// Allocate an object described by `descriptor` in the last swept block, sweeping the next
+    /// blocks until one has room for it. Returns `None` once every block is swept and full.
+    pub fn allocate(&mut self, descriptor: &'l ObjectDescriptor) -> Option<Object<'l>> {
+        loop {
+            if let Some(block) = self.swept.checked_sub(1).map(|i| &mut self.blocks[i]) {
+                if let Some(object) = block.allocate(descriptor) { return Some(object); }
+            }
+            if !self.sweep_next() { return None; }
+        }
+    }
+
+    /// Sweep the next block, returns `false` if every block is swept already.
+    pub fn sweep_next(&mut self) -> bool {
+        match self.blocks.get_mut(self.swept) {
+            Some(block) => {
+                sweep_block(block, &self.marks, SweepOptions::default(), &mut self.stats);
+                self.swept += 1;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Statistics of the collection so far: the marking, and the blocks swept up to now.
+    pub fn stats(&self) -> GcStats { self.stats }
+
+    /// Sweep the remaining blocks, and return the statistics of the whole collection.
+    pub fn finish(mut self) -> GcStats {
+        while self.sweep_next() {}
+        self.stats
+    }
+}
+
+/// Fill the body of the dead object at `address` with `POISON`, keeping its header.
+fn poison(address: Address) {
+    let size = Object::from(address).total_size() * WORD;
+    unsafe { core::ptr::write_bytes(address.as_ptr::<u8>().add(WORD), POISON, size - WORD) };
+}
+
+/// Mark-compact garbage collection, in the style of the Lisp 2 collector.
+///
+/// Objects reachable from `roots` survive like with [`mark_sweep`](fn.mark_sweep.html), but are
+/// then slid down towards the start of their block, so that the live objects of every block end
+/// up contiguous and the free space is in one piece after them. Objects never move to another
+/// block. All the pointer fields and `roots` are updated to the new locations, including the
+/// pointer fields of roots outside `blocks`. The objects of pinned mega-blocks survive and stay
+/// in place, see [`mark_sweep`](fn.mark_sweep.html).
+///
+/// After marking, the collection makes three passes over the live objects:
+///
+/// 1. compute the new address of every live object, and store it as a forwarding pointer in its
+///    descriptor slot (see [`Object::set_forwarded`]), keeping the descriptor aside;
+/// 2. update every pointer to a live object with its forwarding pointer;
+/// 3. restore the descriptors, and move the objects to their new addresses.
+///
+/// [`Object::set_forwarded`]: ../object/struct.Object.html#method.set_forwarded
+pub fn mark_compact(roots: &RootSet, blocks: &mut MegaBlockList) -> GcStats {
+    mark_compact_with_observer(roots, blocks, &mut NoObserver)
+}
+
+/// Mark-compact garbage collection, reporting the `Mark` and `Compact` phases to `observer`.
+///
+/// See [`mark_compact`](fn.mark_compact.html).
+pub fn mark_compact_with_observer(
+    roots: &RootSet, blocks: &mut MegaBlockList, observer: &mut dyn GcObserver) -> GcStats {
+    let mut marks = MarkBitmap::new(blocks, &LargeObjectSpace::new());
+    let mut stats = GcStats::default();
+    observer.on_phase_start(Phase::Mark);
+    mark(roots, pinned_objects(blocks), &mut marks, &mut stats);
+    observer.on_phase_end(Phase::Mark, stats);
+    observer.on_phase_start(Phase::Compact);
+
+    // pass 1: forwarding addresses, live objects are listed in address order for every block
+    let mut live: Vec<(Address, &ObjectDescriptor, Address)> = Vec::new();
+    for mega_block in blocks.iter_mut() {
+        let pinned = mega_block.is_pinned();
+        for block in mega_block.blocks_mut() {
+            let mut dest = Address::from(block.start);
+            for address in block.object_addresses() {
+                if !marks.is_marked(raw(address)) { continue; }
+                // pinned objects are forwarded to themselves, and still get their pointers updated
+                if pinned { dest = address; }
+                let mut object = Object::from(address);
+                let descriptor = *object.descriptor;
+                live.push((address, descriptor, dest));
+                object.set_forwarded(dest);
+                dest = unsafe { dest.offset((descriptor.total_size() * WORD) as isize) };
+            }
+            let used = block.used_bytes();
+            block.free = dest.as_ptr();
+            stats.bytes_live += block.used_bytes();
+            stats.bytes_reclaimed += used - block.used_bytes();
+            if used > 0 && block.free == block.start { stats.blocks_freed += 1; }
+        }
+    }
+
+    // pass 2: pointers to live objects, which are all forwarded by now
+    let forward = |pointer: &mut Address| {
+        if let Some(new_address) = Object::forwarding_address(*pointer) { *pointer = new_address; }
+    };
+    let mut outside = BTreeSet::new();
+    roots.for_each_mut(|root| match Object::forwarding_address(*root) {
+        Some(new_address) => *root = new_address,
+        // forwarding twice would be wrong: new addresses are old addresses of other objects
+        None if outside.insert(*root) =>
+            Object::from(*root).trace_pointers_mut().for_each(forward),
+        None => {}
+    });
+    for &(address, descriptor, _) in &live {
+        let fields = unsafe { address.as_ptr::<Address>().add(1) };
+        for i in 0..descriptor.total_size() - 1 {
+            if descriptor.field_kind(i) != FieldKind::Pointer { continue; }
+            let pointer = unsafe { &mut *fields.add(i) };
+            if !pointer.is_null() { forward(pointer); }
+        }
+    }
+
+    // pass 3: sliding, never overwriting a live object not moved yet
+    for &(address, descriptor, new_address) in &live {
+        unsafe {
+            *address.as_ptr::<&ObjectDescriptor>() = descriptor;
+            let mut dest = new_address;
+            Object::from(address).move_to(&mut dest);
+        }
+    }
+    observer.on_phase_end(Phase::Compact, stats);
+    stats
+}
+
+/// The to-space of a copying collection, objects are copied here by bumping `free` pointers.
+struct ToSpace<'b> {
+    blocks: Vec<&'b mut BlockDescriptor<'b>>,
+    current: usize,
+}
+
+impl<'b> ToSpace<'b> {
+    fn new(to: &'b mut MegaBlockList) -> Self {
+        let blocks = to.iter_mut().flat_map(|m| m.blocks_mut().iter_mut()).collect();
+        ToSpace { blocks, current: 0 }
+    }
+
+    fn copy<'a>(&mut self, address: Address<'a>) -> Address<'a> {
+        let words = Object::from(address).total_size();
+        let bytes = words * WORD;
+        while self.blocks.get(self.current).expect("to-space exhausted").remaining_bytes() < bytes {
+            self.current += 1;
+        }
+        let block = &mut self.blocks[self.current];
+        let mut free = Address::from(block.free);
+        let copied = unsafe { Object::from(address).copy_to(&mut free) };
+        block.free = free.as_ptr();
+        copied.start_address()
+    }
+}
+
+/// Copying (semi-space) garbage collection.
+///
+/// Objects in `from` reachable from `roots` (transitively through their pointer fields) are
+/// copied into `to`, then all the pointer fields and `roots` are updated to the new locations.
+/// Every block in `from` is empty afterwards, except in pinned mega-blocks (see
+/// [`MegaBlock::pin`]): their objects all survive in place, and serve as additional roots.
+/// Pointers out of `from` are left as is.
+///
+/// The descriptor slot of a copied object is overwritten with a forwarding pointer (tagged with
+/// the lowest bit set), so that objects shared by several parents are copied exactly once.
+///
+/// # Panics
+///
+/// Panics if `to` has not enough free space for the surviving objects.
+///
+/// [`MegaBlock::pin`]: ../allocate/struct.MegaBlock.html#method.pin
+pub fn scavenge<'a>(
+    from: &mut MegaBlockList, to: &mut MegaBlockList, roots: &RootSet<'a>) -> GcStats {
+    scavenge_with_observer(from, to, roots, &mut NoObserver)
+}
+
+/// Copying garbage collection, reporting its only `Evacuate` phase to `observer`.
+///
+/// See [`scavenge`](fn.scavenge.html).
+pub fn scavenge_with_observer<'a>(
+    from: &mut MegaBlockList, to: &mut MegaBlockList, roots: &RootSet<'a>,
+    observer: &mut dyn GcObserver) -> GcStats {
+    observer.on_phase_start(Phase::Evacuate);
+    let from_ranges = unpinned_ranges(from);
+    let in_from = |address: Address| {
+        let address = raw(address);
+        from_ranges.iter().any(|&(start, end)| start <= address && address < end)
+    };
+    let mut to_space = ToSpace::new(to);
+    let mut pending = pinned_objects(from);
+    let mut bytes_copied = 0;
+    let mut evacuate = |address: Address<'a>, pending: &mut Vec<Address<'a>>| {
+        if !in_from(address) { return address; }
+        if let Some(new_address) = Object::forwarding_address(address) { return new_address; }
+        let new_address = to_space.copy(address);
+        let mut object = Object::from(address);
+        bytes_copied += object.total_size() * WORD;
+        object.set_forwarded(new_address);
+        pending.push(new_address);
+        new_address
+    };
+    let mut stats = GcStats::default();
+    roots.for_each_mut(|root| if in_from(*root) {
+        *root = evacuate(*root, &mut pending);
+    } else {
+        for pointer in Object::from(*root).trace_pointers_mut() {
+            *pointer = evacuate(*pointer, &mut pending);
+        }
+        stats.objects_traced += 1;
+    });
+    while let Some(address) = pending.pop() {
+        for pointer in Object::from(address).trace_pointers_mut() {
+            *pointer = evacuate(*pointer, &mut pending);
+        }
+        stats.objects_traced += 1;
+    }
+    stats.bytes_live = bytes_copied;
+    for mega_block in from.iter_mut() {
+        if mega_block.is_pinned() {
+            stats.bytes_live += mega_block.blocks().map(|block| block.used_bytes()).sum::<usize>();
+            continue;
+        }
+        for block in mega_block.blocks_mut() {
+            let used = block.used_bytes();
+            if used > 0 { stats.blocks_freed += 1; }
+            stats.bytes_reclaimed += used;
+            block.reset();
+        }
+    }
+    stats.bytes_reclaimed -= bytes_copied;
+    observer.on_phase_end(Phase::Evacuate, stats);
+    stats
+}
+
+/// Card table: one byte per `CARD_SIZE` bytes of the old generation, telling which cards may
+/// hold objects with pointers into the young generation.
+///
+/// Cards are marked dirty by the table itself as a [`WriteBarrier`], or by the [`Generations`]
+/// holding it. Minor collections only scan the objects starting in dirty cards.
+///
+/// [`WriteBarrier`]: ../object/trait.WriteBarrier.html
+/// [`Generations`]: struct.Generations.html
+pub struct CardTable(Vec<(usize, usize, Vec<AtomicU8>)>);
+
+impl CardTable {
+    /// Number of bytes covered by a card.
+    pub const CARD_SIZE: usize = 512;
+
+    /// Constructor for `CardTable`, covering all the mega-blocks in `old`, all clean.
+    pub fn new(old: &MegaBlockList) -> Self {
+        CardTable(ranges(old).into_iter().map(|(start, end)| {
+            let cards = (end - start + Self::CARD_SIZE - 1) / Self::CARD_SIZE;
+            (start, end, (0..cards).map(|_| AtomicU8::new(0)).collect())
+        }).collect())
+    }
+
+    fn card(&self, address: Address) -> Option<&AtomicU8> {
+        let address = raw(address);
+        let (start, _, cards) = self.0.iter()
+            .find(|&&(start, end, _)| start <= address && address < end)?;
+        Some(&cards[(address - start) / Self::CARD_SIZE])
+    }
+
+    /// Whether `address` is in the memory covered by this table.
+    pub fn covers(&self, address: Address) -> bool {
+        self.card(address).is_some()
+    }
+
+    /// Mark the card covering `address` dirty. Addresses out of this table are ignored.
+    pub fn mark(&self, address: Address) {
+        if let Some(card) = self.card(address) { card.store(1, Ordering::Relaxed); }
+    }
+
+    /// Whether the card covering `address` is dirty.
+    pub fn is_dirty(&self, address: Address) -> bool {
+        self.card(address).map_or(false, |card| card.load(Ordering::Relaxed) != 0)
+    }
+
+    /// The starting addresses of the dirty cards, in address order within each mega-block.
+    pub fn dirty_cards(&self) -> impl Iterator<Item = Address<'static>> + '_ {
+        self.0.iter().flat_map(|(start, _, cards)| {
+            cards.iter().enumerate()
+                .filter(|(_, card)| card.load(Ordering::Relaxed) != 0)
+                .map(move |(i, _)| Address::from((start + i * Self::CARD_SIZE) as *mut u8))
+        })
+    }
+
+    /// Mark all the cards clean.
+    pub fn clear(&self) {
+        for (_, _, cards) in &self.0 {
+            for card in cards { card.store(0, Ordering::Relaxed); }
+        }
+    }
+}
+
+/// If a pointer to a young object (i.e. out of the table) is stored into an old object (i.e.
+/// covered by the table), the card covering the start of the old object is marked dirty.
+impl WriteBarrier for CardTable {
+    fn record_store(&self, object: Address, index: usize) {
+        if !self.covers(object) { return; }
+        if !self.covers(Object::from(object).pointer(index)) { self.mark(object); }
+    }
+}
+
+/// Two-generation heap layout for [`minor_collection`](fn.minor_collection.html).
+///
+/// New objects are allocated in `young`. Young objects surviving a minor collection are copied to
+/// `survivors`, or promoted to `old` once they survived more than `threshold` minor collections.
+/// The survival count of every young object is kept in a side table keyed by address, like the
+/// mark bits, rather than in the object header. The header is the descriptor pointer, exposed as
+/// a reference by `Object::descriptor`: bits packed into it would make that reference invalid,
+/// and the only low bit free on every target already tags forwarded objects. The table only holds
+/// the survivors of the last minor collection, and is rebuilt as they are copied.
+pub struct Generations<'a> {
+    /// The young generation, where new objects are allocated.
+    pub young: MegaBlockList,
+    /// Empty mega-blocks receiving the young survivors, swapped with `young` after every minor
+    /// collection.
+    pub survivors: MegaBlockList,
+    /// The old generation, holding the promoted objects.
+    pub old: MegaBlockList,
+    /// The remembered set: old objects which may hold pointers to young objects.
+    ///
+    /// Pointer stores into old objects should be recorded here, e.g. by a write barrier.
+    pub remembered: BTreeSet<Address<'a>>,
+    /// The card table over `old`, if any. Objects starting in dirty cards serve as additional
+    /// roots, like the remembered set.
+    pub cards: Option<CardTable>,
+    /// Number of minor collections a young object may survive before it gets promoted.
+    pub threshold: usize,
+    ages: BTreeMap<usize, usize>,
+}
+
+impl<'a> Generations<'a> {
+    /// Constructor for `Generations`, all generations are empty.
+    pub fn new(threshold: usize) -> Self {
+        Generations {
+            young: MegaBlockList::new(),
+            survivors: MegaBlockList::new(),
+            old: MegaBlockList::new(),
+            remembered: BTreeSet::new(),
+            cards: None,
+            threshold,
+            ages: BTreeMap::new(),
+        }
+    }
+
+    /// Number of minor collections the young object at `address` has survived.
+    pub fn age(&self, address: Address) -> usize {
+        self.ages.get(&raw(address)).copied().unwrap_or(0)
+    }
+
+    /// Record a pointer store into the old object at `address`, see `remembered`.
+    pub fn remember(&mut self, address: Address<'a>) {
+        self.remembered.insert(address);
+    }
+
+    /// Visit the old objects in the remembered set or starting in dirty cards, i.e. the ones
+    /// recorded by the write barrier since the last walk or minor collection, in address order.
+    /// The remembered set is emptied and all the cards are marked clean afterwards.
+    pub fn walk_dirty(&mut self, mut visit: impl FnMut(&Object<'a>)) {
+        let mut dirty = core::mem::take(&mut self.remembered);
+        dirty.extend(self.carded());
+        if let Some(cards) = &self.cards { cards.clear(); }
+        for address in dirty { visit(&Object::from(address)); }
+    }
+
+    /// The old objects starting in dirty cards, in address order within each mega-block.
+    fn carded(&self) -> Vec<Address<'a>> {
+        let mut carded = Vec::new();
+        if let Some(cards) = &self.cards {
+            let dirty = |block: &BlockDescriptor| (0..BlockDescriptor::SIZE)
+                .step_by(CardTable::CARD_SIZE)
+                .any(|offset| cards.is_dirty(Address::from(block.start.wrapping_add(offset))));
+            for mega_block in self.old.iter() {
+                for block in mega_block.blocks().filter(|block| dirty(block)) {
+                    carded.extend(block.object_addresses()
+                        .filter(|a| cards.is_dirty(*a))
+                        .map(|a| Address::<'a>::from(a.as_ptr::<u8>())));
+                }
+            }
+        }
+        carded
+    }
+}
+
+/// Pointer stores are recorded in the card table, if any.
+impl<'a> WriteBarrier for Generations<'a> {
+    fn record_store(&self, object: Address, index: usize) {
+        if let Some(cards) = &self.cards { cards.record_store(object, index); }
+    }
+}
+
+/// Minor (young generation) garbage collection.
+///
+/// Young objects reachable from `roots`, from the remembered set, or from the objects starting in
+/// dirty cards are copied out of the young
+/// generation, like [`scavenge`](fn.scavenge.html) does: to `survivors` if their age does not
+/// exceed the `threshold` yet, or to `old` otherwise. The old generation is not traced, only the
+/// objects in the remembered set or in dirty cards serve as additional roots. Afterwards, `young`
+/// and `survivors` are swapped, and the remembered set and the dirty cards only keep the old
+/// objects still pointing to young ones.
+///
+/// The objects of pinned young mega-blocks (see [`MegaBlock::pin`]) all survive in place and
+/// serve as additional roots, the mega-blocks stay in the young generation.
+///
+/// # Panics
+///
+/// Panics if `survivors` or `old` has not enough free space for the surviving objects.
+///
+/// [`MegaBlock::pin`]: ../allocate/struct.MegaBlock.html#method.pin
+pub fn minor_collection<'a>(generations: &mut Generations<'a>, roots: &RootSet<'a>) -> GcStats {
+    minor_collection_with_observer(generations, roots, &mut NoObserver)
+}
+
+/// Minor garbage collection, reporting its only `Evacuate` phase to `observer`.
+///
+/// See [`minor_collection`](fn.minor_collection.html).
+pub fn minor_collection_with_observer<'a>(
+    generations: &mut Generations<'a>, roots: &RootSet<'a>,
+    observer: &mut dyn GcObserver) -> GcStats {
+    observer.on_phase_start(Phase::Evacuate);
+    let young_ranges = unpinned_ranges(&generations.young);
+    let in_young = |address: Address| {
+        let address = raw(address);
+        young_ranges.iter().any(|&(start, end)| start <= address && address < end)
+    };
+    let carded = generations.carded();
+    if let Some(cards) = &generations.cards { cards.clear(); }
+    let threshold = generations.threshold;
+    let ages = core::mem::take(&mut generations.ages);
+    let mut new_ages = BTreeMap::new();
+    let mut promoted = Vec::new();
+    let mut pending = pinned_objects(&generations.young);
+    let mut survivors = ToSpace::new(&mut generations.survivors);
+    let mut old = ToSpace::new(&mut generations.old);
+    let mut bytes_copied = 0;
+    let mut evacuate = |address: Address<'a>, pending: &mut Vec<Address<'a>>| {
+        if !in_young(address) { return address; }
+        if let Some(new_address) = Object::forwarding_address(address) { return new_address; }
+        let age = ages.get(&raw(address)).copied().unwrap_or(0) + 1;
+        let new_address = if age > threshold {
+            let new_address = old.copy(address);
+            promoted.push(new_address);
+            new_address
+        } else {
+            let new_address = survivors.copy(address);
+            new_ages.insert(raw(new_address), age);
+            new_address
+        };
+        let mut object = Object::from(address);
+        bytes_copied += object.total_size() * WORD;
+        object.set_forwarded(new_address);
+        pending.push(new_address);
+        new_address
+    };
+    let mut stats = GcStats::default();
+    roots.for_each_mut(|root| if in_young(*root) {
+        *root = evacuate(*root, &mut pending);
+    } else {
+        pending.push(*root);
+    });
+    pending.extend(generations.remembered.iter().copied());
+    pending.extend(carded.iter().copied());
+    while let Some(address) = pending.pop() {
+        for pointer in Object::from(address).trace_pointers_mut() {
+            *pointer = evacuate(*pointer, &mut pending);
+        }
+        stats.objects_traced += 1;
+    }
+    stats.bytes_live = bytes_copied;
+
+    // pinned mega-blocks stay young
+    let pinned = generations.young.iter().enumerate()
+        .filter(|(_, mega_block)| mega_block.is_pinned())
+        .map(|(index, _)| index).collect::<Vec<_>>();
+    for index in pinned.into_iter().rev() {
+        let mega_block = generations.young.remove(index);
+        stats.bytes_live += mega_block.blocks().map(|block| block.used_bytes()).sum::<usize>();
+        generations.survivors.push_front(mega_block);
+    }
+    let survivor_ranges = ranges(&generations.survivors);
+    let points_to_young = |address: &Address| Object::from(*address).trace_pointers().any(|p| {
+        let p = raw(p.start_address());
+        survivor_ranges.iter().any(|&(start, end)| start <= p && p < end)
+    });
+    generations.remembered.extend(promoted);
+    generations.remembered.retain(|address| points_to_young(address));
+    if let Some(cards) = &generations.cards {
+        for address in carded.iter().filter(|a| points_to_young(a)) { cards.mark(*address); }
+    }
+    generations.ages = new_ages;
+    for mega_block in generations.young.iter_mut() {
+        for block in mega_block.blocks_mut() {
+            let used = block.used_bytes();
+            if used > 0 { stats.blocks_freed += 1; }
+            stats.bytes_reclaimed += used;
+            block.reset();
+        }
+    }
+    stats.bytes_reclaimed -= bytes_copied;
+    core::mem::swap(&mut generations.young, &mut generations.survivors);
+    observer.on_phase_end(Phase::Evacuate, stats);
+    stats
+}
+
+/// Why [`verify`](fn.verify.html) rejected an object.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum VerifyReason {
+    /// The descriptor pointer of the object is null, or not properly aligned.
+    MisalignedDescriptor,
+    /// The object extends past the `free` pointer of its block.
+    PastFree,
+    /// The `field_map` of the descriptor disagrees with its field counts.
+    BadFieldMap,
+    /// A pointer field holds this address, which is not the start of an object in any block.
+    DanglingPointer(Address<'static>),
+}
+
+/// A heap invariant violated by the object at `address`, see [`verify`](fn.verify.html).
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct VerifyError {
+    /// Starting address of the offending object.
+    pub address: Address<'static>,
+    /// The violated invariant.
+    pub reason: VerifyReason,
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "object at {:?}: ", self.address)?;
+        match self.reason {
+            VerifyReason::MisalignedDescriptor =>
+                write!(f, "descriptor pointer null or misaligned"),
+            VerifyReason::PastFree => write!(f, "object extends past the free pointer"),
+            VerifyReason::BadFieldMap => write!(f, "field map disagrees with the field counts"),
+            VerifyReason::DanglingPointer(target) => write!(f, "dangling pointer to {:?}", target),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for VerifyError {}
+
+/// Check the invariants of every object in `blocks`, to catch heap corruption early.
+///
+/// Every object must have a properly aligned descriptor pointer and a consistent descriptor, must
+/// end before the `free` pointer of its block, and its non-null pointer fields must point to the
+/// start of an object in some block in `blocks`. Pointers anywhere else, e.g. into the middle of
+/// an object or into a large object space, are reported as dangling.
+///
+/// The headers of all the objects are checked first, then their pointer fields. Returns the first
+/// violation found.
+pub fn verify(blocks: &MegaBlockList) -> Result<(), VerifyError> {
+    let violation = |address: Address, reason| {
+        Err(VerifyError { address: Address::from(address.as_ptr::<u8>()), reason })
+    };
+    let mut objects = Vec::new();
+    for block in blocks.iter().flat_map(|mega_block| mega_block.blocks()) {
+        let free = Address::from(block.free);
+        let mut current = Address::from(block.start);
+        while current < free {
+            let descriptor = unsafe { *current.as_ptr::<usize>() };
+            let mask = core::mem::align_of::<ObjectDescriptor>() - 1;
+            if descriptor == 0 || descriptor & mask != 0 {
+                return violation(current, VerifyReason::MisalignedDescriptor);
+            }
+            let descriptor = unsafe { &*(descriptor as *const ObjectDescriptor) };
+            let remaining = unsafe { free.word_offset_from(current) } as usize;
+            if descriptor.checked_total_size().map_or(true, |words| words > remaining) {
+                return violation(current, VerifyReason::PastFree);
+            }
+            if !descriptor.validate(remaining) {
+                return violation(current, VerifyReason::BadFieldMap);
+            }
+            objects.push(current);
+            current = unsafe { current.offset((descriptor.total_size() * WORD) as isize) };
+        }
+    }
+    let mut starts: Vec<usize> = objects.iter().map(|&address| raw(address)).collect();
+    starts.sort_unstable();
+    for &address in &objects {
+        let mut object = Object::from(address);
+        let dangling = object.trace_pointers_mut()
+            .find(|p| starts.binary_search(&raw(**p)).is_err());
+        if let Some(target) = dangling {
+            let target = Address::from(target.as_ptr::<u8>());
+            return violation(address, VerifyReason::DanglingPointer(target));
+        }
+    }
+    Ok(())
+}
+
+/// The addresses of all the objects reachable from `roots`, without collecting anything.
+///
+/// The object graph is walked breadth-first through the pointer fields, so the roots come first,
+/// then the objects they point to, and so on. Every object is reported exactly once, even if it
+/// is reachable in several ways (or is a root more than once). Unlike a collection, pointers are
+/// followed wherever they lead, e.g. into large object spaces.
+pub fn reachable_from<'a>(roots: &[&Object<'a>]) -> Vec<Address<'a>> {
+    let mut visited = BTreeSet::new();
+    let mut pending: VecDeque<_> = roots.iter().map(|root| root.start_address()).collect();
+    let mut reachable = Vec::new();
+    while let Some(address) = pending.pop_front() {
+        if visited.insert(address) {
+            reachable.push(address);
+            pending.extend(Object::from(address).trace_pointers().map(|p| p.start_address()));
+        }
+    }
+    reachable
+}
+
+/// Whether some cycle in the object graph is reachable from `roots`, self-loops included.
+///
+/// The object graph is walked depth-first with the usual three colors: an object is grey while
+/// the objects reachable from it are being visited, and black afterwards. A pointer to a grey
+/// object is a back edge, hence a cycle; pointers to black objects are merely shared.
+pub fn has_cycle<'a>(roots: &[&Object<'a>]) -> bool {
+    #[derive(Copy, Clone, Eq, PartialEq)]
+    enum Color { Grey, Black }
+    let successors = |address: Address<'a>| -> Vec<Address<'a>> {
+        Object::from(address).trace_pointers().map(|p| p.start_address()).collect()
+    };
+    let mut colors = BTreeMap::new();
+    for root in roots {
+        let root = root.start_address();
+        if colors.contains_key(&root) { continue; }
+        colors.insert(root, Color::Grey);
+        let mut stack = vec![(root, successors(root))];
+        while let Some((address, pending)) = stack.last_mut() {
+            match pending.pop() {
+                Some(next) => match colors.get(&next) {
+                    Some(Color::Grey) => return true,
+                    Some(Color::Black) => {}
+                    None => {
+                        colors.insert(next, Color::Grey);
+                        stack.push((next, successors(next)));
+                    }
+                },
+                None => {
+                    colors.insert(*address, Color::Black);
+                    stack.pop();
+                }
+            }
+        }
+    }
+    false
+}
+
+/// Write the object graph reachable from `roots` to `out` in the Graphviz DOT language.
+///
+/// Every reachable object is a node labeled by its address and the shape of its descriptor, with
+/// an edge for every non-null pointer field. Objects are visited once (see
+/// [`reachable_from`](fn.reachable_from.html)), so cycles are fine.
+///
+/// ```
+/// use memory_manager::gc::to_dot;
+/// let mut dot = String::new();
+/// to_dot(&[], &mut dot).unwrap();
+/// assert_eq!(dot, "digraph heap {\n}\n");
+/// ```
+pub fn to_dot(roots: &[&Object], out: &mut impl fmt::Write) -> fmt::Result {
+    writeln!(out, "digraph heap {{")?;
+    for address in reachable_from(roots) {
+        let object = Object::from(address);
+        let source = address.as_ptr::<u8>();
+        writeln!(out, "    \"{:p}\" [label=\"{:p}\\n{} unpacked, {} pointers\"];", source, source,
+                 object.descriptor.unpacked_field_count, object.descriptor.pointer_count)?;
+        for target in object.trace_pointers() {
+            let target = target.start_address().as_ptr::<u8>();
+            writeln!(out, "    \"{:p}\" -> \"{:p}\";", source, target)?;
+        }
+    }
+    writeln!(out, "}}")
+}
+
+/// Write the object graph reachable from `roots` to `out`, in a position-independent format.
+///
+/// The format is a sequence of 64-bit little-endian words: the number of objects, then for each
+/// object (in the order of [`reachable_from`](fn.reachable_from.html), so the distinct roots come
+/// first) the shape of its descriptor, i.e. `unpacked_field_count` and `pointer_count`, followed
+/// by its fields in memory order. Unpacked fields are written as is, and pointer fields as the
+/// index of their target in this sequence plus one, with `0` for null.
+///
+/// Read it back with [`deserialize`](fn.deserialize.html).
+pub fn serialize(roots: &[&Object], out: &mut Vec<u8>) {
+    let nodes = reachable_from(roots);
+    let index: BTreeMap<_, _> =
+        nodes.iter().enumerate().map(|(i, address)| (*address, i)).collect();
+    let mut word = |w: usize| out.extend_from_slice(&(w as u64).to_le_bytes());
+    word(nodes.len());
+    for &address in &nodes {
+        let mut object = Object::from(address);
+        let descriptor: &ObjectDescriptor = object.descriptor;
+        word(descriptor.unpacked_field_count);
+        word(descriptor.pointer_count);
+        for (i, field) in object.word_slots_mut(false).enumerate() {
+            match descriptor.field_kind(i) {
+                FieldKind::Unpacked => word(*field),
+                FieldKind::Pointer if *field == 0 => word(0),
+                FieldKind::Pointer => word(index[&Address::from(*field as *mut u8)] + 1),
+            }
+        }
+    }
+}
+
+/// Reasons for [`deserialize`](fn.deserialize.html) to fail.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum DeserializeError {
+    /// The input is truncated, has trailing bytes, or has a pointer to no object.
+    Malformed,
+    /// No descriptor was provided for objects with this many unpacked fields and pointers.
+    UnknownShape(usize, usize),
+    /// The block cannot hold all the objects.
+    OutOfSpace,
+}
+
+impl fmt::Display for DeserializeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DeserializeError::Malformed => write!(f, "malformed object graph"),
+            DeserializeError::UnknownShape(unpacked, pointers) => write!(
+                f, "no descriptor for {} unpacked fields and {} pointers", unpacked, pointers),
+            DeserializeError::OutOfSpace => write!(f, "not enough space in the block"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for DeserializeError {}
+
+/// Rebuild in `block` an object graph written by [`serialize`](fn.serialize.html), returning the
+/// new objects in the serialized order, so the distinct roots come first.
+///
+/// Descriptors are not serialized: `descriptor(unpacked_field_count, pointer_count)` provides the
+/// descriptor of every object, which must have the same layout as the original one. The input
+/// is checked entirely before allocating anything, and `block` is left untouched on failure.
+pub fn deserialize<'a>(
+    bytes: &[u8], block: &mut BlockDescriptor<'a>,
+    mut descriptor: impl FnMut(usize, usize) -> Option<&'a ObjectDescriptor>)
+    -> Result<Vec<Object<'a>>, DeserializeError> {
+    if bytes.len() & 7 != 0 { return Err(DeserializeError::Malformed); }
+    let mut words = bytes.chunks_exact(8).map(|chunk| {
+        let mut word = [0; 8];
+        word.copy_from_slice(chunk);
+        usize::try_from(u64::from_le_bytes(word)).map_err(|_| DeserializeError::Malformed)
+    });
+    let mut next = || words.next().unwrap_or(Err(DeserializeError::Malformed));
+    let count = next()?;
+    let mut nodes = Vec::new();
+    for _ in 0..count {
+        let (unpacked, pointers) = (next()?, next()?);
+        let found = descriptor(unpacked, pointers)
+            .filter(|d| d.unpacked_field_count == unpacked && d.pointer_count == pointers)
+            .ok_or(DeserializeError::UnknownShape(unpacked, pointers))?;
+        let mut fields = Vec::with_capacity(found.total_size() - 1);
+        for i in 0..found.total_size() - 1 {
+            let field = next()?;
+            if found.field_kind(i) == FieldKind::Pointer && field > count {
+                return Err(DeserializeError::Malformed);
+            }
+            fields.push(field);
+        }
+        nodes.push((found, fields));
+    }
+    if next().is_ok() { return Err(DeserializeError::Malformed); }
+    let saved_free = block.free;
+    let mut objects = Vec::with_capacity(count);
+    for (found, _) in &nodes {
+        match block.allocate(found) {
+            Some(object) => objects.push(object),
+            None => {
+                block.free = saved_free;
+                return Err(DeserializeError::OutOfSpace);
+            }
+        }
+    }
+    let addresses: Vec<_> = objects.iter().map(|object| object.start_address()).collect();
+    for (object, (found, fields)) in objects.iter_mut().zip(&nodes) {
+        for (i, (slot, &field)) in object.word_slots_mut(false).zip(fields).enumerate() {
+            *slot = match found.field_kind(i) {
+                FieldKind::Unpacked => field,
+                FieldKind::Pointer if field == 0 => 0,
+                FieldKind::Pointer => addresses[field - 1].as_ptr::<u8>() as usize,
+            };
+        }
+    }
+    Ok(objects)
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::boxed::Box;
+
+    use super::{mark_sweep, mark_sweep_with_large, scavenge, RootSet, GcStats};
+    use super::{mark_sweep_with_options, SweepOptions, POISON, mark_sweep_lazy};
+    use super::{Generations, minor_collection, CardTable};
+    use super::{verify, VerifyError, VerifyReason};
+    use super::{reachable_from, has_cycle, to_dot};
+    use super::{serialize, deserialize, DeserializeError};
+    use super::{mark_compact, mark_compact_with_observer, mark_sweep_with_observer};
+    use super::{GcObserver, Phase};
+    use super::super::allocate::{MegaBlock, MegaBlockList, Protection};
+    use super::super::allocate::{LargeObjectSpace, allocate_object};
+    use super::super::block::{BlockDescriptor, is_filler};
+    use super::super::object::{Object, ObjectDescriptor, FieldKind};
+    use super::super::common::Address;
+
+    static NODE: ObjectDescriptor =
+        ObjectDescriptor { unpacked_field_count: 1, pointer_count: 2, field_map: None };
+
+    /// The blocks of `mega_block`, no longer borrowing it: tests keep objects across moving the
+    /// mega-block into a list, which keeps its memory (and its block table) in place.
+    fn blocks_of<'a>(mega_block: &mut MegaBlock) -> &'a mut [BlockDescriptor<'a>] {
+        let blocks = mega_block.blocks_mut();
+        unsafe { core::slice::from_raw_parts_mut(blocks.as_mut_ptr() as _, blocks.len()) }
+    }
+
+    fn node<'a>(block: &mut BlockDescriptor<'a>, tag: usize) -> Object<'a> {
+        let object = block.allocate(&NODE).unwrap();
+        object.unpacked[0] = tag;
+        object
+    }
+
+    #[test]
+    fn test_mark_sweep() {
+        let mut mega_block = MegaBlock::new(Protection::Read | Protection::Write).unwrap();
+        let blocks = blocks_of(&mut mega_block);
+        let (first, rest) = blocks.split_at_mut(1);
+        let (block0, block1) = (&mut first[0], &mut rest[0]);
+        // block 0: root -> (a, b), a -> root, garbage x -> a, garbage y
+        let root = node(block0, 0);
+        let a = node(block0, 1);
+        let b = node(block0, 2);
+        let x = node(block0, 3);
+        let _y = node(block0, 4);
+        // block 1: garbage cycle z <-> w
+        let z = node(block1, 5);
+        let w = node(block1, 6);
+        root.pointers[0] = a.start_address();
+        root.pointers[1] = b.start_address();
+        a.pointers[0] = root.start_address();
+        x.pointers[0] = a.start_address();
+        z.pointers[0] = w.start_address();
+        w.pointers[0] = z.start_address();
+        let live_end = x.start_address().as_ptr::<u8>();
+        let block1_start = block1.start;
+
+        let mut list = MegaBlockList::new();
+        list.push_front(Box::new(mega_block));
+        let roots = RootSet::new();
+        let _handle = roots.register(root.start_address());
+        mark_sweep(&roots, &mut list);
+
+        let blocks = list.head_mut().unwrap().blocks_mut();
+        assert_eq!(blocks[0].free, live_end);
+        let tags: std::vec::Vec<_> = blocks[0].objects().map(|o| o.unpacked[0]).collect();
+        assert_eq!(tags, [0, 1, 2]);
+        assert_eq!(blocks[1].free, block1_start);
+        assert_eq!(blocks[1].objects().count(), 0);
+    }
+
+    #[derive(Default)]
+    struct Recorder(std::vec::Vec<(&'static str, Phase)>, Option<GcStats>);
+
+    impl GcObserver for Recorder {
+        fn on_phase_start(&mut self, phase: Phase) { self.0.push(("start", phase)); }
+        fn on_phase_end(&mut self, phase: Phase, stats: GcStats) {
+            self.0.push(("end", phase));
+            self.1 = Some(stats);
+        }
+    }
+
+    #[test]
+    fn test_observer() {
+        let mut mega_block = MegaBlock::new(Protection::Read | Protection::Write).unwrap();
+        let block = &mut blocks_of(&mut mega_block)[0];
+        node(block, 0);
+        let a = node(block, 1);
+        node(block, 2);
+        let mut list = MegaBlockList::new();
+        list.push_front(Box::new(mega_block));
+        let roots = RootSet::new();
+        let _root = roots.register(a.start_address());
+
+        let mut recorder = Recorder::default();
+        let stats = mark_sweep_with_observer(
+            &roots, &mut list, &mut LargeObjectSpace::new(), SweepOptions::default(),
+            &mut recorder);
+        assert_eq!(recorder.0, [
+            ("start", Phase::Mark), ("end", Phase::Mark),
+            ("start", Phase::Sweep), ("end", Phase::Sweep)]);
+        assert_eq!(recorder.1, Some(stats));
+
+        let mut recorder = Recorder::default();
+        let stats = mark_compact_with_observer(&roots, &mut list, &mut recorder);
+        assert_eq!(recorder.0, [
+            ("start", Phase::Mark), ("end", Phase::Mark),
+            ("start", Phase::Compact), ("end", Phase::Compact)]);
+        assert_eq!(recorder.1, Some(stats));
+    }
+
+    #[test]
+    fn test_lazy_sweep() {
+        let node_bytes = NODE.total_size() * core::mem::size_of::<usize>();
+        let per_block = BlockDescriptor::SIZE / node_bytes;
+        // block 0: 1 live node, block 1: half of them, block 2: none, block 3: all of them
+        let live = [1, per_block / 2, 0, per_block];
+        let heap = || {
+            let mut mega_block = MegaBlock::new(Protection::Read | Protection::Write).unwrap();
+            let mut survivors = std::vec::Vec::new();
+            for (block, &live) in blocks_of(&mut mega_block).iter_mut().zip(&live) {
+                for i in 0..per_block {
+                    let object = node(block, i);
+                    if i < live { survivors.push(object.start_address()); }
+                }
+            }
+            let mut list = MegaBlockList::new();
+            list.push_front(Box::new(mega_block));
+            (list, survivors)
+        };
+
+        let (mut eager, survivors) = heap();
+        let roots = RootSet::new();
+        let _handles: std::vec::Vec<_> = survivors.into_iter().map(|a| roots.register(a)).collect();
+        let expected = mark_sweep(&roots, &mut eager);
+
+        let (mut lazy, survivors) = heap();
+        let roots = RootSet::new();
+        let _handles: std::vec::Vec<_> = survivors.into_iter().map(|a| roots.register(a)).collect();
+        let first_block = lazy.head().unwrap().blocks().next().unwrap();
+        let first_free = first_block.start.wrapping_add(node_bytes);
+        let mut sweep = mark_sweep_lazy(&roots, &mut lazy);
+        assert_eq!(sweep.stats().bytes_reclaimed, 0);
+        let mut reclaimed = 0;
+        for (block, &live) in live.iter().enumerate() {
+            reclaimed += (per_block - live) * node_bytes;
+            for i in 0..per_block - live {
+                let object = sweep.allocate(&NODE).unwrap();
+                if block == 0 && i == 0 {
+                    assert_eq!(object.start_address().as_ptr::<u8>(), first_free);
+                }
+                assert_eq!(sweep.stats().bytes_reclaimed, reclaimed);
+            }
+        }
+        assert_eq!(sweep.finish(), expected);
+    }
+
+    #[test]
+    fn test_sweep_filler() {
+        let mut mega_block = MegaBlock::new(Protection::Read | Protection::Write).unwrap();
+        let block = &mut blocks_of(&mut mega_block)[0];
+        // root -> b, with garbage x, y, z in between
+        let root = node(block, 0);
+        for tag in 1..4 { node(block, tag); }
+        let b = node(block, 4);
+        root.pointers[0] = b.start_address();
+        let mut list = MegaBlockList::new();
+        list.push_front(Box::new(mega_block));
+        let roots = RootSet::new();
+        let _handle = roots.register(root.start_address());
+        mark_sweep(&roots, &mut list);
+
+        let block = &list.head_mut().unwrap().blocks_mut()[0];
+        let objects: std::vec::Vec<_> = block.objects().collect();
+        assert_eq!(objects.len(), 3);
+        assert!(is_filler(objects[1].descriptor));
+        assert_eq!(objects[1].total_size(), 3 * NODE.total_size());
+        assert_eq!((objects[0].unpacked[0], objects[2].unpacked[0]), (0, 4));
+    }
+
+    #[test]
+    fn test_poison_on_free() {
+        let bytes = NODE.total_size() * core::mem::size_of::<usize>();
+        for &poison_on_free in &[true, false] {
+            let mut mega_block = MegaBlock::new(Protection::Read | Protection::Write).unwrap();
+            let block = &mut blocks_of(&mut mega_block)[0];
+            // root -> b, with garbage x in between, and garbage y at the end
+            let root = node(block, 0);
+            let x = node(block, 1);
+            let b = node(block, 2);
+            let y = node(block, 3);
+            root.pointers[0] = b.start_address();
+            let mut list = MegaBlockList::new();
+            list.push_front(Box::new(mega_block));
+            let roots = RootSet::new();
+            let _handle = roots.register(root.start_address());
+            let options = SweepOptions { poison_on_free };
+            mark_sweep_with_options(&roots, &mut list, &mut LargeObjectSpace::new(), options);
+
+            let body = |object: &Object| unsafe {
+                core::slice::from_raw_parts(object.start_address().as_ptr::<u8>(), bytes)
+            };
+            for dead in [&x, &y] {
+                let poisoned = body(dead)[core::mem::size_of::<usize>()..].iter()
+                    .all(|&b| b == POISON);
+                assert_eq!(poisoned, poison_on_free);
+            }
+            assert_eq!((root.unpacked[0], b.unpacked[0]), (0, 2));
+            assert_eq!(root.pointers[0], b.start_address());
+            assert_eq!(list.head_mut().unwrap().blocks_mut()[0].objects().count(), 3);
+        }
+        assert_eq!(SweepOptions::default().poison_on_free, cfg!(debug_assertions));
+    }
+
+    #[test]
+    fn test_stats() {
+        let size = NODE.total_size() * core::mem::size_of::<usize>();
+        let build = |mega_block: &mut MegaBlock| {
+            let blocks = blocks_of(mega_block);
+            let (first, rest) = blocks.split_at_mut(1);
+            let (block0, block1) = (&mut first[0], &mut rest[0]);
+            // block 0: root -> (a, b), a -> b; block 1: garbage d -> e
+            let root = node(block0, 0);
+            let a = node(block0, 1);
+            let b = node(block0, 2);
+            let d = node(block1, 3);
+            let e = node(block1, 4);
+            root.pointers[0] = a.start_address();
+            root.pointers[1] = b.start_address();
+            a.pointers[0] = b.start_address();
+            d.pointers[0] = e.start_address();
+            root.start_address()
+        };
+        let expected = GcStats {
+            bytes_live: 3 * size, bytes_reclaimed: 2 * size, objects_traced: 3, blocks_freed: 1 };
+
+        let mut mega_block = MegaBlock::new(Protection::Read | Protection::Write).unwrap();
+        let root = build(&mut mega_block);
+        let mut list = MegaBlockList::new();
+        list.push_front(Box::new(mega_block));
+        let roots = RootSet::new();
+        let _handle = roots.register(root);
+        assert_eq!(mark_sweep(&roots, &mut list), expected);
+
+        let mut from_block = MegaBlock::new(Protection::Read | Protection::Write).unwrap();
+        let to_block = MegaBlock::new(Protection::Read | Protection::Write).unwrap();
+        let root = build(&mut from_block);
+        let mut from = MegaBlockList::new();
+        from.push_front(Box::new(from_block));
+        let mut to = MegaBlockList::new();
+        to.push_front(Box::new(to_block));
+        let roots = RootSet::new();
+        let _handle = roots.register(root);
+        assert_eq!(scavenge(&mut from, &mut to, &roots), GcStats { blocks_freed: 2, ..expected });
+    }
+
+    #[test]
+    fn test_scavenge_shared() {
+        let mut from_block = MegaBlock::new(Protection::Read | Protection::Write).unwrap();
+        let to_block = MegaBlock::new(Protection::Read | Protection::Write).unwrap();
+        let block = &mut blocks_of(&mut from_block)[0];
+        // root -> (left, right), left -> shared, right -> shared
+        let root = node(block, 0);
+        let left = node(block, 1);
+        let right = node(block, 2);
+        let shared = node(block, 3);
+        let _garbage = node(block, 4);
+        root.pointers[0] = left.start_address();
+        root.pointers[1] = right.start_address();
+        left.pointers[0] = shared.start_address();
+        right.pointers[0] = shared.start_address();
+
+        let mut from = MegaBlockList::new();
+        from.push_front(Box::new(from_block));
+        let mut to = MegaBlockList::new();
+        to.push_front(Box::new(to_block));
+        let roots = RootSet::new();
+        let handle = roots.register(root.start_address());
+        scavenge(&mut from, &mut to, &roots);
+        let root = handle.object();
+
+        assert_eq!(from.head_mut().unwrap().blocks_mut()[0].objects().count(), 0);
+        let to_space = &to.head_mut().unwrap().blocks_mut()[0];
+        assert_eq!(to_space.used_bytes(), 4 * NODE.total_size() * core::mem::size_of::<usize>());
+        assert_eq!(root.start_address().as_ptr::<u8>(), to_space.start);
+        assert_eq!(root.unpacked[0], 0);
+        let left = Object::from(root.pointers[0]);
+        let right = Object::from(root.pointers[1]);
+        assert_eq!((left.unpacked[0], right.unpacked[0]), (1, 2));
+        assert_eq!(left.pointers[0], right.pointers[0]);
+        assert_eq!(Object::from(left.pointers[0]).unpacked[0], 3);
+    }
+
+    #[test]
+    fn test_large_objects() {
+        const WORD: usize = core::mem::size_of::<usize>();
+        static HUGE: ObjectDescriptor = ObjectDescriptor {
+            unpacked_field_count: 64 * 1024 / WORD - 1, pointer_count: 0, field_map: None };
+        let mut mega_block = MegaBlock::new(Protection::Read | Protection::Write).unwrap();
+        let mut large = LargeObjectSpace::new();
+        let root = node(&mut blocks_of(&mut mega_block)[0], 0);
+        // large objects borrow the space, only keep their addresses
+        let allocate = |mega_block: &mut MegaBlock, large: &mut LargeObjectSpace| {
+            let block = &mut blocks_of(mega_block)[0];
+            let object = allocate_object(block, large, &HUGE).unwrap().unwrap();
+            object.start_address().as_ptr::<u8>()
+        };
+        let live = allocate(&mut mega_block, &mut large);
+        let dead = allocate(&mut mega_block, &mut large);
+        Object::from(Address::from(live)).unpacked[0] = 42;
+        root.pointers[0] = Address::from(live);
+        assert_eq!(blocks_of(&mut mega_block)[0].used_bytes(), NODE.total_size() * WORD);
+        assert_eq!(large.len(), 2);
+        assert!(large.contains(Address::from(live)));
+        assert!(large.contains(Address::from(dead)));
+
+        let mut list = MegaBlockList::new();
+        list.push_front(Box::new(mega_block));
+        let roots = RootSet::new();
+        let _handle = roots.register(root.start_address());
+        let stats = mark_sweep_with_large(&roots, &mut list, &mut large);
+        assert_eq!(large.len(), 1);
+        assert!(large.contains(Address::from(live)));
+        assert_eq!(Object::from(Address::from(live)).unpacked[0], 42);
+        assert_eq!(stats.bytes_reclaimed, 64 * 1024);
+        assert_eq!(stats.bytes_live, 64 * 1024 + NODE.total_size() * WORD);
+    }
+
+    #[test]
+    fn test_minor_collection() {
+        let rw = Protection::Read | Protection::Write;
+        let mut young = MegaBlock::new(rw).unwrap();
+        let survivors = MegaBlock::new(rw).unwrap();
+        let mut old = MegaBlock::new(rw).unwrap();
+        // young: root -> a, garbage g; old: o -> b (remembered)
+        let root = node(&mut blocks_of(&mut young)[0], 0);
+        let a = node(&mut blocks_of(&mut young)[0], 1);
+        let _g = node(&mut blocks_of(&mut young)[0], 2);
+        let b = node(&mut blocks_of(&mut young)[0], 3);
+        let o = node(&mut blocks_of(&mut old)[0], 4);
+        root.pointers[0] = a.start_address();
+        o.pointers[0] = b.start_address();
+        let mut generations = Generations::new(1);
+        generations.young.push_front(Box::new(young));
+        generations.survivors.push_front(Box::new(survivors));
+        generations.old.push_front(Box::new(old));
+        generations.remember(o.start_address());
+        let roots = RootSet::new();
+        let handle = roots.register(root.start_address());
+        let tag = |address| Object::from(address).unpacked[0];
+
+        minor_collection(&mut generations, &roots);
+        let root = handle.address();
+        assert!(generations.young.find_containing(root).is_some());
+        assert_eq!(generations.age(root), 1);
+        assert_eq!(tag(handle.object().pointers[0]), 1);
+        assert!(generations.remembered.iter().eq([o.start_address()].iter()));
+        assert!(generations.young.find_containing(o.pointers[0]).is_some());
+        assert_eq!(tag(o.pointers[0]), 3);
+
+        let stats = minor_collection(&mut generations, &roots);
+        let root = handle.address();
+        assert!(generations.old.find_containing(root).is_some());
+        assert!(generations.old.find_containing(handle.object().pointers[0]).is_some());
+        assert!(generations.old.find_containing(o.pointers[0]).is_some());
+        assert_eq!(generations.age(root), 0);
+        assert_eq!((tag(root), tag(handle.object().pointers[0]), tag(o.pointers[0])), (0, 1, 3));
+        assert!(generations.remembered.is_empty());
+        assert_eq!(stats.bytes_live, 3 * NODE.total_size() * core::mem::size_of::<usize>());
+        assert_eq!(stats.bytes_reclaimed, 0);
+    }
+
+    #[test]
+    fn test_card_table() {
+        let rw = Protection::Read | Protection::Write;
+        let mut young = MegaBlock::new(rw).unwrap();
+        let survivors = MegaBlock::new(rw).unwrap();
+        let mut old = MegaBlock::new(rw).unwrap();
+        let y1 = node(&mut blocks_of(&mut young)[0], 1);
+        let y2 = node(&mut blocks_of(&mut young)[0], 2);
+        let mut o1 = node(&mut blocks_of(&mut old)[0], 3);
+        let mut o2 = node(&mut blocks_of(&mut old)[2], 4);
+        let mut generations = Generations::new(1);
+        generations.young.push_front(Box::new(young));
+        generations.survivors.push_front(Box::new(survivors));
+        generations.old.push_front(Box::new(old));
+        generations.cards = Some(CardTable::new(&generations.old));
+        let dirty_cards = |generations: &Generations| -> std::vec::Vec<_> {
+            generations.cards.as_ref().unwrap().dirty_cards().collect()
+        };
+        let card_of = |object: &Object| {
+            let address = object.start_address().as_ptr::<u8>() as usize;
+            Address::from((address & !(CardTable::CARD_SIZE - 1)) as *mut u8)
+        };
+
+        o2.set_pointer(1, &o1, &generations);
+        assert_eq!(dirty_cards(&generations), []);
+        o1.set_pointer(0, &y1, &generations);
+        assert_eq!(dirty_cards(&generations), [card_of(&o1)]);
+
+        // not recorded, o2 is in a clean card and must not be scanned
+        o2.pointers[0] = y2.start_address();
+        let stale = o2.pointers[0];
+        minor_collection(&mut generations, &RootSet::new());
+        assert!(generations.young.find_containing(o1.pointers[0]).is_some());
+        assert_eq!(Object::from(o1.pointers[0]).unpacked[0], 1);
+        assert_eq!(o2.pointers[0], stale);
+        assert_eq!(dirty_cards(&generations), [card_of(&o1)]);
+    }
+
+    #[test]
+    fn test_walk_dirty() {
+        let rw = Protection::Read | Protection::Write;
+        let mut young = MegaBlock::new(rw).unwrap();
+        let mut old = MegaBlock::new(rw).unwrap();
+        let y = node(&mut blocks_of(&mut young)[0], 0);
+        let mut o1 = node(&mut blocks_of(&mut old)[0], 1);
+        let mut o2 = node(&mut blocks_of(&mut old)[2], 2);
+        let mut o3 = node(&mut blocks_of(&mut old)[4], 3);
+        let o4 = node(&mut blocks_of(&mut old)[6], 4);
+        let mut generations = Generations::new(1);
+        generations.young.push_front(Box::new(young));
+        generations.old.push_front(Box::new(old));
+        generations.cards = Some(CardTable::new(&generations.old));
+
+        o1.set_pointer(0, &y, &generations);
+        o3.set_pointer(1, &o1, &generations);
+        o2.set_pointer(1, &y, &generations);
+        generations.remember(o4.start_address());
+        let mut visited = std::vec::Vec::new();
+        generations.walk_dirty(|object| visited.push(object.unpacked[0]));
+        assert_eq!(visited, [1, 2, 4]);
+        assert!(generations.remembered.is_empty());
+        assert_eq!(generations.cards.as_ref().unwrap().dirty_cards().count(), 0);
+        generations.walk_dirty(|_| panic!("nothing is dirty"));
+    }
+
+    #[test]
+    fn test_verify() {
+        let mut mega_block = MegaBlock::new(Protection::Read | Protection::Write).unwrap();
+        let blocks = blocks_of(&mut mega_block);
+        let (first, rest) = blocks.split_at_mut(1);
+        let (block0, block1) = (&mut first[0], &mut rest[0]);
+        let a = node(block0, 0);
+        let b = node(block1, 1);
+        a.pointers[0] = b.start_address();
+        b.pointers[1] = a.start_address();
+        let mut list = MegaBlockList::new();
+        list.push_front(Box::new(mega_block));
+        assert_eq!(verify(&list), Ok(()));
+
+        // a pointer past `free`, into the unused part of block 1
+        let past_free = Address::from(list.head_mut().unwrap().blocks_mut()[1].free);
+        a.pointers[1] = past_free;
+        let error = VerifyError {
+            address: Address::from(a.start_address().as_ptr::<u8>()),
+            reason: VerifyReason::DanglingPointer(Address::from(past_free.as_ptr::<u8>())),
+        };
+        assert_eq!(verify(&list), Err(error));
+        a.pointers[1] = Address::from(core::ptr::null_mut::<u8>());
+        assert_eq!(verify(&list), Ok(()));
+
+        // an object cut by `free`
+        let block1 = &mut list.head_mut().unwrap().blocks_mut()[1];
+        block1.free = unsafe { block1.free.sub(core::mem::size_of::<usize>()) };
+        assert_eq!(verify(&list).unwrap_err().reason, VerifyReason::PastFree);
+        let block1 = &mut list.head_mut().unwrap().blocks_mut()[1];
+        block1.free = unsafe { block1.free.add(core::mem::size_of::<usize>()) };
+
+        // a misaligned descriptor pointer
+        let slot = b.start_address().as_ptr::<usize>();
+        let descriptor = unsafe { *slot };
+        unsafe { *slot = descriptor + 1 };
+        assert_eq!(verify(&list).unwrap_err().reason, VerifyReason::MisalignedDescriptor);
+        unsafe { *slot = descriptor };
+        assert_eq!(verify(&list), Ok(()));
+
+        // a field map disagreeing with the field counts
+        static BROKEN: ObjectDescriptor = ObjectDescriptor {
+            unpacked_field_count: 1, pointer_count: 2, field_map: Some(&[FieldKind::Pointer]) };
+        unsafe { *slot = &BROKEN as *const _ as usize };
+        assert_eq!(verify(&list).unwrap_err().reason, VerifyReason::BadFieldMap);
+        unsafe { *slot = descriptor };
+
+        // a pointer into the middle of an object
+        let interior = unsafe { b.start_address().offset(core::mem::size_of::<usize>() as isize) };
+        a.pointers[1] = interior;
+        let reason = VerifyReason::DanglingPointer(Address::from(interior.as_ptr::<u8>()));
+        assert_eq!(verify(&list).unwrap_err().reason, reason);
+        a.pointers[1] = Address::from(core::ptr::null_mut::<u8>());
+        assert_eq!(verify(&list), Ok(()));
+    }
+
+    #[test]
+    fn test_root_set() {
+        let object = |n: usize| Address::from((0x1000 + n * 8) as *mut u8);
+        let roots = RootSet::new();
+        let outer = roots.register(object(0));
+        assert_eq!(roots.len(), 1);
+        {
+            let _inner = roots.register(object(1));
+            let duplicate = roots.register(object(0));
+            assert_eq!(roots.len(), 3);
+            assert_eq!(duplicate.address(), outer.address());
+            {
+                let _innermost = roots.register(object(2));
+                assert_eq!(roots.len(), 4);
+            }
+            assert_eq!(roots.len(), 3);
+        }
+        assert_eq!(roots.len(), 1);
+        assert_eq!(outer.address(), object(0));
+        let _reused = roots.register(object(3));
+        assert_eq!(roots.slots.borrow().len(), 4);
+        drop(outer);
+        assert_eq!(roots.len(), 1);
+    }
+
+    #[test]
+    fn test_reachable_from() {
+        let mut mega_block = MegaBlock::new(Protection::Read | Protection::Write).unwrap();
+        let block = &mut blocks_of(&mut mega_block)[0];
+        // diamond: top -> (left, right) -> bottom, with a back edge bottom -> top
+        let top = node(block, 0);
+        let left = node(block, 1);
+        let right = node(block, 2);
+        let bottom = node(block, 3);
+        let _unreachable = node(block, 4);
+        top.pointers[0] = left.start_address();
+        top.pointers[1] = right.start_address();
+        left.pointers[0] = bottom.start_address();
+        right.pointers[1] = bottom.start_address();
+        bottom.pointers[0] = top.start_address();
+
+        let reachable = reachable_from(&[&top]);
+        let tags: std::vec::Vec<_> =
+            reachable.iter().map(|address| Object::from(*address).unpacked[0]).collect();
+        assert_eq!(tags, [0, 1, 2, 3]);
+        assert_eq!(reachable_from(&[&bottom, &left, &bottom]).len(), 4);
+        assert!(reachable_from(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_has_cycle() {
+        let mut mega_block = MegaBlock::new(Protection::Read | Protection::Write).unwrap();
+        let block = &mut blocks_of(&mut mega_block)[0];
+        // diamond: top -> (left, right) -> bottom, shared but acyclic
+        let top = node(block, 0);
+        let left = node(block, 1);
+        let right = node(block, 2);
+        let bottom = node(block, 3);
+        top.pointers[0] = left.start_address();
+        top.pointers[1] = right.start_address();
+        left.pointers[0] = bottom.start_address();
+        right.pointers[0] = bottom.start_address();
+        assert!(!has_cycle(&[&top]));
+        assert!(!has_cycle(&[&bottom, &top, &left]));
+        // two-node cycle: a <-> b
+        let a = node(block, 4);
+        let b = node(block, 5);
+        a.pointers[0] = b.start_address();
+        b.pointers[1] = a.start_address();
+        assert!(has_cycle(&[&a]));
+        assert!(has_cycle(&[&top, &b]));
+        // self-loop
+        let c = node(block, 6);
+        c.pointers[0] = c.start_address();
+        assert!(has_cycle(&[&c]));
+        assert!(!has_cycle(&[]));
+    }
+
+    #[test]
+    fn test_to_dot() {
+        use std::string::String;
+        let mut mega_block = MegaBlock::new(Protection::Read | Protection::Write).unwrap();
+        let block = &mut blocks_of(&mut mega_block)[0];
+        // diamond with a back edge: top -> (left, right) -> bottom -> top
+        let top = node(block, 0);
+        let left = node(block, 1);
+        let right = node(block, 2);
+        let bottom = node(block, 3);
+        top.pointers[0] = left.start_address();
+        top.pointers[1] = right.start_address();
+        left.pointers[0] = bottom.start_address();
+        right.pointers[0] = bottom.start_address();
+        bottom.pointers[1] = top.start_address();
+
+        let mut dot = String::new();
+        to_dot(&[&top], &mut dot).unwrap();
+        assert!(dot.starts_with("digraph heap {\n"));
+        assert!(dot.ends_with("}\n"));
+        assert_eq!(dot.matches(" -> ").count(), 5);
+        assert_eq!(dot.matches("1 unpacked, 2 pointers").count(), 4);
+        let edge = std::format!("\"{:p}\" -> \"{:p}\"",
+                                bottom.start_address().as_ptr::<u8>(),
+                                top.start_address().as_ptr::<u8>());
+        assert!(dot.contains(&edge));
+    }
+
+    #[test]
+    fn test_serialize() {
+        let mut mega_block = MegaBlock::new(Protection::Read | Protection::Write).unwrap();
+        let blocks = blocks_of(&mut mega_block);
+        let (first, rest) = blocks.split_at_mut(1);
+        let (block0, block1) = (&mut first[0], &mut rest[0]);
+        // diamond with a back edge: top -> (left, right) -> bottom -> top
+        let top = node(block0, 10);
+        let left = node(block0, 11);
+        let right = node(block0, 12);
+        let bottom = node(block0, 13);
+        top.pointers[0] = left.start_address();
+        top.pointers[1] = right.start_address();
+        left.pointers[1] = bottom.start_address();
+        right.pointers[0] = bottom.start_address();
+        bottom.pointers[0] = top.start_address();
+
+        let mut bytes = std::vec::Vec::new();
+        serialize(&[&top], &mut bytes);
+        assert_eq!(bytes.len(), 8 * (1 + 4 * 5));
+        let shape = |unpacked, pointers| match (unpacked, pointers) {
+            (1, 2) => Some(&NODE),
+            _ => None,
+        };
+        let copies = deserialize(&bytes, block1, shape).unwrap();
+        let copied_range = block1.start..block1.free;
+
+        // the copies are isomorphic to the originals, in the same order
+        let originals = reachable_from(&[&top]);
+        assert_eq!(copies.len(), originals.len());
+        let position = |addresses: &[Address], target: Address| {
+            addresses.iter().position(|address| *address == target)
+        };
+        let copied: std::vec::Vec<_> = copies.iter().map(|copy| copy.start_address()).collect();
+        for (original, copy) in originals.iter().zip(&copies) {
+            let original = Object::from(*original);
+            assert!(copied_range.contains(&copy.start_address().as_ptr::<u8>()));
+            assert_eq!(original.unpacked, copy.unpacked);
+            for (p, q) in original.pointers.iter().zip(copy.pointers.iter()) {
+                if p.is_null() {
+                    assert!(q.is_null());
+                } else {
+                    assert_eq!(position(&originals, *p), position(&copied, *q));
+                }
+            }
+        }
+
+        // the block is left untouched on failure
+        let free = block1.free;
+        let unknown = |_, _| None;
+        let error = deserialize(&bytes, block1, unknown).err();
+        assert_eq!(error, Some(DeserializeError::UnknownShape(1, 2)));
+        let error = deserialize(&bytes[..bytes.len() - 8], block1, shape).err();
+        assert_eq!(error, Some(DeserializeError::Malformed));
+        let error = deserialize(&bytes[..bytes.len() - 1], block1, shape).err();
+        assert_eq!(error, Some(DeserializeError::Malformed));
+        bytes.extend_from_slice(&[0; 8]);
+        let error = deserialize(&bytes, block1, shape).err();
+        assert_eq!(error, Some(DeserializeError::Malformed));
+        assert_eq!(block1.free, free);
+    }
+
+    #[test]
+    fn test_mark_compact() {
+        let mut mega_block = MegaBlock::new(Protection::Read | Protection::Write).unwrap();
+        let blocks = blocks_of(&mut mega_block);
+        let (first, rest) = blocks.split_at_mut(1);
+        let (block0, block1) = (&mut first[0], &mut rest[0]);
+        // block 0: garbage g0, a, garbage g1, b, garbage g2, c; block 1: garbage g3, d
+        // a -> (b, d), b -> c, c -> a, d -> b
+        node(block0, 100);
+        let a = node(block0, 0);
+        node(block0, 101);
+        let b = node(block0, 1);
+        node(block0, 102);
+        let c = node(block0, 2);
+        node(block1, 103);
+        let d = node(block1, 3);
+        a.pointers[0] = b.start_address();
+        a.pointers[1] = d.start_address();
+        b.pointers[0] = c.start_address();
+        c.pointers[1] = a.start_address();
+        d.pointers[0] = b.start_address();
+        let node_bytes = NODE.total_size() * core::mem::size_of::<usize>();
+        let (start0, start1) = (block0.start, block1.start);
+
+        // an object outside the heap, pointing to c
+        let c_address = c.start_address().as_ptr::<u8>() as usize;
+        let mut outside = [&NODE as *const _ as usize, 7, c_address, 0];
+        let outside = Address::from(outside.as_mut_ptr());
+
+        let mut list = MegaBlockList::new();
+        list.push_front(Box::new(mega_block));
+        let roots = RootSet::new();
+        let root = roots.register(a.start_address());
+        let _outside = roots.register(outside);
+        let _again = roots.register(outside);
+        let stats = mark_compact(&roots, &mut list);
+        assert_eq!(stats.bytes_live, 4 * node_bytes);
+        assert_eq!(stats.bytes_reclaimed, 4 * node_bytes);
+        assert_eq!(verify(&list), Ok(()));
+
+        // live objects are contiguous at the start of their blocks, in the same order
+        let blocks = list.head_mut().unwrap().blocks_mut();
+        assert_eq!(blocks[0].free, unsafe { start0.add(3 * node_bytes) });
+        assert_eq!(blocks[1].free, unsafe { start1.add(node_bytes) });
+        let tags: std::vec::Vec<_> = blocks[0].objects().map(|o| o.unpacked[0]).collect();
+        assert_eq!(tags, [0, 1, 2]);
+        assert_eq!(blocks[1].objects().next().unwrap().unpacked[0], 3);
+
+        // pointers follow the objects
+        let tag = |address: Address| Object::from(address).unpacked[0];
+        assert_eq!(root.address().as_ptr::<u8>(), start0);
+        let a = root.object();
+        assert_eq!(tag(a.pointers[0]), 1);
+        assert_eq!(tag(a.pointers[1]), 3);
+        assert_eq!(tag(Object::from(a.pointers[0]).pointers[0]), 2);
+        assert_eq!(Object::from(a.pointers[1]).pointers[0], a.pointers[0]);
+        assert_eq!(tag(Object::from(outside).pointers[0]), 2);
+        assert_eq!(Object::from(outside).pointers[0].as_ptr::<u8>(),
+                   unsafe { start0.add(2 * node_bytes) });
+    }
+
+    #[test]
+    fn test_handle_across_compaction() {
+        let mut mega_block = MegaBlock::new(Protection::Read | Protection::Write).unwrap();
+        let block = &mut blocks_of(&mut mega_block)[0];
+        for tag in 0..3 { node(block, 100 + tag); }
+        let object = node(block, 42);
+        let child = node(block, 43);
+        object.pointers[1] = child.start_address();
+        let old_address = object.start_address();
+
+        let mut list = MegaBlockList::new();
+        list.push_front(Box::new(mega_block));
+        let roots = RootSet::new();
+        let handle = roots.register(old_address);
+        {
+            let _temporary = roots.register(child.start_address());
+        }
+        mark_compact(&roots, &mut list);
+
+        assert!(handle.address() < old_address);
+        let object = handle.object();
+        assert_eq!(object.unpacked[0], 42);
+        assert_eq!(Object::from(object.pointers[1]).unpacked[0], 43);
+        assert_eq!(roots.len(), 1);
+    }
+
+    #[test]
+    fn test_pinned_buffer() {
+        let rw = Protection::Read | Protection::Write;
+        let node_bytes = NODE.total_size() * core::mem::size_of::<usize>();
+        let mut pinned = Box::new(MegaBlock::new(rw).unwrap());
+        let mut other = MegaBlock::new(rw).unwrap();
+        // pinned: garbage g, the buffer, p -> a; other: garbage h, a
+        let block = &mut blocks_of(&mut pinned)[0];
+        node(block, 0);
+        // the buffer keeps borrowing its mega-block while the collections borrow the list
+        let mega_block: *mut MegaBlock = &mut *pinned;
+        let mut buffer = unsafe { &mut *mega_block }.alloc_pinned_buffer(100).unwrap();
+        let p = node(block, 1);
+        node(&mut blocks_of(&mut other)[0], 2);
+        let a = node(&mut blocks_of(&mut other)[0], 3);
+        p.pointers[0] = a.start_address();
+        assert_eq!(buffer.len(), 100);
+        assert!(buffer.iter().all(|b| *b == 0));
+        buffer.copy_from_slice(&[0x5A; 100]);
+        let (address, pinned_used) = (buffer.as_ptr(), block.used_bytes());
+        let mut list = MegaBlockList::new();
+        list.push_front(Box::new(other));
+        list.push_front(pinned);
+
+        let roots = RootSet::new();
+        let stats = mark_compact(&roots, &mut list);
+        assert_eq!(buffer.as_ptr(), address);
+        assert!(buffer.iter().all(|b| *b == 0x5A));
+        assert_eq!(block.used_bytes(), pinned_used);
+        assert!(p.pointers[0] < a.start_address());
+        assert_eq!(Object::from(p.pointers[0]).unpacked[0], 3);
+        assert_eq!(stats.bytes_reclaimed, node_bytes);
+
+        drop(buffer);
+        assert!(!list.head().unwrap().is_pinned());
+        let stats = mark_compact(&roots, &mut list);
+        assert_eq!(block.used_bytes(), 0);
+        assert_eq!(stats.bytes_reclaimed, pinned_used + node_bytes);
+    }
+
+    #[test]
+    fn test_pinned_evacuation() {
+        let rw = Protection::Read | Protection::Write;
+        let node_bytes = NODE.total_size() * core::mem::size_of::<usize>();
+        let mut pinned = Box::new(MegaBlock::new(rw).unwrap());
+        let mut young = MegaBlock::new(rw).unwrap();
+        // pinned: p -> a, garbage g; young: a, garbage h
+        let p = node(&mut blocks_of(&mut pinned)[0], 0);
+        node(&mut blocks_of(&mut pinned)[0], 1);
+        let a = node(&mut blocks_of(&mut young)[0], 2);
+        node(&mut blocks_of(&mut young)[0], 3);
+        p.pointers[0] = a.start_address();
+        let pin = unsafe { &*(&*pinned as *const MegaBlock) }.pin();
+        let mut generations = Generations::new(1);
+        generations.young.push_front(Box::new(young));
+        generations.young.push_front(pinned);
+        generations.survivors.push_front(Box::new(MegaBlock::new(rw).unwrap()));
+        let roots = RootSet::new();
+        let tag = |address| Object::from(address).unpacked[0];
+
+        let stats = minor_collection(&mut generations, &roots);
+        assert_eq!(generations.young.len(), 2);
+        assert!(generations.young.find_containing(p.start_address()).is_some());
+        assert!(generations.young.find_containing(p.pointers[0]).is_some());
+        assert_ne!(p.pointers[0], a.start_address());
+        assert_eq!(tag(p.pointers[0]), 2);
+        assert_eq!((stats.bytes_live, stats.bytes_reclaimed), (3 * node_bytes, node_bytes));
+
+        let stats = scavenge(&mut generations.young, &mut generations.survivors, &roots);
+        assert!(generations.survivors.find_containing(p.pointers[0]).is_some());
+        assert_eq!(tag(p.pointers[0]), 2);
+        assert_eq!((stats.bytes_live, stats.bytes_reclaimed), (3 * node_bytes, 0));
+
+        drop(pin);
+        let stats = scavenge(&mut generations.young, &mut generations.old, &roots);
+        assert!(generations.young.iter().all(|mega_block| mega_block.is_empty()));
+        assert_eq!((stats.bytes_live, stats.bytes_reclaimed), (0, 2 * node_bytes));
+    }
+}
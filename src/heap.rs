@@ -0,0 +1,941 @@
+/*
+ * garbage-collected memory manager in Rust
+ * Copyright (C) 2020  Xie Ruifeng
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! A bump-allocating heap for raw memory requests.
+use super::primitives::{self, Advice};
+use super::common::Address;
+use super::allocate::{MegaBlock, MMapError, Protection, Result};
+use super::block::BlockDescriptor;
+use super::object::{Object, ObjectDescriptor};
+
+use core::alloc::Layout;
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+#[cfg(feature = "allocator-api")]
+use allocator_api2::alloc::AllocError;
+#[cfg(feature = "allocator-api")]
+use core::ptr::NonNull;
+
+/// Header at the start of every region reserved by a `Heap`, linking all the regions together.
+struct Region {
+    previous: *mut Region,
+    size: usize,
+    /// End of the memory handed out from this region, set once it is no longer the current one.
+    limit: *mut u8,
+}
+
+/// Header of a deallocated range of memory, linking all such ranges together.
+struct FreeNode {
+    next: *mut FreeNode,
+    size: usize,
+}
+
+/// Growth and shrinking policy of a [`Heap`](struct.Heap.html), see
+/// [`Heap::with_config`](struct.Heap.html#method.with_config).
+///
+/// The capacity of a heap is the total size of its regions. Keeping `shrink_ratio` well below
+/// `grow_ratio` avoids reserving and releasing regions over and over under an oscillating load.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct HeapConfig {
+    /// A new region is reserved ahead of time once the bytes in use exceed this fraction of the
+    /// capacity. At 1, regions are only reserved when an allocation does not fit anymore.
+    pub grow_ratio: f64,
+    /// Wholly free regions are only given back to the system while the bytes in use are below
+    /// this fraction of the capacity.
+    pub shrink_ratio: f64,
+    /// Minimum number of deallocations between two shrinks.
+    pub cooldown: usize,
+}
+
+impl HeapConfig {
+    /// The default policy: grow only when full, shrink below half of the capacity, no cooldown.
+    pub const DEFAULT: HeapConfig = HeapConfig { grow_ratio: 1.0, shrink_ratio: 0.5, cooldown: 0 };
+}
+
+impl Default for HeapConfig {
+    fn default() -> Self { HeapConfig::DEFAULT }
+}
+
+/// The bump-allocation state of a `Heap`.
+///
+/// Memory in `[free, committed)` is ready for allocation, `[committed, end)` is only reserved.
+/// Deallocated ranges large enough for a `FreeNode` are kept in `free_list` for reuse, sorted by
+/// address, adjacent ranges are merged into one.
+struct State {
+    regions: *mut Region,
+    free_list: *mut FreeNode,
+    free: *mut u8,
+    committed: *mut u8,
+    end: *mut u8,
+    config: HeapConfig,
+    /// Total size of the regions.
+    capacity: usize,
+    /// Number of regions reserved and released so far.
+    grows: usize,
+    shrinks: usize,
+    /// Number of deallocations since the last shrink.
+    since_shrink: usize,
+}
+
+/// A heap bump-allocating raw memory out of reserved mega-block sized regions.
+///
+/// Regions are reserved with [`aligned_reserve_chunk`], and committed block by block as the
+/// allocation pointer proceeds. Explicitly deallocated memory is kept for reuse by later
+/// allocations, merged with the free memory around it: the pages of a block (or larger page) it
+/// covers entirely are given back to the system, and so are the regions other than the current
+/// one once they are free as a whole, as allowed by the [`HeapConfig`]. All the other regions
+/// are given back when the heap is dropped.
+///
+/// The heap never allocates through `alloc`, so with the `global-alloc` feature it can serve as
+/// the `#[global_allocator]`. With the `allocator-api` feature, `&Heap` implements
+/// `allocator_api2::alloc::Allocator`, so containers can allocate from a heap:
+///
+/// ```
+/// # #[cfg(feature = "allocator-api")] {
+/// use allocator_api2::vec::Vec;
+/// use memory_manager::heap::Heap;
+/// let heap = Heap::new();
+/// let mut v = Vec::new_in(&heap);
+/// v.extend_from_slice(&[1, 2, 3]);
+/// assert_eq!(v, [1, 2, 3]);
+/// # }
+/// ```
+///
+/// ```
+/// use core::alloc::Layout;
+/// use memory_manager::heap::Heap;
+/// let heap = Heap::new();
+/// let p = heap.allocate(Layout::from_size_align(24, 8).unwrap())?;
+/// assert_eq!(p as usize % 8, 0);
+/// # Ok::<(), memory_manager::primitives::MMapError>(())
+/// ```
+///
+/// [`aligned_reserve_chunk`]: ../primitives/fn.aligned_reserve_chunk.html
+/// [`HeapConfig`]: struct.HeapConfig.html
+pub struct Heap {
+    lock: AtomicBool,
+    state: UnsafeCell<State>,
+    in_use: AtomicUsize,
+    peak: AtomicUsize,
+}
+
+unsafe impl Sync for Heap {}
+unsafe impl Send for Heap {}
+
+impl Default for Heap {
+    fn default() -> Self { Heap::new() }
+}
+
+impl Heap {
+    /// Constructor for `Heap`, with the default `HeapConfig`. Nothing is reserved until the
+    /// first allocation.
+    pub const fn new() -> Self {
+        Heap {
+            lock: AtomicBool::new(false),
+            state: UnsafeCell::new(State {
+                regions: core::ptr::null_mut(),
+                free_list: core::ptr::null_mut(),
+                free: core::ptr::null_mut(),
+                committed: core::ptr::null_mut(),
+                end: core::ptr::null_mut(),
+                config: HeapConfig::DEFAULT,
+                capacity: 0,
+                grows: 0,
+                shrinks: 0,
+                since_shrink: 0,
+            }),
+            in_use: AtomicUsize::new(0),
+            peak: AtomicUsize::new(0),
+        }
+    }
+
+    /// Constructor for `Heap` growing and shrinking according to `config`.
+    ///
+    /// ```
+    /// use core::alloc::Layout;
+    /// use memory_manager::allocate::MegaBlock;
+    /// use memory_manager::heap::{Heap, HeapConfig};
+    /// let config = HeapConfig { grow_ratio: 0.5, shrink_ratio: 0.25, cooldown: 0 };
+    /// let heap = Heap::with_config(config);
+    /// heap.allocate(Layout::from_size_align(MegaBlock::SIZE * 3 / 4, 8).unwrap())?;
+    /// assert_eq!(heap.grow_count(), 2);
+    /// # Ok::<(), memory_manager::primitives::MMapError>(())
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// Panics if `config.shrink_ratio` is not below `config.grow_ratio`.
+    pub fn with_config(config: HeapConfig) -> Self {
+        let HeapConfig { grow_ratio, shrink_ratio, .. } = config;
+        assert!(shrink_ratio < grow_ratio,
+                "shrink ratio {} is not below grow ratio {}", shrink_ratio, grow_ratio);
+        let mut heap = Heap::new();
+        heap.state.get_mut().config = config;
+        heap
+    }
+
+    /// Allocate memory fitting `layout`, by bumping the allocation pointer up to the alignment,
+    /// then past the requested size. A new region is reserved if the current one is exhausted.
+    ///
+    /// Previously deallocated memory fitting `layout` is reused first.
+    pub fn allocate(&self, layout: Layout) -> Result<*mut u8> {
+        let p = self.with_state(|state| {
+            let p = match state.reuse(layout) {
+                Some(p) => p,
+                None => match state.bump(layout)? {
+                    Some(p) => p,
+                    None => {
+                        state.reserve(layout)?;
+                        state.bump(layout)?.ok_or(MMapError::NoMemory)?
+                    }
+                },
+            };
+            let in_use = self.in_use.load(Ordering::Relaxed) + layout.size();
+            if in_use as f64 > state.config.grow_ratio * state.capacity as f64 {
+                // growing ahead of time is optional, the allocation succeeded anyway
+                let _ = state.reserve(Layout::new::<u8>());
+            }
+            Ok(p)
+        })?;
+        self.grow_in_use(layout.size());
+        Ok(p)
+    }
+
+    /// Give back memory allocated with `layout` for reuse. The most recent allocation is undone
+    /// directly, other memory is kept in a free list if it can hold the list node, or if it
+    /// extends a free range next to it.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must come from `allocate` with the same `layout`, and must not be used afterwards.
+    pub unsafe fn deallocate(&self, ptr: *mut u8, layout: Layout) {
+        let in_use = self.in_use.fetch_sub(layout.size(), Ordering::Relaxed) - layout.size();
+        self.with_state(|state| {
+            state.release(ptr, layout.size());
+            state.since_shrink += 1;
+            state.shrink(in_use);
+        });
+    }
+
+    /// Try to resize the memory at `ptr` from `old_size` to `new_size` without moving it, which
+    /// is possible when shrinking, when growing the most recent allocation in a region with
+    /// enough space left, or when growing into a large enough free range right after the memory.
+    /// Returns whether the memory has been resized.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must come from `allocate` with a layout of size `old_size`.
+    pub unsafe fn resize_in_place(&self, ptr: *mut u8, old_size: usize, new_size: usize) -> bool {
+        let resized = self.with_state(|state| if new_size <= old_size {
+            state.release(ptr.add(new_size), old_size - new_size);
+            true
+        } else if ptr.add(old_size) == state.free {
+            new_size - old_size <= state.end as usize - state.free as usize
+                && state.advance(ptr.add(new_size)).is_ok()
+        } else {
+            state.take(ptr.add(old_size), new_size - old_size)
+        });
+        if resized && new_size <= old_size {
+            self.in_use.fetch_sub(old_size - new_size, Ordering::Relaxed);
+        } else if resized {
+            self.grow_in_use(new_size - old_size);
+        }
+        resized
+    }
+
+    /// Whether `addr` could be a pointer into this heap, i.e. it lies in some region reserved by
+    /// this heap, for conservative scanning of words of unknown types.
+    ///
+    /// There are no false negatives for memory allocated from this heap, but addresses in the
+    /// unallocated parts of a region are reported as well.
+    pub fn is_heap_pointer(&self, addr: usize) -> bool {
+        self.with_state(|state| {
+            let mut region = state.regions;
+            while !region.is_null() {
+                let Region { previous, size, .. } = unsafe { region.read() };
+                let start = region as usize;
+                if start <= addr && addr - start < size { return true; }
+                region = previous;
+            }
+            false
+        })
+    }
+
+    /// Acquire a thread-local allocation buffer of `size` bytes (rounded up to whole words).
+    ///
+    /// Allocation in the buffer only bumps a local pointer, the heap is locked only when the
+    /// buffer is acquired or refilled.
+    ///
+    /// ```
+    /// use memory_manager::heap::Heap;
+    /// use memory_manager::object::ObjectDescriptor;
+    /// static PAIR: ObjectDescriptor =
+    ///     ObjectDescriptor { unpacked_field_count: 2, pointer_count: 0, field_map: None };
+    /// let heap = Heap::new();
+    /// let mut tlab = heap.acquire_tlab(4096)?;
+    /// let object = match tlab.allocate(&PAIR) {
+    ///     Some(object) => object,
+    ///     None => { tlab.refill()?; tlab.allocate(&PAIR).unwrap() }
+    /// };
+    /// assert_eq!(object.unpacked, [0, 0]);
+    /// # Ok::<(), memory_manager::primitives::MMapError>(())
+    /// ```
+    pub fn acquire_tlab(&self, size: usize) -> Result<Tlab<'_>> {
+        let word = core::mem::size_of::<usize>();
+        let size = size.checked_add(word - 1).ok_or(MMapError::InvalidArguments)? & !(word - 1);
+        let mut tlab = Tlab {
+            heap: self,
+            free: core::ptr::null_mut(),
+            end: core::ptr::null_mut(),
+            size,
+        };
+        tlab.refill()?;
+        Ok(tlab)
+    }
+
+    /// Total size of the regions currently reserved by this heap.
+    pub fn capacity(&self) -> usize { self.with_state(|state| state.capacity) }
+
+    /// Number of regions reserved by this heap so far.
+    pub fn grow_count(&self) -> usize { self.with_state(|state| state.grows) }
+
+    /// Number of regions given back to the system by this heap so far, not counting the ones
+    /// given back when the heap is dropped.
+    pub fn shrink_count(&self) -> usize { self.with_state(|state| state.shrinks) }
+
+    /// Bytes currently allocated from this heap, i.e. the sizes of the allocated layouts minus
+    /// the deallocated ones. A thread-local allocation buffer is counted in full when it is
+    /// acquired or refilled, and only its unused rest is given back when it is released.
+    pub fn heap_bytes_in_use(&self) -> usize { self.in_use.load(Ordering::Relaxed) }
+
+    /// The maximum [`heap_bytes_in_use`](#method.heap_bytes_in_use) ever reached, since this
+    /// heap was created or since the last [`reset_peak`](#method.reset_peak).
+    ///
+    /// ```
+    /// use core::alloc::Layout;
+    /// use memory_manager::heap::Heap;
+    /// let heap = Heap::new();
+    /// let layout = Layout::new::<[u64; 8]>();
+    /// let p = heap.allocate(layout)?;
+    /// unsafe { heap.deallocate(p, layout) };
+    /// assert_eq!((heap.heap_bytes_in_use(), heap.heap_peak_bytes()), (0, 64));
+    /// # Ok::<(), memory_manager::primitives::MMapError>(())
+    /// ```
+    pub fn heap_peak_bytes(&self) -> usize { self.peak.load(Ordering::Relaxed) }
+
+    /// Restart tracking the peak from the current usage.
+    pub fn reset_peak(&self) {
+        self.peak.store(self.heap_bytes_in_use(), Ordering::Relaxed);
+    }
+
+    /// Count `size` more bytes in use, raising the peak if it is exceeded.
+    fn grow_in_use(&self, size: usize) {
+        let in_use = self.in_use.fetch_add(size, Ordering::Relaxed) + size;
+        if in_use > self.peak.load(Ordering::Relaxed) {
+            self.peak.fetch_max(in_use, Ordering::Relaxed);
+        }
+    }
+
+    fn with_state<T>(&self, f: impl FnOnce(&mut State) -> T) -> T {
+        while self.lock.compare_exchange_weak(
+            false, true, Ordering::Acquire, Ordering::Relaxed).is_err() {
+            core::hint::spin_loop();
+        }
+        let res = f(unsafe { &mut *self.state.get() });
+        self.lock.store(false, Ordering::Release);
+        res
+    }
+}
+
+/// Thread-local allocation buffer: a range of memory taken from a `Heap`, owned by a single
+/// thread, and allocated from by bumping a local pointer without locking.
+///
+/// The unused rest of the buffer is given back to the heap when the buffer is refilled or dropped.
+pub struct Tlab<'h> {
+    heap: &'h Heap,
+    free: *mut u8,
+    end: *mut u8,
+    size: usize,
+}
+
+impl<'h> Tlab<'h> {
+    /// Number of bytes still available for allocation in this buffer.
+    pub fn remaining_bytes(&self) -> usize {
+        self.end as usize - self.free as usize
+    }
+
+    /// Allocate an object described by `descriptor` by bumping the local `free` pointer.
+    ///
+    /// The descriptor pointer is written at the start of the new object, and all its fields are
+    /// zero-initialized. Returns `None` if the rest of this buffer cannot hold the object, in
+    /// which case the buffer should be [`refill`](#method.refill)ed.
+    ///
+    /// The object may outlive this buffer, but not the heap, which unmaps its memory on drop:
+    ///
+    /// ```compile_fail
+    /// use memory_manager::heap::Heap;
+    /// use memory_manager::object::ObjectDescriptor;
+    /// static PAIR: ObjectDescriptor =
+    ///     ObjectDescriptor { unpacked_field_count: 2, pointer_count: 0, field_map: None };
+    /// let object = {
+    ///     let heap = Heap::new();
+    ///     let mut tlab = heap.acquire_tlab(64).unwrap();
+    ///     tlab.allocate(&PAIR).unwrap()
+    /// };
+    /// object.unpacked[0] = 42;
+    /// ```
+    pub fn allocate(&mut self, descriptor: &'h ObjectDescriptor) -> Option<Object<'h>> {
+        let word = core::mem::size_of::<usize>();
+        if !descriptor.validate(self.remaining_bytes() / word) { return None; }
+        let words = descriptor.total_size();
+        let address = Address::from(self.free);
+        unsafe {
+            let slots = address.as_ptr::<usize>();
+            slots.write(descriptor as *const _ as usize);
+            core::ptr::write_bytes(slots.add(1), 0, words - 1);
+            self.free = self.free.add(words * word);
+        }
+        Some(Object::from(address))
+    }
+
+    /// Give back the rest of this buffer, and take a fresh one of the same size from the heap.
+    pub fn refill(&mut self) -> Result<()> {
+        self.release();
+        let layout = Layout::from_size_align(self.size, core::mem::size_of::<usize>())
+            .map_err(|_| MMapError::InvalidArguments)?;
+        self.free = self.heap.allocate(layout)?;
+        self.end = unsafe { self.free.add(self.size) };
+        Ok(())
+    }
+
+    fn release(&mut self) {
+        if self.free.is_null() { return; }
+        let rest = self.remaining_bytes();
+        self.heap.with_state(|state| state.release(self.free, rest));
+        self.heap.in_use.fetch_sub(rest, Ordering::Relaxed);
+        self.free = core::ptr::null_mut();
+        self.end = core::ptr::null_mut();
+    }
+}
+
+impl<'h> Drop for Tlab<'h> {
+    fn drop(&mut self) {
+        self.release();
+    }
+}
+
+/// Memory is committed in units of this size: a `Block`, or a page if pages are larger.
+fn commit_granularity() -> Result<usize> {
+    Ok(BlockDescriptor::SIZE.max(primitives::get_page_size()?))
+}
+
+impl State {
+    /// Allocate from the current region, `None` if it cannot hold `layout`.
+    fn bump(&mut self, layout: Layout) -> Result<Option<*mut u8>> {
+        if self.free.is_null() { return Ok(None); }
+        let start = Address::from(self.free).align_up(layout.align());
+        let start = start.as_ptr::<u8>();
+        if start > self.end || layout.size() > self.end as usize - start as usize {
+            return Ok(None);
+        }
+        self.advance(unsafe { start.add(layout.size()) })?;
+        Ok(Some(start))
+    }
+
+    /// Move the allocation pointer to `end` in the current region, committing memory as needed.
+    fn advance(&mut self, end: *mut u8) -> Result<()> {
+        if end > self.committed {
+            let target = Address::from(end).align_up(commit_granularity()?).as_ptr::<u8>();
+            let len = target as usize - self.committed as usize;
+            let rw = Protection::Read | Protection::Write;
+            unsafe { primitives::commit(self.committed as _, len, rw)?; }
+            self.committed = target;
+        }
+        self.free = end;
+        Ok(())
+    }
+
+    /// Take the first range in the free list fitting `layout`, the rest of the range goes back
+    /// to the free list.
+    fn reuse(&mut self, layout: Layout) -> Option<*mut u8> {
+        let mut link: *mut *mut FreeNode = &mut self.free_list;
+        unsafe {
+            while !(*link).is_null() {
+                let node = *link;
+                let FreeNode { next, size } = node.read();
+                let start = node as *mut u8;
+                if start as usize & (layout.align() - 1) == 0 && layout.size() <= size {
+                    *link = next;
+                    self.insert(start.add(layout.size()), start.add(size));
+                    return Some(start);
+                }
+                link = &mut (*node).next;
+            }
+        }
+        None
+    }
+
+    /// Take `size` bytes from the free range starting at `start`, if there is such a range large
+    /// enough. The rest of the range stays in the free list.
+    fn take(&mut self, start: *mut u8, size: usize) -> bool {
+        let mut link: *mut *mut FreeNode = &mut self.free_list;
+        unsafe {
+            while !(*link).is_null() && (*link as *mut u8) < start { link = &mut (**link).next; }
+            let node = *link;
+            if node as *mut u8 != start || (*node).size < size { return false; }
+            let FreeNode { next, size: available } = node.read();
+            *link = next;
+            self.insert(start.add(size), start.add(available));
+        }
+        true
+    }
+
+    /// Give back the memory in `[ptr, ptr + size)`.
+    fn release(&mut self, ptr: *mut u8, size: usize) {
+        let end = unsafe { ptr.add(size) };
+        if end == self.free {
+            self.free = ptr;
+            return;
+        }
+        if let Some(link) = self.insert(ptr, end) { self.reclaim(link, ptr, end); }
+    }
+
+    /// Put `[start, end)` in the free list, merged with the ranges right before and after it.
+    /// Returns the link to the range now holding it, `None` if it is too small to be kept.
+    fn insert(&mut self, start: *mut u8, end: *mut u8) -> Option<*mut *mut FreeNode> {
+        if start == end { return None; }
+        let mut previous: *mut *mut FreeNode = core::ptr::null_mut();
+        let mut link: *mut *mut FreeNode = &mut self.free_list;
+        unsafe {
+            while !(*link).is_null() && (*link as *mut u8) < start {
+                previous = link;
+                link = &mut (**link).next;
+            }
+            // the next range is read first, a new node may overwrite its header
+            let (mut next, mut extra) = (*link, 0);
+            if next as *mut u8 == end {
+                let FreeNode { next: after, size } = next.read();
+                next = after;
+                extra = size;
+            }
+            if !previous.is_null() && (*previous as *mut u8).add((**previous).size) == start {
+                (**previous).size += end as usize - start as usize + extra;
+                (**previous).next = next;
+                return Some(previous);
+            }
+            let align = core::mem::align_of::<FreeNode>();
+            let node = Address::from(start).align_up(align).as_ptr::<FreeNode>();
+            if node as *mut u8 > end { return None; }
+            let size = end as usize - node as usize + extra;
+            if size < core::mem::size_of::<FreeNode>() { return None; }
+            node.write(FreeNode { next, size });
+            *link = node;
+            Some(link)
+        }
+    }
+
+    /// Give back to the system the pages of the free range at `*link` that `[start, end)` just
+    /// made entirely free, keeping the ones of the list node.
+    fn reclaim(&mut self, link: *mut *mut FreeNode, start: *mut u8, end: *mut u8) {
+        let node = unsafe { *link };
+        let (node_start, node_end) = (node as usize, node as usize + unsafe { (*node).size });
+        let granularity = match commit_granularity() {
+            Ok(granularity) => granularity,
+            Err(_) => return,
+        };
+        // the pages touching `[start, end)` which are now entirely free
+        let mask = granularity - 1;
+        let from = (node_start + core::mem::size_of::<FreeNode>()).max(start as usize & !mask);
+        let from = (from + mask) & !mask;
+        let to = node_end.min((end as usize + mask) & !mask) & !mask;
+        if from < to {
+            // best effort: the pages are only reused later, whether they are reclaimed or not
+            let _ = unsafe { primitives::advise(from as _, to - from, Advice::DontNeed) };
+        }
+    }
+
+    /// Give back the wholly free regions other than the current one, while the `in_use` bytes are
+    /// below the shrink ratio of the capacity, unless the last shrink is too recent.
+    fn shrink(&mut self, in_use: usize) {
+        if self.regions.is_null() || self.since_shrink < self.config.cooldown { return; }
+        let header = core::mem::size_of::<Region>();
+        let mut released = false;
+        unsafe {
+            let mut region_link: *mut *mut Region = &mut (*self.regions).previous;
+            while !(*region_link).is_null()
+                && (in_use as f64) < self.config.shrink_ratio * self.capacity as f64 {
+                let region = *region_link;
+                let Region { previous, size, limit } = region.read();
+                let start = (region as *mut u8).add(header);
+                let mut link: *mut *mut FreeNode = &mut self.free_list;
+                while !(*link).is_null() && (*link as *mut u8) < start {
+                    link = &mut (**link).next;
+                }
+                let node = *link;
+                if node as *mut u8 != start || (start as usize + (*node).size) != limit as usize {
+                    region_link = &mut (*region).previous;
+                    continue;
+                }
+                *link = (*node).next;
+                *region_link = previous;
+                primitives::deallocate_chunk(region as _, size)
+                    .expect("failed to deallocate memory: ");
+                self.capacity -= size;
+                self.shrinks += 1;
+                released = true;
+            }
+        }
+        if released { self.since_shrink = 0; }
+    }
+
+    /// Reserve a new region large enough for `layout`, and make it the current one. The committed
+    /// rest of the current region goes to the free list, the uncommitted rest is abandoned.
+    fn reserve(&mut self, layout: Layout) -> Result<()> {
+        let alignment = primitives::get_minimum_alignment()?;
+        let header = core::mem::size_of::<Region>();
+        let needed = header.checked_add(layout.size())
+            .and_then(|n| n.checked_add(layout.align()))
+            .ok_or(MMapError::InvalidArguments)?;
+        let size = Address::from(needed.max(MegaBlock::SIZE) as *mut u8)
+            .align_up(alignment).as_ptr::<u8>() as usize;
+        let granularity = commit_granularity()?;
+        let (rest, committed) = (self.free, self.committed);
+        unsafe {
+            let base = primitives::aligned_reserve_chunk(alignment, size)? as *mut u8;
+            primitives::commit(base as _, granularity, Protection::Read | Protection::Write)?;
+            if !self.regions.is_null() { (*self.regions).limit = committed; }
+            let region = base as *mut Region;
+            region.write(Region { previous: self.regions, size, limit: core::ptr::null_mut() });
+            self.regions = region;
+            self.capacity += size;
+            self.grows += 1;
+            self.free = base.add(header);
+            self.committed = base.add(granularity);
+            self.end = base.add(size);
+        }
+        if rest < committed { self.release(rest, committed as usize - rest as usize); }
+        Ok(())
+    }
+}
+
+impl Drop for Heap {
+    fn drop(&mut self) {
+        let mut region = self.state.get_mut().regions;
+        while !region.is_null() {
+            let Region { previous, size, .. } = unsafe { region.read() };
+            unsafe {
+                primitives::deallocate_chunk(region as _, size)
+                    .expect("failed to deallocate memory: ")
+            }
+            region = previous;
+        }
+    }
+}
+
+/// Allocation failures are reported as null pointers, `dealloc` is a no-op.
+#[cfg(feature = "global-alloc")]
+unsafe impl core::alloc::GlobalAlloc for Heap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.allocate(layout).unwrap_or(core::ptr::null_mut())
+    }
+
+    unsafe fn dealloc(&self, _ptr: *mut u8, _layout: Layout) {}
+}
+
+/// Like `NonNull::slice_from_raw_parts`, which is younger than the minimum supported Rust.
+#[cfg(feature = "allocator-api")]
+fn slice_from_raw_parts(data: NonNull<u8>, len: usize) -> NonNull<[u8]> {
+    unsafe { NonNull::new_unchecked(core::ptr::slice_from_raw_parts_mut(data.as_ptr(), len)) }
+}
+
+#[cfg(feature = "allocator-api")]
+unsafe impl allocator_api2::alloc::Allocator for &Heap {
+    fn allocate(&self, layout: Layout) -> core::result::Result<NonNull<[u8]>, AllocError> {
+        let p = Heap::allocate(self, layout).map_err(|_| AllocError)?;
+        let p = NonNull::new(p).ok_or(AllocError)?;
+        Ok(slice_from_raw_parts(p, layout.size()))
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        Heap::deallocate(self, ptr.as_ptr(), layout)
+    }
+
+    unsafe fn grow(
+        &self, ptr: NonNull<u8>, old_layout: Layout,
+        new_layout: Layout) -> core::result::Result<NonNull<[u8]>, AllocError> {
+        let aligned = ptr.as_ptr() as usize & (new_layout.align() - 1) == 0;
+        if aligned && self.resize_in_place(ptr.as_ptr(), old_layout.size(), new_layout.size()) {
+            return Ok(slice_from_raw_parts(ptr, new_layout.size()));
+        }
+        let new = allocator_api2::alloc::Allocator::allocate(self, new_layout)?;
+        core::ptr::copy_nonoverlapping(ptr.as_ptr(), new.as_ptr() as *mut u8, old_layout.size());
+        Heap::deallocate(self, ptr.as_ptr(), old_layout);
+        Ok(new)
+    }
+
+    unsafe fn shrink(
+        &self, ptr: NonNull<u8>, old_layout: Layout,
+        new_layout: Layout) -> core::result::Result<NonNull<[u8]>, AllocError> {
+        if ptr.as_ptr() as usize & (new_layout.align() - 1) == 0 {
+            self.resize_in_place(ptr.as_ptr(), old_layout.size(), new_layout.size());
+            return Ok(slice_from_raw_parts(ptr, new_layout.size()));
+        }
+        let new = allocator_api2::alloc::Allocator::allocate(self, new_layout)?;
+        core::ptr::copy_nonoverlapping(ptr.as_ptr(), new.as_ptr() as *mut u8, new_layout.size());
+        Heap::deallocate(self, ptr.as_ptr(), old_layout);
+        Ok(new)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::{Heap, HeapConfig, FreeNode};
+    use super::super::allocate::MegaBlock;
+    use super::super::block::BlockDescriptor;
+    use super::super::object::ObjectDescriptor;
+    use core::alloc::Layout;
+    use std::vec::Vec;
+
+    #[test]
+    fn test_allocate() {
+        let heap = Heap::new();
+        let mut ranges = Vec::new();
+        for (i, align) in [1, 8, 64, 1, 4096, 16].iter().cycle().take(60).enumerate() {
+            let layout = Layout::from_size_align(i * 13 + 1, *align).unwrap();
+            let p = heap.allocate(layout).unwrap();
+            assert_eq!(p as usize % align, 0);
+            unsafe { core::ptr::write_bytes(p, 0xAA, layout.size()) };
+            ranges.push((p as usize, p as usize + layout.size()));
+        }
+        ranges.sort();
+        assert!(ranges.windows(2).all(|w| w[0].1 <= w[1].0));
+    }
+
+    #[test]
+    fn test_allocate_large() {
+        let heap = Heap::new();
+        let small = heap.allocate(Layout::new::<u64>()).unwrap();
+        let layout = Layout::from_size_align(MegaBlock::SIZE * 2, 4096).unwrap();
+        let large = heap.allocate(layout).unwrap();
+        unsafe { core::ptr::write_bytes(large, 0xAA, layout.size()) };
+        unsafe { small.write(42) };
+        assert_eq!(unsafe { small.read() }, 42);
+    }
+
+    #[cfg(feature = "global-alloc")]
+    #[test]
+    fn test_global_alloc() {
+        use core::alloc::GlobalAlloc;
+        let heap = Heap::new();
+        let layouts = [Layout::new::<u8>(), Layout::new::<u128>(), Layout::new::<[u64; 7]>()];
+        let pointers: Vec<_> = layouts.iter().map(|l| unsafe { heap.alloc(*l) }).collect();
+        for (p, layout) in pointers.iter().zip(layouts.iter()) {
+            assert!(!p.is_null());
+            assert_eq!(*p as usize % layout.align(), 0);
+            unsafe { heap.dealloc(*p, *layout) };
+        }
+    }
+
+    #[test]
+    fn test_deallocate_reuse() {
+        let heap = Heap::new();
+        let layout = Layout::new::<[u64; 4]>();
+        let a = heap.allocate(layout).unwrap();
+        let b = heap.allocate(layout).unwrap();
+        unsafe { heap.deallocate(a, layout) };
+        assert_eq!(heap.allocate(Layout::new::<[u64; 2]>()).unwrap(), a);
+        unsafe { heap.deallocate(b, layout) };
+        assert_eq!(heap.allocate(layout).unwrap(), b);
+        assert!(unsafe { heap.resize_in_place(b, layout.size(), 4096) });
+        assert!(!unsafe { heap.resize_in_place(a, 16, 4096) });
+    }
+
+    /// The ranges in the free list of `heap`, as `(start, size)` pairs.
+    fn free_ranges(heap: &Heap) -> Vec<(usize, usize)> {
+        heap.with_state(|state| {
+            let mut ranges = Vec::new();
+            let mut node = state.free_list;
+            while !node.is_null() {
+                let FreeNode { next, size } = unsafe { node.read() };
+                ranges.push((node as usize, size));
+                node = next;
+            }
+            ranges
+        })
+    }
+
+    #[test]
+    fn test_defragment() {
+        let heap = Heap::new();
+        let size = BlockDescriptor::SIZE;
+        let first = Layout::from_size_align(64, size).unwrap();
+        let layout = Layout::from_size_align(64, 8).unwrap();
+        let mut pointers = std::vec![heap.allocate(first).unwrap()];
+        pointers.extend((1..size / 64).map(|_| heap.allocate(layout).unwrap()));
+        let block = pointers[0];
+        assert_eq!(pointers[pointers.len() - 1] as usize + 64, block as usize + size);
+        // keeps the block away from the allocation pointer
+        let _guard = heap.allocate(layout).unwrap();
+
+        let n = pointers.len();
+        for i in (0..n).step_by(2).chain((1..n).step_by(2).rev()) {
+            unsafe { heap.deallocate(pointers[i], if i == 0 { first } else { layout }) };
+        }
+        assert_eq!(free_ranges(&heap), [(block as usize, size)]);
+        let whole = Layout::from_size_align(size, 8).unwrap();
+        assert_eq!(heap.allocate(whole).unwrap(), block);
+        assert!(free_ranges(&heap).is_empty());
+    }
+
+    #[test]
+    fn test_release_region() {
+        let heap = Heap::new();
+        let small = Layout::new::<[u64; 4]>();
+        let a = heap.allocate(small).unwrap();
+        let b = heap.allocate(small).unwrap();
+        // does not fit in the first region, the rest of which goes to the free list
+        let large = Layout::from_size_align(MegaBlock::SIZE, 8).unwrap();
+        let c = heap.allocate(large).unwrap();
+        assert_eq!(free_ranges(&heap).len(), 1);
+        unsafe { heap.deallocate(c, large) };
+        unsafe { heap.deallocate(b, small) };
+        assert!(heap.is_heap_pointer(a as usize));
+        unsafe { heap.deallocate(a, small) };
+        assert!(!heap.is_heap_pointer(a as usize));
+        assert!(heap.is_heap_pointer(c as usize));
+        assert!(free_ranges(&heap).is_empty());
+        assert_eq!((heap.grow_count(), heap.shrink_count()), (2, 1));
+    }
+
+    #[test]
+    fn test_oscillating_load() {
+        let half = Layout::from_size_align(MegaBlock::SIZE / 2, 8).unwrap();
+        let oscillate = |config| {
+            let heap = Heap::with_config(config);
+            let _base = heap.allocate(half).unwrap();
+            for _ in 0..50 {
+                let peak = [heap.allocate(half).unwrap(), heap.allocate(half).unwrap()];
+                for p in peak.iter() { unsafe { heap.deallocate(*p, half) }; }
+            }
+            (heap.grow_count(), heap.shrink_count())
+        };
+        // shrinking as soon as possible: a region is reserved and released on every cycle
+        let (grows, shrinks) =
+            oscillate(HeapConfig { grow_ratio: 1.0, shrink_ratio: 0.9, cooldown: 0 });
+        assert!(grows >= 50 && shrinks >= 50);
+        // at most one shrink every 20 deallocations, and a grow only after a shrink
+        let (grows, shrinks) =
+            oscillate(HeapConfig { grow_ratio: 1.0, shrink_ratio: 0.25, cooldown: 20 });
+        assert!(shrinks <= 100 / 20);
+        assert!(grows <= 2 + shrinks);
+    }
+
+    #[test]
+    fn test_peak_bytes() {
+        let heap = Heap::new();
+        let layout = Layout::new::<[u64; 4]>();
+        let pointers: Vec<_> = (0..10).map(|_| heap.allocate(layout).unwrap()).collect();
+        assert_eq!(heap.heap_bytes_in_use(), 10 * 32);
+        for p in &pointers[5..] { unsafe { heap.deallocate(*p, layout) }; }
+        let a = heap.allocate(layout).unwrap();
+        assert!(unsafe { heap.resize_in_place(a, 32, 64) });
+        assert_eq!(heap.heap_bytes_in_use(), 6 * 32 + 32);
+        assert_eq!(heap.heap_peak_bytes(), 10 * 32);
+        unsafe { heap.deallocate(a, Layout::new::<[u64; 8]>()) };
+        for p in &pointers[..5] { unsafe { heap.deallocate(*p, layout) }; }
+        assert_eq!(heap.heap_bytes_in_use(), 0);
+        assert_eq!(heap.heap_peak_bytes(), 10 * 32);
+
+        heap.reset_peak();
+        assert_eq!(heap.heap_peak_bytes(), 0);
+        let tlab = heap.acquire_tlab(1000).unwrap();
+        assert_eq!(heap.heap_peak_bytes(), 1000);
+        drop(tlab);
+        assert_eq!(heap.heap_bytes_in_use(), 0);
+    }
+
+    #[test]
+    fn test_is_heap_pointer() {
+        let heap = Heap::new();
+        assert!(!heap.is_heap_pointer(0x1000));
+        let layout = Layout::new::<[u64; 4]>();
+        let p = heap.allocate(layout).unwrap() as usize;
+        let large = Layout::from_size_align(MegaBlock::SIZE * 2, 8).unwrap();
+        let q = heap.allocate(large).unwrap() as usize;
+        for &(start, size) in &[(p, layout.size()), (q, large.size())] {
+            assert!(heap.is_heap_pointer(start));
+            assert!(heap.is_heap_pointer(start + size - 1));
+        }
+        let heap = Heap::new();
+        heap.allocate(layout).unwrap();
+        let region = unsafe { &*heap.state.get() }.regions;
+        let (start, size) = (region as usize, unsafe { (*region).size });
+        assert!(heap.is_heap_pointer(start));
+        assert!(heap.is_heap_pointer(start + size - 1));
+        assert!(!heap.is_heap_pointer(start - 1));
+        assert!(!heap.is_heap_pointer(start + size));
+        let local = 0u64;
+        assert!(!heap.is_heap_pointer(&local as *const _ as usize));
+    }
+
+    #[test]
+    fn test_tlab() {
+        static NODE: ObjectDescriptor =
+            ObjectDescriptor { unpacked_field_count: 1, pointer_count: 2, field_map: None };
+        let size = NODE.total_size() * core::mem::size_of::<usize>();
+        let heap = Heap::new();
+        let mut starts: Vec<usize> = std::thread::scope(|scope| {
+            let threads: Vec<_> = (0..8).map(|tag| {
+                let heap = &heap;
+                scope.spawn(move || {
+                    let mut tlab = heap.acquire_tlab(1000).unwrap();
+                    (0..5000).map(|_| {
+                        let object = match tlab.allocate(&NODE) {
+                            Some(object) => object,
+                            None => { tlab.refill().unwrap(); tlab.allocate(&NODE).unwrap() }
+                        };
+                        object.unpacked[0] = tag;
+                        object.start_address().as_ptr::<u8>() as usize
+                    }).collect::<Vec<_>>()
+                })
+            }).collect();
+            threads.into_iter().flat_map(|t| t.join().unwrap()).collect()
+        });
+        starts.sort();
+        assert_eq!(starts.len(), 8 * 5000);
+        assert!(starts.windows(2).all(|w| w[0] + size <= w[1]));
+    }
+
+    #[cfg(feature = "allocator-api")]
+    #[test]
+    fn test_allocator_vec() {
+        let heap = Heap::new();
+        let mut v = allocator_api2::vec::Vec::new_in(&heap);
+        for i in 0..10000u64 { v.push(i); }
+        assert!(v.iter().copied().eq(0..10000));
+        let mut w = allocator_api2::vec::Vec::new_in(&heap);
+        w.extend_from_slice(&[1u8, 2, 3]);
+        v.truncate(10);
+        v.shrink_to_fit();
+        assert_eq!(v.len(), 10);
+        assert_eq!(w, [1, 2, 3]);
+    }
+}
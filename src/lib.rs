@@ -30,8 +30,12 @@ pub mod object;
 pub mod block;
 pub mod allocate;
 pub mod primitives;
+pub mod gc;
+pub mod heap;
 
-#[cfg(test)]
+extern crate alloc;
+
+#[cfg(any(test, feature = "std"))]
 extern crate std;
 
 #[cfg(test)]
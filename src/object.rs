@@ -18,6 +18,7 @@
 
 //! An object is effectively a collection of pointers.
 use super::common;
+use core::fmt;
 
 /// Object descriptors.
 ///
@@ -31,14 +32,64 @@ use super::common;
 ///
 /// All unpacked fields are gathered at the front of the object. Therefore, all the pointer fields
 /// are left at the back. This makes the object descriptor simple: 2 words determines the layout.
+///
+/// For languages interleaving unpacked fields and pointers, a `field_map` may give the kind of
+/// every field in memory order instead, see [`mapped`](#method.mapped). Objects with such a
+/// layout expose all their fields as raw words in `Object::unpacked`, and no `Object::pointers`;
+/// use [`Object::trace_pointers`](struct.Object.html#method.trace_pointers) to find the pointers.
+#[derive(Debug)]
 pub struct ObjectDescriptor {
     /// Number of unpacked fields in objects described by this descriptor.
     pub unpacked_field_count: usize,
     /// Number of boxed fields (i.e. pointers) in objects described by this descriptor.
     pub pointer_count: usize,
+    /// The kind of every field in memory order, or `None` for the packed layout.
+    pub field_map: Option<&'static [FieldKind]>,
+}
+
+/// Kinds of fields in an object.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum FieldKind {
+    /// An unpacked field, never traced.
+    Unpacked,
+    /// A boxed field, i.e. a pointer to another object.
+    Pointer,
 }
 
 impl ObjectDescriptor {
+    /// Describe objects whose fields are laid out as in `field_map`.
+    ///
+    /// ```
+    /// use memory_manager::object::{FieldKind, ObjectDescriptor};
+    /// use FieldKind::*;
+    /// static DESCRIPTOR: ObjectDescriptor =
+    ///     ObjectDescriptor::mapped(&[Pointer, Unpacked, Pointer]);
+    /// assert_eq!((DESCRIPTOR.unpacked_field_count, DESCRIPTOR.pointer_count), (1, 2));
+    /// ```
+    pub const fn mapped(field_map: &'static [FieldKind]) -> Self {
+        let mut pointer_count = 0;
+        let mut i = 0;
+        while i < field_map.len() {
+            if let FieldKind::Pointer = field_map[i] { pointer_count += 1; }
+            i += 1;
+        }
+        ObjectDescriptor {
+            unpacked_field_count: field_map.len() - pointer_count,
+            pointer_count,
+            field_map: Some(field_map),
+        }
+    }
+
+    /// The kind of the field at `index`, counting from the first field after the descriptor
+    /// pointer, in memory order.
+    pub fn field_kind(&self, index: usize) -> FieldKind {
+        match self.field_map {
+            Some(map) => map[index],
+            None if index < self.unpacked_field_count => FieldKind::Unpacked,
+            None => FieldKind::Pointer,
+        }
+    }
+
     /// The total size occupied by this kind of object.
     /// Always aligned to a `Word` (i.e. `usize`).
     ///
@@ -50,16 +101,69 @@ impl ObjectDescriptor {
     pub fn total_size(&self) -> usize {
         1 + self.unpacked_field_count + self.pointer_count
     }
+
+    /// The total size like [`total_size`](#method.total_size), or `None` on overflow.
+    ///
+    /// ```
+    /// use memory_manager::object::ObjectDescriptor;
+    /// let descriptor = ObjectDescriptor {
+    ///     unpacked_field_count: usize::MAX, pointer_count: 1, field_map: None };
+    /// assert_eq!(descriptor.checked_total_size(), None);
+    /// ```
+    pub fn checked_total_size(&self) -> Option<usize> {
+        self.unpacked_field_count.checked_add(self.pointer_count)?.checked_add(1)
+    }
+
+    /// Check that objects described by this descriptor fit in `max_words` words, and that their
+    /// size in bytes does not overflow.
+    ///
+    /// A `field_map`, if any, must agree with the field counts.
+    pub fn validate(&self, max_words: usize) -> bool {
+        let max_words = max_words.min(usize::MAX / core::mem::size_of::<usize>());
+        let fits = self.checked_total_size().map_or(false, |words| words <= max_words);
+        fits && self.field_map.map_or(true, |map| {
+            let pointers = map.iter().filter(|k| **k == FieldKind::Pointer).count();
+            map.len() == self.total_size() - 1 && pointers == self.pointer_count
+        })
+    }
+}
+
+/// Write barriers: invoked after every pointer store through
+/// [`Object::set_pointer`](struct.Object.html#method.set_pointer).
+///
+/// A barrier belongs to the heap or the collector it records stores for, so that every heap may
+/// have its own. Closures taking the object address and the field index are barriers as well.
+pub trait WriteBarrier {
+    /// Record a store into the pointer field at `index` of the object starting at `object`.
+    fn record_store(&self, object: common::Address, index: usize);
+}
+
+impl<F: Fn(common::Address, usize)> WriteBarrier for F {
+    fn record_store(&self, object: common::Address, index: usize) { self(object, index) }
 }
 
+/// The write barrier recording nothing, for heaps without a collector interested in stores.
+pub struct NoBarrier;
+
+impl WriteBarrier for NoBarrier {
+    fn record_store(&self, _: common::Address, _: usize) {}
+}
+
+/// Tag bit in the descriptor slot of an object, telling that it has been moved.
+///
+/// Descriptors are at least `Word`-aligned, so the lowest bit of a real descriptor pointer is
+/// always clear.
+const FORWARDED_TAG: usize = 1;
+
 /// An object, with a lifetime attached.
 pub struct Object<'a> {
     /// The pointer to `ObjectDescriptor`.
     pub descriptor: &'a mut &'a ObjectDescriptor,
-    /// The unpacked fields.
+    /// The unpacked fields, or all the fields for a descriptor with a `field_map`.
     pub unpacked: &'a mut [usize],
-    /// The boxed fields (i.e. pointers).
-    pub pointers: &'a mut [&'a Object<'a>],
+    /// The boxed fields (i.e. pointers), each the starting address of another object. Empty for a
+    /// descriptor with a `field_map`.
+    pub pointers: &'a mut [common::Address<'a>],
 }
 
 impl<'a> Object<'a> {
@@ -71,20 +175,443 @@ impl<'a> Object<'a> {
 
     /// The starting address of this object, i.e. where the pointer to
     /// [`ObjectDescriptor`](struct.ObjectDescriptor.html) is stored.
-    pub fn start_address(&mut self) -> common::Address<'a> {
-        common::Address::from(self.descriptor as *mut _)
+    pub fn start_address(&self) -> common::Address<'a> {
+        common::Address::from(&*self.descriptor as *const &ObjectDescriptor as *mut u8)
+    }
+
+    /// The new address of the object starting at `address` if it has been moved, i.e. its
+    /// descriptor slot holds a forwarding pointer, `None` otherwise.
+    ///
+    /// Unlike [`forwarded`](#method.forwarded), this only reads the descriptor slot, and works
+    /// when no `Object` can be materialized at `address` any more.
+    pub fn forwarding_address(address: common::Address<'a>) -> Option<common::Address<'a>> {
+        let slot = unsafe { *address.as_ptr::<usize>() };
+        if slot & FORWARDED_TAG == 0 { return None; }
+        Some(common::Address::from((slot & !FORWARDED_TAG) as *mut u8))
+    }
+
+    /// The new address of this object if it has been moved, `None` otherwise.
+    /// See also [`set_forwarded`](#method.set_forwarded).
+    pub fn forwarded(&self) -> Option<common::Address<'a>> {
+        Object::forwarding_address(self.start_address())
+    }
+
+    /// Replace the descriptor slot of this object with a forwarding pointer to `new_addr`, a
+    /// tagged pointer with the lowest bit set.
+    ///
+    /// Afterwards, the descriptor must not be read through this object any more, and
+    /// `Object::from` will panic on the old address. Use [`forwarded`](#method.forwarded) to
+    /// find the new location instead.
+    pub fn set_forwarded(&mut self, new_addr: common::Address) {
+        let new_addr = new_addr.as_ptr::<usize>() as usize;
+        let slot = self.descriptor as *mut &ObjectDescriptor as *mut usize;
+        unsafe { *slot = new_addr | FORWARDED_TAG; }
+    }
+
+    /// The address stored in the pointer field at `index`, null for a fresh object.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index` is not less than the `pointer_count` of the descriptor.
+    pub fn pointer(&self, index: usize) -> common::Address<'a> {
+        let count = self.descriptor.pointer_count;
+        assert!(index < count, "pointer field index {} out of range: object has {} pointer fields",
+                index, count);
+        self.pointer_fields().nth(index).unwrap()
+    }
+
+    /// Store `target` into the pointer field at `index`, then invoke `barrier`, see
+    /// [`WriteBarrier`](trait.WriteBarrier.html).
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index` is not less than the `pointer_count` of the descriptor.
+    pub fn set_pointer(
+        &mut self, index: usize, target: &Object<'a>, barrier: &(impl WriteBarrier + ?Sized)) {
+        let count = self.descriptor.pointer_count;
+        assert!(index < count, "pointer field index {} out of range: object has {} pointer fields",
+                index, count);
+        *self.pointer_slots_mut().nth(index).unwrap() = target.start_address();
+        barrier.record_store(self.start_address(), index);
+    }
+
+    /// The objects referenced by the pointer fields of this object, in field order. Null pointer
+    /// fields (e.g. of a freshly allocated object) are skipped.
+    pub fn trace_pointers(&self) -> impl Iterator<Item = Object<'a>> + '_ {
+        self.pointer_fields().filter(|p| !p.is_null()).map(Object::from)
+    }
+
+    /// The non-null pointer fields of this object, in field order, for relocation.
+    ///
+    /// Stores through the returned references do NOT invoke the write barrier.
+    pub fn trace_pointers_mut(&mut self) -> impl Iterator<Item = &mut common::Address<'a>> {
+        self.pointer_slots_mut().filter(|p| !p.is_null())
+    }
+
+    /// The values of all the pointer fields, according to the layout of the descriptor.
+    fn pointer_fields(&self) -> impl Iterator<Item = common::Address<'a>> + '_ {
+        let descriptor: &ObjectDescriptor = self.descriptor;
+        let mapped = self.unpacked.iter().enumerate()
+            .filter(move |(i, _)| descriptor.field_kind(*i) == FieldKind::Pointer)
+            .map(|(_, field)| unsafe { *(field as *const usize as *const common::Address<'a>) });
+        mapped.chain(self.pointers.iter().copied())
+    }
+
+    /// All the pointer fields, according to the layout of the descriptor.
+    ///
+    /// The slots are borrowed from the field slices, rather than derived from the start address,
+    /// so that writes through them stay within the unique borrows of this object.
+    fn pointer_slots_mut(&mut self) -> impl Iterator<Item = &mut common::Address<'a>> {
+        let descriptor: &ObjectDescriptor = self.descriptor;
+        let mapped = self.unpacked.iter_mut().enumerate()
+            .filter(move |(i, _)| descriptor.field_kind(*i) == FieldKind::Pointer)
+            .map(|(_, field)| unsafe { &mut *(field as *mut usize as *mut common::Address<'a>) });
+        mapped.chain(self.pointers.iter_mut())
+    }
+
+    /// All the word-sized slots of this object in memory order, `total_size` words in all: the
+    /// descriptor pointer, then the unpacked fields, then the pointer fields.
+    ///
+    /// See also [`word_slots_mut`](#method.word_slots_mut).
+    pub fn all_word_slots_mut(&mut self) -> impl Iterator<Item = &mut usize> {
+        self.word_slots_mut(true)
+    }
+
+    /// The word-sized slots of this object, optionally including the descriptor pointer.
+    ///
+    /// Most runtimes keep their descriptors `'static`, then the descriptor slot must never be
+    /// rewritten by a relocator. Only pass `include_descriptor = true` when descriptors live in
+    /// the moving heap themselves.
+    pub fn word_slots_mut(&mut self, include_descriptor: bool) -> impl Iterator<Item = &mut usize> {
+        let descriptor = &mut *self.descriptor as *mut &ObjectDescriptor as *mut usize;
+        let pointers = self.pointers.as_mut_ptr() as *mut usize;
+        let pointers = unsafe { core::slice::from_raw_parts_mut(pointers, self.pointers.len()) };
+        core::iter::once(unsafe { &mut *descriptor })
+            .chain(self.unpacked.iter_mut())
+            .chain(pointers.iter_mut())
+            .skip(if include_descriptor { 0 } else { 1 })
+    }
+
+    /// Copy this object verbatim to `dest`, then advance `dest` past the copy.
+    ///
+    /// All `total_size` words are copied as is, including the descriptor pointer and the pointer
+    /// fields, so the copy references the same objects as the original.
+    ///
+    /// # Safety
+    ///
+    /// `dest` must be valid for writing `total_size` words, and must not overlap this object (see
+    /// [`move_to`](#method.move_to) for overlapping moves).
+    pub unsafe fn copy_to(&self, dest: &mut common::Address<'a>) -> Object<'a> {
+        let words = self.total_size();
+        let new_addr = *dest;
+        let src = self.start_address().as_ptr::<usize>();
+        core::ptr::copy_nonoverlapping(src, new_addr.as_ptr::<usize>(), words);
+        *dest = new_addr.offset((words * core::mem::size_of::<usize>()) as isize);
+        Object::from(new_addr)
+    }
+
+    /// Move this object to `dest`, then advance `dest` past the moved object.
+    ///
+    /// The source and destination regions may overlap (memmove semantics), as is the case when
+    /// sliding objects down during in-place compaction.
+    ///
+    /// # Safety
+    ///
+    /// `dest` must be valid for writing `total_size` words. The old object must not be used
+    /// after the move, use the returned `Object` instead.
+    pub unsafe fn move_to(&mut self, dest: &mut common::Address<'a>) -> Object<'a> {
+        let words = self.total_size();
+        let new_addr = *dest;
+        let src = self.start_address().as_ptr::<usize>();
+        core::ptr::copy(src, new_addr.as_ptr::<usize>(), words);
+        *dest = new_addr.offset((words * core::mem::size_of::<usize>()) as isize);
+        Object::from(new_addr)
+    }
+}
+
+/// The unpacked fields of an object, in memory order, whatever its layout.
+struct UnpackedFields<'b, 'a>(&'b Object<'a>);
+
+impl<'b, 'a> fmt::Debug for UnpackedFields<'b, 'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let descriptor: &ObjectDescriptor = self.0.descriptor;
+        f.debug_list().entries(self.0.unpacked.iter().enumerate()
+            .filter(|(i, _)| descriptor.field_kind(*i) == FieldKind::Unpacked)
+            .map(|(_, field)| field)).finish()
+    }
+}
+
+/// Pointer fields are rendered as the addresses they point to, without following them.
+struct PointerTargets<'b, 'a>(&'b Object<'a>);
+
+impl<'b, 'a> fmt::Debug for PointerTargets<'b, 'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.0.pointer_fields()).finish()
     }
 }
 
+/// Objects are rendered according to their descriptors, pointer fields are never followed, so
+/// cyclic object graphs are fine. Fields are grouped by kind in both layouts.
+///
+/// ```
+/// use memory_manager::common::Address;
+/// use memory_manager::object::{FieldKind::*, Object, ObjectDescriptor};
+/// static DESCRIPTOR: ObjectDescriptor =
+///     ObjectDescriptor { unpacked_field_count: 1, pointer_count: 0, field_map: None };
+/// let mut mem = [&DESCRIPTOR as *const _ as usize, 42];
+/// let object = Object::from(Address::from(mem.as_mut_ptr()));
+/// assert!(format!("{:?}", object).contains("unpacked: [42]"));
+///
+/// static MAPPED: ObjectDescriptor = ObjectDescriptor::mapped(&[Pointer, Unpacked]);
+/// let target = mem.as_mut_ptr() as usize;
+/// let mut mapped = [&MAPPED as *const _ as usize, target, 7];
+/// let object = Object::from(Address::from(mapped.as_mut_ptr()));
+/// let rendered = format!("{:?}", object);
+/// assert!(rendered.contains("unpacked: [7]"));
+/// assert!(rendered.contains(&format!("pointers: [Address({:#x})]", target)));
+/// ```
+impl<'a> fmt::Debug for Object<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let address = common::Address::from(&*self.descriptor as *const _ as *mut ());
+        f.debug_struct("Object")
+            .field("address", &address)
+            .field("descriptor", *self.descriptor)
+            .field("unpacked", &UnpackedFields(self))
+            .field("pointers", &PointerTargets(self))
+            .finish()
+    }
+}
+
+/// # Panics
+///
+/// Panics if the object at `address` has been forwarded, see
+/// [`Object::set_forwarded`](struct.Object.html#method.set_forwarded).
 impl<'a> From<common::Address<'a>> for Object<'a> {
     fn from(mut address: common::Address<'a>) -> Self {
+        assert!(Object::forwarding_address(address).is_none(),
+                "object at {:?} has been forwarded", address);
         unsafe {
             let descriptor = common::consume_as_ref::<&'a ObjectDescriptor>(&mut address);
-            let unpacked = common::consume_as_slice::<usize>(
-                &mut address, descriptor.unpacked_field_count);
-            let pointers = common::consume_as_slice::<&'a Object>(
-                &mut address, descriptor.pointer_count);
+            let (unpacked_count, pointer_count) = match descriptor.field_map {
+                None => (descriptor.unpacked_field_count, descriptor.pointer_count),
+                Some(_) => (descriptor.total_size() - 1, 0),
+            };
+            let unpacked = common::consume_as_slice::<usize>(&mut address, unpacked_count);
+            let pointers = common::consume_as_slice::<common::Address<'a>>(
+                &mut address, pointer_count);
             Object { descriptor, unpacked, pointers }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::{Object, ObjectDescriptor, NoBarrier};
+    use super::common::Address;
+    use core::cell::Cell;
+    use std::vec::Vec;
+
+    static DESCRIPTOR: ObjectDescriptor =
+        ObjectDescriptor { unpacked_field_count: 2, pointer_count: 1, field_map: None };
+
+    #[test]
+    fn test_checked_total_size() {
+        assert_eq!(DESCRIPTOR.checked_total_size(), Some(4));
+        assert!(DESCRIPTOR.validate(4));
+        assert!(!DESCRIPTOR.validate(3));
+        let huge = ObjectDescriptor {
+            unpacked_field_count: usize::MAX, pointer_count: usize::MAX, field_map: None };
+        assert_eq!(huge.checked_total_size(), None);
+        assert!(!huge.validate(usize::MAX));
+    }
+
+    #[test]
+    fn test_trace_pointers() {
+        static PAIR: ObjectDescriptor =
+            ObjectDescriptor { unpacked_field_count: 1, pointer_count: 2, field_map: None };
+        let mut a = [&DESCRIPTOR as *const _ as usize, 1, 0, 0];
+        let mut b = [&DESCRIPTOR as *const _ as usize, 2, 0, 0];
+        let mut root = [&PAIR as *const _ as usize, 0, 0, 0];
+        let mut root = Object::from(Address::from(root.as_mut_ptr()));
+        assert_eq!(root.trace_pointers().count(), 0);
+        root.pointers[0] = Address::from(a.as_mut_ptr());
+        root.pointers[1] = Address::from(b.as_mut_ptr());
+        let children: Vec<_> = root.trace_pointers().map(|o| o.unpacked[0]).collect();
+        assert_eq!(children, [1, 2]);
+        let b_address = Address::from(b.as_mut_ptr());
+        for pointer in root.trace_pointers_mut() { *pointer = b_address; }
+        let children: Vec<_> = root.trace_pointers().map(|o| o.unpacked[0]).collect();
+        assert_eq!(children, [2, 2]);
+    }
+
+    #[test]
+    fn test_field_map() {
+        use super::FieldKind::{Pointer, Unpacked};
+        static MAPPED: ObjectDescriptor = ObjectDescriptor::mapped(&[Pointer, Unpacked, Pointer]);
+        assert!(MAPPED.validate(4));
+        let mut a = [&DESCRIPTOR as *const _ as usize, 1, 0, 0];
+        let mut b = [&DESCRIPTOR as *const _ as usize, 2, 0, 0];
+        let mut mem = [&MAPPED as *const _ as usize, 0, 42, 0];
+        let mut object = Object::from(Address::from(mem.as_mut_ptr()));
+        assert_eq!((object.unpacked.len(), object.pointers.len()), (3, 0));
+        assert_eq!(object.trace_pointers().count(), 0);
+        object.set_pointer(0, &Object::from(Address::from(a.as_mut_ptr())), &NoBarrier);
+        object.set_pointer(1, &Object::from(Address::from(b.as_mut_ptr())), &NoBarrier);
+        let children: Vec<_> = object.trace_pointers().map(|o| o.unpacked[0]).collect();
+        assert_eq!(children, [1, 2]);
+        assert_eq!(object.trace_pointers_mut().count(), 2);
+        assert_eq!(mem[2], 42);
+        assert_eq!(mem[1], a.as_ptr() as usize);
+        assert_eq!(mem[3], b.as_ptr() as usize);
+    }
+
+    #[test]
+    fn test_word_slots() {
+        let mut mem = [0usize; 4];
+        mem[0] = &DESCRIPTOR as *const _ as usize;
+        let mut object = Object::from(Address::from(mem.as_mut_ptr()));
+        assert_eq!(object.all_word_slots_mut().count(), DESCRIPTOR.total_size());
+        assert_eq!(object.word_slots_mut(false).count(), DESCRIPTOR.total_size() - 1);
+        for (i, slot) in object.word_slots_mut(false).enumerate() { *slot = i + 1; }
+        assert_eq!(mem, [&DESCRIPTOR as *const _ as usize, 1, 2, 3]);
+    }
+
+    #[test]
+    fn test_copy_to() {
+        let mut target = [&DESCRIPTOR as *const _ as usize, 0, 0, 0];
+        let mut mem = [&DESCRIPTOR as *const _ as usize, 1, 2, target.as_mut_ptr() as usize];
+        let mut copy = [0usize; 5];
+        let object = Object::from(Address::from(mem.as_mut_ptr()));
+        let mut dest = Address::from(copy.as_mut_ptr());
+        let copied = unsafe { object.copy_to(&mut dest) };
+        assert_eq!(copied.unpacked, &[1, 2]);
+        assert_eq!(copied.start_address(), Address::from(copy.as_mut_ptr()));
+        assert_eq!(dest, Address::from(unsafe { copy.as_mut_ptr().add(4) }));
+        assert_eq!(copy[..4], mem);
+        assert_eq!(copy[4], 0);
+    }
+
+    #[test]
+    fn test_forwarding() {
+        let mut mem = [&DESCRIPTOR as *const _ as usize, 1, 2, 0];
+        let mut new = [0usize; 4];
+        let mut object = Object::from(Address::from(mem.as_mut_ptr()));
+        assert_eq!(object.forwarded(), None);
+        let new_addr = Address::from(new.as_mut_ptr());
+        object.set_forwarded(new_addr);
+        assert_eq!(object.forwarded(), Some(new_addr));
+        assert_eq!(Object::forwarding_address(Address::from(mem.as_mut_ptr())), Some(new_addr));
+        assert_eq!(mem[1..], [1, 2, 0]);
+    }
+
+    /// Write `descriptor` at the start of `mem` as a reference rather than an integer, so that
+    /// the object keeps its provenance under Miri.
+    fn object_in<'a>(mem: &'a mut [usize], descriptor: &'static ObjectDescriptor) -> Object<'a> {
+        let start = mem.as_mut_ptr();
+        unsafe { (start as *mut &ObjectDescriptor).write(descriptor) };
+        Object::from(Address::from(start))
+    }
+
+    /// Also meant to run under Miri, checking the stores against the aliasing rules.
+    #[test]
+    fn test_slots_aliasing() {
+        use super::FieldKind::*;
+        static PACKED: ObjectDescriptor =
+            ObjectDescriptor { unpacked_field_count: 1, pointer_count: 2, field_map: None };
+        static MAPPED: ObjectDescriptor = ObjectDescriptor::mapped(&[Pointer, Unpacked, Pointer]);
+        let mut mems = [[0usize; 4]; 2];
+        let mut target = [0usize; 4];
+        let target = object_in(&mut target, &DESCRIPTOR);
+        for (descriptor, mem) in [&PACKED, &MAPPED].iter().zip(mems.iter_mut()) {
+            let mut object = object_in(mem, descriptor);
+            object.set_pointer(0, &target, &NoBarrier);
+            assert_eq!(object.pointer(0), target.start_address());
+            assert_eq!(object.trace_pointers_mut().count(), 1);
+            object.set_pointer(1, &target, &NoBarrier);
+            assert_eq!(object.trace_pointers_mut().count(), 2);
+            for pointer in object.trace_pointers_mut() { *pointer = Address::from(8 as *mut u8); }
+            for slot in object.word_slots_mut(false) { *slot += 1; }
+            assert_eq!(object.all_word_slots_mut().count(), 4);
+            assert_eq!(object.pointer(1), Address::from(9 as *mut u8));
+        }
+    }
+
+    /// Also meant to run under Miri, checking the store against the aliasing rules.
+    #[test]
+    fn test_forwarding_aliasing() {
+        let mut mem = [0usize; 4];
+        let mut new = [0usize; 4];
+        let new_addr = Address::from(new.as_mut_ptr());
+        let mut object = object_in(&mut mem, &DESCRIPTOR);
+        object.unpacked[0] = 1;
+        object.set_forwarded(new_addr);
+        assert_eq!(object.forwarded(), Some(new_addr));
+        assert_eq!(Object::forwarding_address(Address::from(mem.as_mut_ptr())), Some(new_addr));
+    }
+
+    #[test]
+    #[should_panic(expected = "has been forwarded")]
+    fn test_forwarded_from() {
+        let mut mem = [&DESCRIPTOR as *const _ as usize, 1, 2, 0];
+        let mut new = [0usize; 4];
+        let address = Address::from(mem.as_mut_ptr());
+        Object::from(address).set_forwarded(Address::from(new.as_mut_ptr()));
+        let _ = Object::from(address);
+    }
+
+    #[test]
+    fn test_move_overlapping() {
+        let mut mem = [0usize; 5];
+        mem[1] = &DESCRIPTOR as *const _ as usize;
+        mem[2..].copy_from_slice(&[1, 2, 3]);
+        let mut object = Object::from(Address::from(unsafe { mem.as_mut_ptr().add(1) }));
+        let mut dest = Address::from(mem.as_mut_ptr());
+        let moved = unsafe { object.move_to(&mut dest) };
+        assert_eq!(moved.unpacked, &[1, 2]);
+        assert_eq!(dest, Address::from(unsafe { mem.as_mut_ptr().add(4) }));
+        assert_eq!(mem[..4], [&DESCRIPTOR as *const _ as usize, 1, 2, 3]);
+    }
+
+    #[test]
+    fn test_debug() {
+        let mut mem = [&DESCRIPTOR as *const _ as usize, 7, 42, 0x1000];
+        let object = Object::from(Address::from(mem.as_mut_ptr()));
+        let s = std::format!("{:?}", object);
+        assert!(s.contains("unpacked_field_count: 2, pointer_count: 1"));
+        assert!(s.contains("unpacked: [7, 42]"));
+        assert!(s.contains("pointers: [Address(0x1000)]"));
+    }
+
+    #[test]
+    fn test_set_pointer() {
+        let mut mem = [&DESCRIPTOR as *const _ as usize, 7, 42, 0];
+        let mut target_mem = [&DESCRIPTOR as *const _ as usize, 0, 0, 0];
+        let mut object = Object::from(Address::from(mem.as_mut_ptr()));
+        let target = Object::from(Address::from(target_mem.as_mut_ptr()));
+        let address = object.start_address();
+        let stores = Cell::new(0);
+        let barrier = |stored: Address, index: usize| {
+            assert_eq!((stored, index), (address, 0));
+            stores.set(stores.get() + 1);
+        };
+        object.set_pointer(0, &target, &barrier);
+        assert_eq!(object.pointers[0], target.start_address());
+        assert_eq!(stores.get(), 1);
+        object.set_pointer(0, &target, &barrier);
+        assert_eq!(stores.get(), 2);
+        object.set_pointer(0, &target, &NoBarrier);
+        assert_eq!(stores.get(), 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "pointer field index 1 out of range")]
+    fn test_set_pointer_out_of_range() {
+        let mut mem = [&DESCRIPTOR as *const _ as usize, 7, 42, 0];
+        let mut target_mem = [&DESCRIPTOR as *const _ as usize, 0, 0, 0];
+        let mut object = Object::from(Address::from(mem.as_mut_ptr()));
+        let target = Object::from(Address::from(target_mem.as_mut_ptr()));
+        object.set_pointer(1, &target, &NoBarrier);
+    }
+}
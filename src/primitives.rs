@@ -19,6 +19,7 @@
 //! Memory allocation primitives.
 mod unix;
 mod windows;
+pub(crate) mod registry;
 
 /// Common errors from `mmap`.
 ///
@@ -34,14 +35,27 @@ pub enum MMapError {
     InvalidArguments,
     /// (UNIX-specific) Too much memory has been locked.
     TryAgain,
-    /// (UNIX-specific) Any of the following applies:
+    /// Out of memory. On UNIX-like systems, any of the following applies:
     /// - No memory available, or
     /// - Maximum number of mappings exceeded, or
     /// - `RLIMIT_DATA` exceeded
+    ///
+    /// On Windows, the system is out of memory, or the commit limit has been reached.
     NoMemory,
     /// Number of pages overflows `unsigned long`.
     /// (32-bit platform only, UNIX-specific)
     LengthOverflow,
+    /// The chunk to free was never allocated, or has already been freed. Only reported with the
+    /// `chunk-registry` feature.
+    NotAllocated,
+    /// Locking the pages would exceed the limit of locked memory (`RLIMIT_MEMLOCK` on UNIX, the
+    /// working set quota on Windows), or the process is not privileged to lock memory at all.
+    LockLimit,
+    /// (UNIX-specific) The requested protection is not allowed, e.g. write access to a file
+    /// mapping opened read-only, or execute access to a file on a `noexec` file system.
+    AccessDenied,
+    /// (UNIX-specific) The file descriptor to map is not valid.
+    BadFile,
     /// Errors not recognized, with the raw error code on the host system.
     UnknownError(u32),
     /// No error at all, NOT EXPECTED.
@@ -49,9 +63,98 @@ pub enum MMapError {
     NoError,
 }
 
+impl core::fmt::Display for MMapError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            MMapError::InvalidArguments => write!(f, "invalid arguments"),
+            MMapError::TryAgain => write!(f, "resource temporarily unavailable, try again"),
+            MMapError::NoMemory => write!(f, "out of memory"),
+            MMapError::LengthOverflow => write!(f, "number of pages overflows"),
+            MMapError::NotAllocated => write!(f, "chunk not allocated or already freed"),
+            MMapError::LockLimit => write!(f, "locked memory limit exceeded"),
+            MMapError::AccessDenied => write!(f, "access denied"),
+            MMapError::BadFile => write!(f, "bad file descriptor"),
+            MMapError::UnknownError(code) => write!(f, "unknown error (code {})", code),
+            MMapError::NoError => write!(f, "no error reported"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for MMapError {}
+
+#[cfg(feature = "std")]
+impl From<MMapError> for std::io::Error {
+    fn from(e: MMapError) -> Self {
+        use std::io::{Error, ErrorKind};
+        match e {
+            MMapError::InvalidArguments => Error::new(ErrorKind::InvalidInput, e),
+            MMapError::TryAgain => Error::new(ErrorKind::WouldBlock, e),
+            MMapError::NoMemory => Error::new(ErrorKind::OutOfMemory, e),
+            MMapError::LengthOverflow => Error::new(ErrorKind::InvalidInput, e),
+            MMapError::NotAllocated => Error::new(ErrorKind::InvalidInput, e),
+            MMapError::LockLimit => Error::new(ErrorKind::PermissionDenied, e),
+            MMapError::AccessDenied => Error::new(ErrorKind::PermissionDenied, e),
+            MMapError::BadFile => Error::new(ErrorKind::InvalidInput, e),
+            MMapError::UnknownError(code) => Error::from_raw_os_error(code as i32),
+            MMapError::NoError => Error::new(ErrorKind::Other, e),
+        }
+    }
+}
+
 /// Memory allocation results.
 pub type Result<T> = core::result::Result<T, MMapError>;
 
+/// Mapping flags for [`aligned_allocate_chunk`](fn.aligned_allocate_chunk.html).
+///
+/// These are platform-neutral, and translated to the host flags on allocation:
+///
+/// - On UNIX-like systems, they map to the `MAP_*` flags of `mmap`.
+/// - On Windows, `NoReserve` only reserves the address space without committing memory (the
+///   memory is inaccessible until committed, see [`commit`](fn.commit.html)), `Private` and
+///   `Anonymous` are implied, and `Shared` fails with `InvalidArguments`, since anonymous
+///   memory cannot be shared through `VirtualAlloc2`.
+///
+/// `Prefault` is `MAP_POPULATE` on Linux; elsewhere, one byte per page is touched right after the
+/// allocation instead.
+#[repr(u32)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq, BitFlags)]
+pub enum MapFlags {
+    /// Share this mapping (updates visible to other processes).
+    Shared = 0x01,
+    /// Private copy-on-write mapping.
+    Private = 0x02,
+    /// The mapping is not backed by any file; its contents are initialized to zero.
+    Anonymous = 0x04,
+    /// Do not reserve swap space (UNIX) or commit memory (Windows) for this mapping.
+    NoReserve = 0x08,
+    /// (Linux-specific) Allocate the mapping using huge pages.
+    #[cfg(target_os = "linux")]
+    HugeTLB = 0x10,
+    /// Fault all pages in on allocation, so that the first accesses do not stall. Ignored with
+    /// `NoReserve` or `Protection::NONE`.
+    Prefault = 0x20,
+}
+
+/// Access-pattern hints for [`advise`](fn.advise.html).
+///
+/// On Windows only `DontNeed` has an effect, the other hints are accepted and ignored.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Advice {
+    /// No special treatment, the default.
+    Normal,
+    /// Pages will be accessed in sequential order, read ahead aggressively.
+    Sequential,
+    /// Pages will be accessed in random order, read ahead is pointless.
+    Random,
+    /// Pages will be accessed soon, read them in ahead of time.
+    WillNeed,
+    /// Pages will not be accessed soon, the system may reclaim them. The mapping is kept: on
+    /// UNIX-like systems, later accesses see zero pages (for private anonymous mappings); on
+    /// Windows, the contents are undefined until rewritten.
+    DontNeed,
+}
+
 #[cfg(unix)]
 use unix as detail;
 #[cfg(windows)]
@@ -62,15 +165,85 @@ pub use detail::Protection;
 pub use detail::get_page_size;
 pub use detail::get_minimum_alignment;
 
+pub use detail::allocate_chunk;
 pub use detail::aligned_allocate_chunk;
 pub use detail::deallocate_chunk;
+pub use detail::allocate_chunk_at;
+pub use detail::{aligned_reserve_chunk, commit, decommit};
+pub use detail::protect;
+pub use detail::advise;
+pub use detail::{lock, unlock};
+pub use detail::resident_pages;
+
+#[cfg(unix)]
+pub use detail::{map_file, aligned_map_file, sync};
+#[cfg(target_os = "linux")]
+pub use detail::{HugePageSize, allocate_huge_chunk, aligned_allocate_huge_chunk};
+#[cfg(target_os = "linux")]
+pub use detail::remap;
+#[cfg(any(windows, target_os = "linux"))]
+pub use detail::query;
+
+use core::ffi::c_void;
+use enumflags2::BitFlags;
+
+/// Allocate an aligned memory chunk like [`aligned_allocate_chunk`], retrying on `TryAgain`.
+///
+/// The allocation is tried at most `attempts` times (but at least once), with a short spin
+/// between tries. Errors other than `TryAgain` fail immediately. If all attempts fail, the last
+/// error is returned.
+///
+/// # Safety
+///
+/// See [`aligned_allocate_chunk`].
+///
+/// [`aligned_allocate_chunk`]: fn.aligned_allocate_chunk.html
+pub unsafe fn aligned_allocate_chunk_retry(
+    alignment: usize, size: usize, protection: BitFlags<Protection>, flags: BitFlags<MapFlags>,
+    attempts: u32) -> Result<*mut c_void> {
+    retry(attempts, || aligned_allocate_chunk(alignment, size, protection, flags))
+}
+
+/// Touch one byte per page in `[addr, addr + size)`, so that the pages are resident. Pages are
+/// written back with their own contents if writable, only read if readable, and left alone if
+/// not accessible at all.
+#[cfg_attr(target_os = "linux", allow(dead_code))]
+unsafe fn touch_pages(
+    addr: *mut c_void, size: usize, page_size: usize, protection: BitFlags<Protection>) {
+    let addr = addr as *mut u8;
+    for offset in (0..size).step_by(page_size) {
+        let byte = addr.add(offset);
+        if protection.contains(Protection::Write) {
+            byte.write_volatile(byte.read_volatile());
+        } else if protection.contains(Protection::Read) {
+            byte.read_volatile();
+        }
+    }
+}
+
+/// Upper bound of the spin between two attempts, as a power of 2.
+const MAX_BACKOFF_SHIFT: u32 = 10;
+
+fn retry<T>(attempts: u32, mut allocate: impl FnMut() -> Result<T>) -> Result<T> {
+    let mut result = allocate();
+    for i in 1..attempts {
+        if !matches!(result, Err(MMapError::TryAgain)) { break; }
+        for _ in 0..1u32 << i.min(MAX_BACKOFF_SHIFT) { core::hint::spin_loop(); }
+        result = allocate();
+    }
+    result
+}
 
 #[cfg(test)]
 mod tests {
     extern crate std;
 
-    use super::Protection;
+    use super::{MMapError, Protection, Advice};
+    use enumflags2::BitFlags;
+    use super::retry;
+    use super::advise;
     use super::get_minimum_alignment;
+    use super::allocate_chunk;
     use super::aligned_allocate_chunk;
     use super::deallocate_chunk;
 
@@ -80,9 +253,125 @@ mod tests {
         let alignment = page_size * 2;
         let size = alignment * 3;
         let addr = unsafe {
-            aligned_allocate_chunk(alignment, size, Protection::NONE).unwrap()
+            aligned_allocate_chunk(alignment, size, Protection::NONE, BitFlags::empty()).unwrap()
         };
         assert_eq!(addr as usize % alignment, 0);
         unsafe { deallocate_chunk(addr, size).unwrap() }
     }
+
+    #[test]
+    fn test_allocate_chunk() {
+        let size = get_minimum_alignment().unwrap() * 3;
+        let protection = Protection::Read | Protection::Write;
+        let addr = unsafe { allocate_chunk(size, protection, BitFlags::empty()).unwrap() };
+        assert_eq!(addr as usize % get_minimum_alignment().unwrap(), 0);
+        let bytes = unsafe { core::slice::from_raw_parts_mut(addr as *mut u8, size) };
+        bytes[size - 1] = 42;
+        assert_eq!(bytes[size - 1], 42);
+        unsafe { deallocate_chunk(addr, size).unwrap() }
+        assert_eq!(unsafe { allocate_chunk(0, protection, BitFlags::empty()) },
+                   Err(MMapError::InvalidArguments));
+    }
+
+    #[test]
+    fn test_advise() {
+        let size = get_minimum_alignment().unwrap();
+        let protection = Protection::Read | Protection::Write;
+        let addr = unsafe {
+            aligned_allocate_chunk(size, size, protection, BitFlags::empty()).unwrap()
+        };
+        unsafe { (addr as *mut u8).write(42) };
+        assert_eq!(unsafe { advise(addr, size, Advice::DontNeed) }, Ok(()));
+        assert_eq!(unsafe { advise(addr, size, Advice::Normal) }, Ok(()));
+        #[cfg(unix)]
+        assert_eq!(unsafe { (addr as *mut u8).read() }, 0);
+        unsafe { deallocate_chunk(addr, size).unwrap() }
+    }
+
+    #[test]
+    fn test_native_bits() {
+        let bits = Protection::native_bits(Protection::Read | Protection::Write);
+        #[cfg(unix)]
+        assert_eq!(bits, (libc::PROT_READ | libc::PROT_WRITE) as u32);
+        #[cfg(windows)]
+        assert_eq!(bits, winapi::um::winnt::PAGE_READWRITE);
+    }
+
+    #[test]
+    fn test_protection_none() {
+        assert_eq!(Protection::NONE.bits(), 0);
+        assert_eq!(Protection::NONE, BitFlags::empty());
+        #[cfg(unix)]
+        assert_eq!(Protection::native_bits(Protection::NONE), libc::PROT_NONE as u32);
+    }
+
+    #[test]
+    fn test_display() {
+        use std::string::ToString;
+        assert_eq!(MMapError::InvalidArguments.to_string(), "invalid arguments");
+        assert_eq!(MMapError::TryAgain.to_string(), "resource temporarily unavailable, try again");
+        assert_eq!(MMapError::NoMemory.to_string(), "out of memory");
+        assert_eq!(MMapError::LengthOverflow.to_string(), "number of pages overflows");
+        assert_eq!(MMapError::NotAllocated.to_string(), "chunk not allocated or already freed");
+        assert_eq!(MMapError::LockLimit.to_string(), "locked memory limit exceeded");
+        assert_eq!(MMapError::AccessDenied.to_string(), "access denied");
+        assert_eq!(MMapError::BadFile.to_string(), "bad file descriptor");
+        assert_eq!(MMapError::UnknownError(42).to_string(), "unknown error (code 42)");
+        assert_eq!(MMapError::NoError.to_string(), "no error reported");
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_error() {
+        use std::boxed::Box;
+        use std::string::ToString;
+        let error: Box<dyn std::error::Error> = MMapError::NoMemory.into();
+        assert_eq!(error.to_string(), "out of memory");
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_io_error() {
+        use std::io::{Error, ErrorKind};
+        let kind = |e: MMapError| Error::from(e).kind();
+        assert_eq!(kind(MMapError::InvalidArguments), ErrorKind::InvalidInput);
+        assert_eq!(kind(MMapError::TryAgain), ErrorKind::WouldBlock);
+        assert_eq!(kind(MMapError::NoMemory), ErrorKind::OutOfMemory);
+        assert_eq!(kind(MMapError::LengthOverflow), ErrorKind::InvalidInput);
+        assert_eq!(kind(MMapError::NotAllocated), ErrorKind::InvalidInput);
+        assert_eq!(kind(MMapError::LockLimit), ErrorKind::PermissionDenied);
+        assert_eq!(kind(MMapError::AccessDenied), ErrorKind::PermissionDenied);
+        assert_eq!(kind(MMapError::BadFile), ErrorKind::InvalidInput);
+        assert_eq!(kind(MMapError::NoError), ErrorKind::Other);
+        assert_eq!(Error::from(MMapError::UnknownError(22)).raw_os_error(), Some(22));
+    }
+
+    /// Double frees are tested on the registry itself, freeing a real mapping twice would unmap
+    /// whatever another test mapped at the same address in between.
+    #[cfg(feature = "chunk-registry")]
+    #[test]
+    fn test_free_never_allocated() {
+        let size = get_minimum_alignment().unwrap();
+        let mut local = 0u8;
+        let never = &mut local as *mut u8 as _;
+        assert_eq!(unsafe { deallocate_chunk(never, size) }, Err(MMapError::NotAllocated));
+    }
+
+    #[test]
+    fn test_retry() {
+        let mut calls = 0;
+        let result = retry(5, || {
+            calls += 1;
+            if calls <= 2 { Err(MMapError::TryAgain) } else { Ok(calls) }
+        });
+        assert_eq!(result, Ok(3));
+
+        let mut calls = 0;
+        let result = retry(5, || { calls += 1; Err::<(), _>(MMapError::NoMemory) });
+        assert_eq!((result, calls), (Err(MMapError::NoMemory), 1));
+
+        let mut calls = 0;
+        let result = retry(3, || { calls += 1; Err::<(), _>(MMapError::TryAgain) });
+        assert_eq!((result, calls), (Err(MMapError::TryAgain), 3));
+    }
 }
@@ -0,0 +1,102 @@
+/*
+ * garbage-collected memory manager in Rust
+ * Copyright (C) 2020  Xie Ruifeng
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Registry of live chunk base addresses, for detecting bad frees (`chunk-registry` feature).
+//!
+//! Without the feature, registering is a no-op and every free is accepted.
+//!
+//! The registry holds at most `CAPACITY` live chunks. Once a chunk fails to be registered for
+//! lack of room, bad frees are no longer detected for the rest of the process, even after the
+//! number of live chunks drops again: the untracked chunks are indistinguishable from addresses
+//! never allocated.
+use super::Result;
+
+#[cfg(feature = "chunk-registry")]
+use super::MMapError;
+#[cfg(feature = "chunk-registry")]
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+/// Number of chunks the registry can track at the same time.
+#[cfg(feature = "chunk-registry")]
+const CAPACITY: usize = 4096;
+
+/// Base addresses of the live chunks, `0` for a vacant slot. A fixed table, so that the registry
+/// never allocates, and is usable below a global allocator.
+#[cfg(feature = "chunk-registry")]
+static CHUNKS: [AtomicUsize; CAPACITY] = [VACANT; CAPACITY];
+
+/// A vacant slot, for initializing `CHUNKS`: every use of the constant is a fresh atomic.
+#[cfg(feature = "chunk-registry")]
+#[allow(clippy::declare_interior_mutable_const)]
+const VACANT: AtomicUsize = AtomicUsize::new(0);
+
+/// Set once a chunk could not be registered for lack of room. From then on, unknown addresses
+/// might be untracked live chunks, and are no longer reported. Never cleared, see above.
+#[cfg(feature = "chunk-registry")]
+static OVERFLOWED: AtomicBool = AtomicBool::new(false);
+
+/// Record `addr` as the base address of a live chunk.
+#[cfg(feature = "chunk-registry")]
+pub fn register(addr: usize) {
+    let vacant = CHUNKS.iter().find(|slot| {
+        slot.compare_exchange(0, addr, Ordering::AcqRel, Ordering::Relaxed).is_ok()
+    });
+    if vacant.is_none() { OVERFLOWED.store(true, Ordering::Relaxed); }
+}
+
+/// Forget the live chunk at `addr`, before freeing it.
+///
+/// Fails with `NotAllocated` if no live chunk is registered at `addr`.
+#[cfg(feature = "chunk-registry")]
+pub fn unregister(addr: usize) -> Result<()> {
+    let found = CHUNKS.iter().any(|slot| {
+        slot.compare_exchange(addr, 0, Ordering::AcqRel, Ordering::Relaxed).is_ok()
+    });
+    if found || OVERFLOWED.load(Ordering::Relaxed) {
+        Ok(())
+    } else {
+        Err(MMapError::NotAllocated)
+    }
+}
+
+/// Record `addr` as the base address of a live chunk.
+#[cfg(not(feature = "chunk-registry"))]
+#[inline]
+pub fn register(_addr: usize) {}
+
+/// Forget the live chunk at `addr`, before freeing it.
+#[cfg(not(feature = "chunk-registry"))]
+#[inline]
+pub fn unregister(_addr: usize) -> Result<()> { Ok(()) }
+
+#[cfg(all(test, feature = "chunk-registry"))]
+mod tests {
+    use super::{register, unregister};
+    use super::super::MMapError;
+
+    #[test]
+    fn test_double_free() {
+        // never the base address of a real chunk, so that other tests cannot interfere
+        static MARKER: u8 = 0;
+        let addr = &MARKER as *const u8 as usize;
+        assert_eq!(unregister(addr), Err(MMapError::NotAllocated));
+        register(addr);
+        assert_eq!(unregister(addr), Ok(()));
+        assert_eq!(unregister(addr), Err(MMapError::NotAllocated));
+    }
+}
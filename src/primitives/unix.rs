@@ -22,9 +22,18 @@
 
 use super::MMapError;
 use super::Result;
+use super::Advice;
+use super::MapFlags;
+use super::registry;
 
 use enumflags2::BitFlags;
 use libc::{c_int, c_void, off_t};
+use core::sync::atomic::{AtomicUsize, Ordering};
+#[cfg(target_os = "linux")]
+use alloc::vec::Vec;
+
+#[cfg(target_os = "linux")]
+use crate::common::{MiB, GiB};
 
 /// Memory protection flags.
 ///
@@ -57,30 +66,69 @@ pub enum Protection {
 impl Protection {
     /// Pages may not be accessed.
     #[allow(dead_code)]
-    pub const NONE: BitFlags<Protection> = unsafe { core::mem::transmute(0u32) };
+    pub const NONE: BitFlags<Protection> = BitFlags::EMPTY;
+
+    /// The protection bits as understood by the host system, i.e. `PROT_*` flags.
+    ///
+    /// On UNIX-like systems the enum values are the `PROT_*` constants themselves.
+    pub fn native_bits(flags: BitFlags<Protection>) -> u32 {
+        flags.bits()
+    }
 }
 
-/// `mmap` flags on UNIX-like systems.
-#[repr(u32)]
-#[derive(Copy, Clone, Debug, Eq, PartialEq, BitFlags)]
-pub enum MapFlags {
-    /// Share this mapping (updates visible to other processes).
-    Shared = libc::MAP_SHARED as u32,
-    /// Private copy-on-write mapping.
-    Private = libc::MAP_PRIVATE as u32,
-    /// The mapping is not backed by any file; its contents are initialized to zero.
-    Anonymous = libc::MAP_ANONYMOUS as u32,
-    /// Do not reserve swap space for this mapping.
-    NoReserve = libc::MAP_NORESERVE as u32,
+/// Translate `MapFlags` to the `MAP_*` flags understood by `mmap`.
+fn native_map_flags(flags: BitFlags<MapFlags>) -> c_int {
+    let mut bits = 0;
+    if flags.contains(MapFlags::Shared) { bits |= libc::MAP_SHARED; }
+    if flags.contains(MapFlags::Private) { bits |= libc::MAP_PRIVATE; }
+    if flags.contains(MapFlags::Anonymous) { bits |= libc::MAP_ANONYMOUS; }
+    if flags.contains(MapFlags::NoReserve) { bits |= libc::MAP_NORESERVE; }
+    #[cfg(target_os = "linux")]
+    if flags.contains(MapFlags::HugeTLB) { bits |= libc::MAP_HUGETLB; }
+    #[cfg(target_os = "linux")]
+    if flags.contains(MapFlags::Prefault) { bits |= libc::MAP_POPULATE; }
+    bits
+}
+
+/// Huge page sizes for `MAP_HUGETLB` mappings on Linux.
+#[cfg(target_os = "linux")]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum HugePageSize {
+    /// 2 MiB huge pages.
+    Size2MiB,
+    /// 1 GiB huge pages.
+    Size1GiB,
+}
+
+#[cfg(target_os = "linux")]
+impl HugePageSize {
+    /// Size of a huge page in bytes.
+    pub fn bytes(self) -> usize {
+        match self {
+            HugePageSize::Size2MiB => 2 * MiB,
+            HugePageSize::Size1GiB => GiB,
+        }
+    }
+
+    /// The size-encoding bits for `mmap` flags.
+    fn encoding(self) -> c_int {
+        match self {
+            HugePageSize::Size2MiB => libc::MAP_HUGE_2MB,
+            HugePageSize::Size1GiB => libc::MAP_HUGE_1GB,
+        }
+    }
 }
 
 const INVALID_FILE_DESCRIPTOR: libc::c_int = -1;
 
+/// Wrapper for `mmap`, `extra_flags` are raw flags not expressible as `MapFlags` (e.g. the huge
+/// page size encoding).
 unsafe fn wrapped_mmap(
     addr: *mut c_void, len: usize,
-    prot: BitFlags<Protection>, flags: BitFlags<MapFlags>,
+    prot: BitFlags<Protection>, flags: BitFlags<MapFlags>, extra_flags: c_int,
     fd: c_int, offset: off_t) -> *mut c_void {
-    libc::mmap(addr, len, prot.bits() as c_int, flags.bits() as c_int, fd, offset)
+    let flags = native_map_flags(flags) | extra_flags;
+    libc::mmap(addr, len, Protection::native_bits(prot) as c_int, flags, fd, offset)
 }
 
 // the following copied from nix
@@ -107,6 +155,8 @@ impl MMapError {
             libc::EAGAIN => MMapError::TryAgain,
             libc::ENOMEM => MMapError::NoMemory,
             libc::EOVERFLOW => MMapError::LengthOverflow,
+            libc::EACCES => MMapError::AccessDenied,
+            libc::EBADF => MMapError::BadFile,
             0 => MMapError::NoError,
             _ => MMapError::UnknownError(e as u32),
         }
@@ -120,20 +170,22 @@ impl MMapError {
 
 /// According to the Linux manual for `_SC_PAGESIZE`:
 ///   Size of a page in bytes.  Must not be less than 1.
-static mut PAGE_SIZE: Option<core::num::NonZeroUsize> = None;
+/// The cached `PAGE_SIZE`, `0` until a successful `sysconf`.
+static PAGE_SIZE: AtomicUsize = AtomicUsize::new(0);
 
-/// Get the `PAGE_SIZE`. This function is cached.
+/// Get the `PAGE_SIZE`. This function is cached, and safe to call from multiple threads.
 pub fn get_page_size() -> Result<usize> {
     // use the cached value if successful calls have been made
-    unsafe { if let Some(res) = PAGE_SIZE { return Ok(res.get()); } }
+    let cached = PAGE_SIZE.load(Ordering::Acquire);
+    if cached != 0 { return Ok(cached); }
     // acquire the value
     unsafe { set_errno(0) }
     let sz = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
-    if sz < 0 {
-        Err(unsafe { MMapError::get() })
-    } else {
-        unsafe { PAGE_SIZE = Some(core::num::NonZeroUsize::new_unchecked(sz as usize)) }
-        Ok(sz as usize)
+    if sz <= 0 { return Err(unsafe { MMapError::get() }); }
+    // the first successful caller publishes the value, the others use it
+    match PAGE_SIZE.compare_exchange(0, sz as usize, Ordering::AcqRel, Ordering::Acquire) {
+        Ok(_) => Ok(sz as usize),
+        Err(published) => Ok(published),
     }
 }
 
@@ -144,24 +196,206 @@ pub fn get_minimum_alignment() -> Result<usize> {
     get_page_size()
 }
 
-/// Allocate a memory chunk with the given size and protection flags.
-pub unsafe fn allocate_chunk(size: usize, protection: BitFlags<Protection>) -> Result<*mut c_void> {
+/// Allocate a memory chunk with the given size, protection and mapping flags.
+///
+/// The chunk is only aligned to `PAGE_SIZE`; see [`aligned_allocate_chunk`] for stricter
+/// alignments.
+///
+/// # Safety
+///
+/// The returned memory must be deallocated with `deallocate_chunk`, with the same `size`.
+///
+/// [`aligned_allocate_chunk`]: fn.aligned_allocate_chunk.html
+pub unsafe fn allocate_chunk(
+    size: usize, protection: BitFlags<Protection>,
+    flags: BitFlags<MapFlags>) -> Result<*mut c_void> {
+    let addr = map_anonymous(size, protection, flags, 0)?;
+    registry::register(addr as usize);
+    Ok(addr)
+}
+
+unsafe fn map_anonymous(
+    size: usize, protection: BitFlags<Protection>,
+    flags: BitFlags<MapFlags>, extra_flags: c_int) -> Result<*mut c_void> {
     if size == 0 { return Err(MMapError::InvalidArguments); }
+    let shared = flags.contains(MapFlags::Shared);
+    let sharing = if shared { MapFlags::Shared } else { MapFlags::Private };
     set_errno(0);
     let addr = wrapped_mmap(
         core::ptr::null_mut(), size,
         protection,
-        MapFlags::Private | MapFlags::Anonymous,
+        sharing | MapFlags::Anonymous | flags, extra_flags,
+        INVALID_FILE_DESCRIPTOR, 0);
+    if addr == libc::MAP_FAILED {
+        Err(MMapError::get())
+    } else {
+        Ok(addr)
+    }
+}
+
+/// Allocate a private memory chunk at exactly `addr`, e.g. for a heap at a fixed base address.
+///
+/// Unlike a plain `MAP_FIXED`, existing mappings are never clobbered: if any part of
+/// `[addr, addr + size)` is already mapped, this function fails with `InvalidArguments`. On Linux
+/// this is `MAP_FIXED_NOREPLACE`; elsewhere `addr` is only passed as a hint, and the mapping is
+/// undone if the kernel places it anywhere else.
+///
+/// # Safety
+///
+/// The returned memory must be deallocated with `deallocate_chunk`, with the same `size`.
+pub unsafe fn allocate_chunk_at(
+    addr: *mut c_void, size: usize, protection: BitFlags<Protection>) -> Result<*mut c_void> {
+    if size == 0 || addr as usize & (get_page_size()? - 1) != 0 {
+        return Err(MMapError::InvalidArguments);
+    }
+    #[cfg(target_os = "linux")]
+    let extra_flags = libc::MAP_FIXED_NOREPLACE;
+    #[cfg(not(target_os = "linux"))]
+    let extra_flags = 0;
+    set_errno(0);
+    let mem = wrapped_mmap(
+        addr, size, protection, MapFlags::Private | MapFlags::Anonymous, extra_flags,
         INVALID_FILE_DESCRIPTOR, 0);
+    if mem == libc::MAP_FAILED {
+        return match get_errno() {
+            libc::EEXIST => Err(MMapError::InvalidArguments),
+            e => Err(MMapError::from_errno(e)),
+        };
+    }
+    if mem != addr {
+        // a mere hint, either off Linux, or before Linux 4.17 which ignores the flag
+        unmap(mem, size)?;
+        return Err(MMapError::InvalidArguments);
+    }
+    registry::register(mem as usize);
+    Ok(mem)
+}
+
+/// (Linux-specific) Allocate a memory chunk backed by huge pages of the given size.
+///
+/// The `size` must be a multiple of the huge page size, otherwise this function fails with
+/// `InvalidArguments`. If no huge page is available, this function fails with `NoMemory`.
+///
+/// # Safety
+///
+/// The returned memory must be deallocated with `deallocate_chunk`, with the same `size`.
+#[cfg(target_os = "linux")]
+pub unsafe fn allocate_huge_chunk(
+    size: usize, protection: BitFlags<Protection>,
+    page_size: HugePageSize) -> Result<*mut c_void> {
+    let mask = page_size.bytes() - 1;
+    if size & mask != 0 { return Err(MMapError::InvalidArguments); }
+    let addr = map_anonymous(size, protection, MapFlags::HugeTLB.into(), page_size.encoding())?;
+    registry::register(addr as usize);
+    Ok(addr)
+}
+
+/// (Linux-specific) Resize the mapping at `addr` from `old_size` to `new_size`, moving it if it
+/// cannot be resized in place. Returns the new address of the mapping.
+///
+/// # Safety
+///
+/// `[addr, addr + old_size)` must be a mapping from this module. If the mapping is moved, the old
+/// addresses become invalid.
+#[cfg(target_os = "linux")]
+pub unsafe fn remap(addr: *mut c_void, old_size: usize, new_size: usize) -> Result<*mut c_void> {
+    registry::unregister(addr as usize)?;
+    set_errno(0);
+    let res = libc::mremap(addr, old_size, new_size, libc::MREMAP_MAYMOVE);
+    if res == libc::MAP_FAILED {
+        registry::register(addr as usize);
+        Err(MMapError::get())
+    } else {
+        registry::register(res as usize);
+        Ok(res)
+    }
+}
+
+/// (UNIX-specific) Map `len` bytes of the file `fd`, starting at `offset`, into memory.
+///
+/// With `shared`, writes through the mapping are carried through to the file (see `sync`);
+/// otherwise the mapping is a private copy-on-write view. The `offset` must be a multiple of
+/// `PAGE_SIZE`, otherwise this function fails with `InvalidArguments`.
+///
+/// # Safety
+///
+/// `fd` must be a file opened with access compatible to `protection`. The returned memory must be
+/// deallocated with `deallocate_chunk`, with the same `len`.
+pub unsafe fn map_file(
+    fd: c_int, offset: usize, len: usize,
+    protection: BitFlags<Protection>, shared: bool) -> Result<*mut c_void> {
+    if len == 0 || offset & (get_page_size()? - 1) != 0 {
+        return Err(MMapError::InvalidArguments);
+    }
+    let flags = if shared { MapFlags::Shared } else { MapFlags::Private };
+    set_errno(0);
+    let addr = wrapped_mmap(
+        core::ptr::null_mut(), len, protection, flags.into(), 0, fd, offset as off_t);
     if addr == libc::MAP_FAILED {
         Err(MMapError::get())
     } else {
+        registry::register(addr as usize);
         Ok(addr)
     }
 }
 
+/// (UNIX-specific) Map `len` bytes of the file `fd` like [`map_file`], but at an address aligned
+/// to `alignment`, e.g. for a mega-block backed by a file.
+///
+/// The `len` must be a multiple of `alignment`, otherwise this function fails with
+/// `InvalidArguments`. An aligned range is reserved first, then the file is mapped over it.
+///
+/// # Safety
+///
+/// See [`map_file`].
+///
+/// [`map_file`]: fn.map_file.html
+pub unsafe fn aligned_map_file(
+    alignment: usize, fd: c_int, offset: usize, len: usize,
+    protection: BitFlags<Protection>, shared: bool) -> Result<*mut c_void> {
+    if !is_power_of_2(alignment) || len == 0 || len & (alignment - 1) != 0
+        || offset & (get_page_size()? - 1) != 0 {
+        return Err(MMapError::InvalidArguments);
+    }
+    let addr = aligned_reserve_chunk(alignment, len)?;
+    let flags = if shared { MapFlags::Shared } else { MapFlags::Private };
+    set_errno(0);
+    let res = wrapped_mmap(
+        addr, len, protection, flags.into(), libc::MAP_FIXED, fd, offset as off_t);
+    if res == libc::MAP_FAILED {
+        let error = MMapError::get();
+        deallocate_chunk(addr, len)?;
+        Err(error)
+    } else {
+        Ok(res)
+    }
+}
+
+/// (UNIX-specific) Write the pages in `[addr, addr + size)` of a shared file mapping back to the
+/// file, blocking until done.
+///
+/// # Safety
+///
+/// The pages must be mapped, and `addr` must be a multiple of `PAGE_SIZE`.
+pub unsafe fn sync(addr: *mut c_void, size: usize) -> Result<()> {
+    set_errno(0);
+    if libc::msync(addr, size, libc::MS_SYNC) < 0 {
+        Err(MMapError::get())
+    } else {
+        Ok(())
+    }
+}
+
 /// Deallocate a memory chunk.
+///
+/// With the `chunk-registry` feature, this function fails with `NotAllocated` if `addr` is not
+/// the address of a live chunk from this module.
 pub unsafe fn deallocate_chunk(addr: *mut c_void, size: usize) -> Result<()> {
+    registry::unregister(addr as usize)?;
+    unmap(addr, size)
+}
+
+unsafe fn unmap(addr: *mut c_void, size: usize) -> Result<()> {
     set_errno(0);
     if libc::munmap(addr, size) < 0 {
         Err(MMapError::get())
@@ -170,30 +404,286 @@ pub unsafe fn deallocate_chunk(addr: *mut c_void, size: usize) -> Result<()> {
     }
 }
 
-fn is_power_of_2(x: usize) -> bool {
-    (x - 1) & x == 0
+/// Change the protection of the pages in `[addr, addr + size)`.
+///
+/// # Safety
+///
+/// The pages must be mapped, and no reference into them may be used against the new protection.
+pub unsafe fn protect(
+    addr: *mut c_void, size: usize, protection: BitFlags<Protection>) -> Result<()> {
+    set_errno(0);
+    if libc::mprotect(addr, size, Protection::native_bits(protection) as c_int) < 0 {
+        Err(MMapError::get())
+    } else {
+        Ok(())
+    }
+}
+
+/// Give the kernel a hint about how the pages in `[addr, addr + size)` will be accessed.
+///
+/// `Advice::DontNeed` only lets the pages be reclaimed, the mapping itself stays valid.
+///
+/// # Safety
+///
+/// The pages must be mapped. After `Advice::DontNeed`, their contents are lost.
+pub unsafe fn advise(addr: *mut c_void, size: usize, advice: Advice) -> Result<()> {
+    let advice = match advice {
+        Advice::Normal => libc::MADV_NORMAL,
+        Advice::Sequential => libc::MADV_SEQUENTIAL,
+        Advice::Random => libc::MADV_RANDOM,
+        Advice::WillNeed => libc::MADV_WILLNEED,
+        Advice::DontNeed => libc::MADV_DONTNEED,
+    };
+    set_errno(0);
+    if libc::madvise(addr, size, advice) < 0 {
+        Err(MMapError::get())
+    } else {
+        Ok(())
+    }
+}
+
+/// (Linux-specific) Query the protection of the pages in `[addr, addr + len)`, as listed in
+/// `/proc/self/maps`.
+///
+/// If the range spans pages with different protections, the most restrictive protection is
+/// returned, i.e. the accesses allowed on every page. If any part of the range is not mapped, or
+/// `len` is zero, this function fails with `InvalidArguments`.
+#[cfg(target_os = "linux")]
+pub fn query(addr: *mut c_void, len: usize) -> Result<BitFlags<Protection>> {
+    let start = addr as usize;
+    let end = match start.checked_add(len) {
+        Some(end) if len != 0 => end,
+        _ => return Err(MMapError::InvalidArguments),
+    };
+    let maps = read_proc_self_maps()?;
+    let mut protection = BitFlags::all();
+    let mut cursor = start;
+    // mappings are listed in ascending order of address
+    for (low, high, perms) in maps.split(|b| *b == b'\n').filter_map(parse_maps_line) {
+        if high <= cursor { continue; }
+        if low > cursor { break; }
+        protection &= perms;
+        cursor = high;
+        if cursor >= end { return Ok(protection); }
+    }
+    Err(MMapError::InvalidArguments)
+}
+
+#[cfg(target_os = "linux")]
+fn read_proc_self_maps() -> Result<Vec<u8>> {
+    let path = b"/proc/self/maps\0";
+    let fd = unsafe { libc::open(path.as_ptr() as _, libc::O_RDONLY | libc::O_CLOEXEC) };
+    if fd < 0 { return Err(unsafe { MMapError::get() }); }
+    let mut contents = Vec::new();
+    let mut buffer = [0u8; 4096];
+    let result = loop {
+        let n = unsafe { libc::read(fd, buffer.as_mut_ptr() as _, buffer.len()) };
+        if n > 0 {
+            contents.extend_from_slice(&buffer[..n as usize]);
+        } else if n == 0 {
+            break Ok(contents);
+        } else {
+            let e = unsafe { get_errno() };
+            if e != libc::EINTR { break Err(MMapError::from_errno(e)); }
+        }
+    };
+    unsafe { libc::close(fd) };
+    result
 }
 
-/// Allocate an aligned memory chunk with the given alignment, size and protection flags.
+/// Parse the address range and permissions of a line in `/proc/self/maps`, e.g.
+/// `7f0000000000-7f0000001000 rw-p 00000000 00:00 0`.
+#[cfg(target_os = "linux")]
+fn parse_maps_line(line: &[u8]) -> Option<(usize, usize, BitFlags<Protection>)> {
+    let parse_hex = |bytes: &[u8]| {
+        core::str::from_utf8(bytes).ok().and_then(|s| usize::from_str_radix(s, 16).ok())
+    };
+    let mut fields = line.split(|b| *b == b' ');
+    let range = fields.next()?;
+    let perms = fields.next()?;
+    let dash = range.iter().position(|b| *b == b'-')?;
+    let low = parse_hex(&range[..dash])?;
+    let high = parse_hex(&range[dash + 1..])?;
+    let mut protection = BitFlags::empty();
+    if perms.first() == Some(&b'r') { protection |= Protection::Read; }
+    if perms.get(1) == Some(&b'w') { protection |= Protection::Write; }
+    if perms.get(2) == Some(&b'x') { protection |= Protection::Exec; }
+    Some((low, high, protection))
+}
+
+/// Count the pages in `[addr, addr + size)` currently resident in physical memory, with
+/// `mincore`. The `addr` must be a multiple of `PAGE_SIZE`, and the whole range must be mapped,
+/// otherwise this function fails with `InvalidArguments` or `NoMemory` respectively.
+pub fn resident_pages(addr: *mut c_void, size: usize) -> Result<usize> {
+    let page_size = get_page_size()?;
+    let pages = (size + page_size - 1) / page_size;
+    let mut residency = [0u8; 1024];
+    let mut resident = 0;
+    for first in (0..pages).step_by(residency.len()) {
+        let count = (pages - first).min(residency.len());
+        let start = (addr as usize + first * page_size) as *mut c_void;
+        unsafe {
+            set_errno(0);
+            if libc::mincore(start, count * page_size, residency.as_mut_ptr() as _) < 0 {
+                return Err(MMapError::get());
+            }
+        }
+        resident += residency[..count].iter().filter(|r| **r & 1 != 0).count();
+    }
+    Ok(resident)
+}
+
+/// Lock the pages in `[addr, addr + size)` into RAM, so that they are never swapped out.
 ///
-/// The size is rounded up to a multiple of the alignment.
+/// Fails with `LockLimit` if `RLIMIT_MEMLOCK` would be exceeded, or if the process is not
+/// privileged to lock memory.
 ///
-/// # Panics
+/// # Safety
+///
+/// The pages must be mapped.
+pub unsafe fn lock(addr: *mut c_void, size: usize) -> Result<()> {
+    set_errno(0);
+    if libc::mlock(addr, size) < 0 {
+        match get_errno() {
+            libc::ENOMEM | libc::EPERM => Err(MMapError::LockLimit),
+            e => Err(MMapError::from_errno(e)),
+        }
+    } else {
+        Ok(())
+    }
+}
+
+/// Unlock the pages in `[addr, addr + size)`, so that they may be swapped out again.
+///
+/// # Safety
+///
+/// The pages must be mapped.
+pub unsafe fn unlock(addr: *mut c_void, size: usize) -> Result<()> {
+    set_errno(0);
+    if libc::munlock(addr, size) < 0 {
+        Err(MMapError::get())
+    } else {
+        Ok(())
+    }
+}
+
+fn is_power_of_2(x: usize) -> bool {
+    x != 0 && (x - 1) & x == 0
+}
+
+/// Allocate an aligned memory chunk with the given alignment, size, protection and mapping flags.
 ///
-/// The alignment is asserted to be a multiple of `PAGE_SIZE` **AND** a power of 2.
+/// The mapping is always anonymous, and private unless `MapFlags::Shared` is given. The size is
+/// rounded up to a multiple of the alignment, which must be a power of 2: otherwise this function
+/// fails with `InvalidArguments`, like on Windows.
 pub unsafe fn aligned_allocate_chunk(
-    alignment: usize, size: usize, protection: BitFlags<Protection>) -> Result<*mut c_void> {
-    assert!(is_power_of_2(alignment));
+    alignment: usize, size: usize, protection: BitFlags<Protection>,
+    flags: BitFlags<MapFlags>) -> Result<*mut c_void> {
+    let page_size = get_page_size()?;
+    let addr = aligned_map(alignment, page_size, size, |size| {
+        map_anonymous(size, protection, flags, 0)
+    })?;
+    // without `MAP_POPULATE`, fault the pages in by hand
+    #[cfg(not(target_os = "linux"))]
+    if flags.contains(MapFlags::Prefault) && !flags.contains(MapFlags::NoReserve) {
+        let mask = alignment.max(page_size) - 1;
+        super::touch_pages(addr, (size + mask) & !mask, page_size, protection);
+    }
+    Ok(addr)
+}
+
+/// Reserve an aligned range of address space without committing memory to it.
+///
+/// The range is mapped as `Protection::NONE` without reserving swap space; sub-ranges are made
+/// usable with [`commit`](fn.commit.html). The size is rounded up to a multiple of the alignment.
+///
+/// # Safety
+///
+/// The returned memory must be deallocated with `deallocate_chunk`, with the same `size`.
+///
+/// # Errors
+///
+/// Fails with `InvalidArguments` if the alignment is not a power of 2.
+pub unsafe fn aligned_reserve_chunk(alignment: usize, size: usize) -> Result<*mut c_void> {
+    aligned_map(alignment, get_page_size()?, size, |size| {
+        map_anonymous(size, Protection::NONE, MapFlags::NoReserve.into(), 0)
+    })
+}
+
+/// Commit the pages in `[addr, addr + size)` of a reserved chunk with the given protection.
+///
+/// # Safety
+///
+/// The pages must belong to a chunk from `aligned_reserve_chunk`.
+pub unsafe fn commit(
+    addr: *mut c_void, size: usize, protection: BitFlags<Protection>) -> Result<()> {
+    protect(addr, size, protection)
+}
+
+/// Decommit the pages in `[addr, addr + size)`, returning them to the reserved state.
+///
+/// # Safety
+///
+/// The pages must be mapped. Their contents are lost, and they become inaccessible.
+pub unsafe fn decommit(addr: *mut c_void, size: usize) -> Result<()> {
+    advise(addr, size, Advice::DontNeed)?;
+    protect(addr, size, Protection::NONE)
+}
+
+/// (Linux-specific) Allocate an aligned memory chunk backed by huge pages of the given size.
+///
+/// Both `alignment` and `size` must be multiples of the huge page size, otherwise this function
+/// fails with `InvalidArguments`. If no huge page is available, this function fails with
+/// `NoMemory`.
+///
+/// # Safety
+///
+/// The returned memory must be deallocated with `deallocate_chunk`, with the same `size`.
+#[cfg(target_os = "linux")]
+pub unsafe fn aligned_allocate_huge_chunk(
+    alignment: usize, size: usize, protection: BitFlags<Protection>,
+    page_size: HugePageSize) -> Result<*mut c_void> {
+    let mask = page_size.bytes() - 1;
+    if alignment & mask != 0 || size & mask != 0 {
+        return Err(MMapError::InvalidArguments);
+    }
+    aligned_map(alignment, page_size.bytes(), size, |size| {
+        map_anonymous(size, protection, MapFlags::HugeTLB.into(), page_size.encoding())
+    })
+}
+
+/// Map an aligned region of `size` (rounded up to `alignment`) with `allocate`. Fails with
+/// `InvalidArguments` if `alignment` is not a power of 2.
+///
+/// Mappings are always aligned to `granularity` (the page size), so no extra work is needed if
+/// `alignment` is not larger than that. Otherwise, the mapping is over-allocated by just enough
+/// (`alignment - granularity`) to contain an aligned region, and the padding around that region
+/// is trimmed: that is 1 to 3 system calls. The aligned region itself stays mapped all along, so
+/// no other thread can map into it in between.
+unsafe fn aligned_map(
+    alignment: usize, granularity: usize, size: usize,
+    allocate: impl FnOnce(usize) -> Result<*mut c_void>) -> Result<*mut c_void> {
+    if !is_power_of_2(alignment) { return Err(MMapError::InvalidArguments); }
     let alignment_mask = alignment - 1;
-    let size = (size + alignment - 1) & !alignment_mask;
-    let res = allocate_chunk(size + alignment, protection)?;
-    let back_padding = res as usize & alignment_mask;
-    let front_padding = alignment - back_padding;
-    deallocate_chunk(res, front_padding)?;
-    let start_addr = res.offset(front_padding as isize);
+    let size = size.checked_add(alignment_mask).ok_or(MMapError::LengthOverflow)?
+        & !alignment_mask;
+    if alignment <= granularity {
+        let res = allocate(size)?;
+        registry::register(res as usize);
+        return Ok(res);
+    }
+    let padding = alignment - granularity;
+    let res = allocate(size.checked_add(padding).ok_or(MMapError::LengthOverflow)?)?;
+    let front_padding = (alignment - (res as usize & alignment_mask)) & alignment_mask;
+    let back_padding = padding - front_padding;
+    let start_addr = res.add(front_padding);
+    if front_padding > 0 {
+        unmap(res, front_padding)?;
+    }
     if back_padding > 0 {
-        deallocate_chunk(start_addr, back_padding)?;
+        unmap(start_addr.add(size), back_padding)?;
     }
+    registry::register(start_addr as usize);
     Ok(start_addr)
 }
 
@@ -202,6 +692,20 @@ mod tests {
     extern crate std;
 
     use super::is_power_of_2;
+    use super::{aligned_allocate_chunk, deallocate_chunk, get_page_size, Protection};
+    use super::{native_map_flags, MapFlags};
+    use enumflags2::BitFlags;
+
+    #[test]
+    fn test_from_errno() {
+        use super::MMapError;
+        assert_eq!(MMapError::from_errno(libc::EINVAL), MMapError::InvalidArguments);
+        assert_eq!(MMapError::from_errno(libc::EACCES), MMapError::AccessDenied);
+        assert_eq!(MMapError::from_errno(libc::EBADF), MMapError::BadFile);
+        let unknown = MMapError::UnknownError(libc::ENODEV as u32);
+        assert_eq!(MMapError::from_errno(libc::ENODEV), unknown);
+        assert_eq!(MMapError::from_errno(0), MMapError::NoError);
+    }
 
     #[test]
     fn test_is_power_of_2() {
@@ -209,5 +713,237 @@ mod tests {
         assert!(is_power_of_2(2));
         assert!(is_power_of_2(256));
         assert!(!is_power_of_2(257));
+        assert!(!is_power_of_2(0));
+    }
+
+    #[test]
+    fn test_get_page_size_concurrently() {
+        use std::vec::Vec;
+        let threads: Vec<_> = (0..8)
+            .map(|_| std::thread::spawn(|| {
+                (0..1000).map(|_| get_page_size().unwrap()).collect::<Vec<_>>()
+            }))
+            .collect();
+        let expected = get_page_size().unwrap();
+        assert_eq!(expected, unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize);
+        for thread in threads {
+            assert!(thread.join().unwrap().iter().all(|size| *size == expected));
+        }
+    }
+
+    #[test]
+    fn test_aligned_allocate_chunk_writable() {
+        let alignment = get_page_size().unwrap() * 16;
+        let size = alignment * 2;
+        let protection = Protection::Read | Protection::Write;
+        for _ in 0..8 {
+            let addr = unsafe {
+                aligned_allocate_chunk(alignment, size, protection, BitFlags::empty()).unwrap()
+            };
+            assert_eq!(addr as usize % alignment, 0);
+            let bytes = unsafe { core::slice::from_raw_parts_mut(addr as *mut u8, size) };
+            bytes.iter_mut().for_each(|b| *b = 0xAA);
+            unsafe { deallocate_chunk(addr, size).unwrap() }
+        }
+    }
+
+    #[test]
+    fn test_allocate_chunk_at() {
+        use super::{allocate_chunk_at, aligned_reserve_chunk, MMapError};
+        use libc::c_void;
+        let page_size = get_page_size().unwrap();
+        let size = page_size * 4;
+        let protection = Protection::Read | Protection::Write;
+        let reserved = unsafe { aligned_reserve_chunk(page_size, size).unwrap() };
+        let overlap = unsafe { (reserved as *mut u8).add(page_size * 2) as *mut c_void };
+        let result = unsafe { allocate_chunk_at(overlap, size, protection) };
+        assert_eq!(result, Err(MMapError::InvalidArguments));
+        let odd = unsafe { (reserved as *mut u8).add(1) as *mut c_void };
+        let result = unsafe { allocate_chunk_at(odd, size, protection) };
+        assert_eq!(result, Err(MMapError::InvalidArguments));
+        // the range is free again once unmapped, unless another thread grabbed it meanwhile
+        unsafe { deallocate_chunk(reserved, size).unwrap() };
+        if let Ok(addr) = unsafe { allocate_chunk_at(reserved, size, protection) } {
+            assert_eq!(addr, reserved);
+            unsafe { (addr as *mut u8).write(42) };
+            unsafe { deallocate_chunk(addr, size).unwrap() };
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_query() {
+        use super::{parse_maps_line, protect, query, MMapError};
+        let line = b"7f0000000000-7f0000002000 r-xp 00000000 08:01 42 /usr/lib/libc.so.6";
+        let read_exec = Protection::Read | Protection::Exec;
+        assert_eq!(parse_maps_line(line), Some((0x7f00_0000_0000, 0x7f00_0000_2000, read_exec)));
+        assert_eq!(parse_maps_line(b""), None);
+        let page_size = get_page_size().unwrap();
+        let size = page_size * 2;
+        let rw = Protection::Read | Protection::Write;
+        let addr = unsafe {
+            aligned_allocate_chunk(page_size, size, rw, BitFlags::empty()).unwrap()
+        };
+        assert_eq!(query(addr, size), Ok(rw));
+        let second = unsafe { (addr as *mut u8).add(page_size) as *mut libc::c_void };
+        unsafe { protect(second, page_size, Protection::Read.into()).unwrap() };
+        assert_eq!(query(addr, page_size), Ok(rw));
+        assert_eq!(query(addr, size), Ok(Protection::Read.into()));
+        assert_eq!(query(addr, 0), Err(MMapError::InvalidArguments));
+        unsafe { deallocate_chunk(addr, size).unwrap() };
+    }
+
+    #[test]
+    fn test_aligned_map_alignments() {
+        let page_size = get_page_size().unwrap();
+        let protection = Protection::Read | Protection::Write;
+        for &alignment in &[page_size, page_size * 2, 1 << 20, 4 << 20] {
+            for &size in &[page_size, alignment, alignment + page_size] {
+                let addr = unsafe {
+                    aligned_allocate_chunk(alignment, size, protection, BitFlags::empty()).unwrap()
+                };
+                assert_eq!(addr as usize & (alignment - 1), 0);
+                let rounded = (size + alignment - 1) & !(alignment - 1);
+                let bytes = unsafe { core::slice::from_raw_parts_mut(addr as *mut u8, rounded) };
+                bytes.fill(0xAA);
+                assert!(bytes.iter().all(|b| *b == 0xAA));
+                unsafe { deallocate_chunk(addr, rounded).unwrap() }
+            }
+        }
+    }
+
+    #[test]
+    fn test_aligned_map_odd_sizes() {
+        use super::MMapError;
+        let page_size = get_page_size().unwrap();
+        let protection = Protection::Read | Protection::Write;
+        let alignment = page_size * 4;
+        for &size in &[1, page_size + 1, alignment + 1] {
+            let addr = unsafe {
+                aligned_allocate_chunk(alignment, size, protection, BitFlags::empty()).unwrap()
+            };
+            assert_eq!(addr as usize & (alignment - 1), 0);
+            let rounded = (size + alignment - 1) & !(alignment - 1);
+            let bytes = unsafe { core::slice::from_raw_parts_mut(addr as *mut u8, rounded) };
+            bytes[rounded - 1] = 0xAA;
+            unsafe { deallocate_chunk(addr, rounded).unwrap() }
+        }
+        for &alignment in &[0, 3, page_size + 1] {
+            let res = unsafe {
+                aligned_allocate_chunk(alignment, page_size, protection, BitFlags::empty())
+            };
+            assert_eq!(res, Err(MMapError::InvalidArguments));
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_prefault() {
+        let page_size = get_page_size().unwrap();
+        let size = page_size * 16;
+        assert_eq!(native_map_flags(MapFlags::Prefault.into()), libc::MAP_POPULATE);
+        let protection = Protection::Read | Protection::Write;
+        let addr = unsafe {
+            aligned_allocate_chunk(page_size, size, protection, MapFlags::Prefault.into()).unwrap()
+        };
+        let mut residency = [0u8; 16];
+        assert_eq!(unsafe { libc::mincore(addr, size, residency.as_mut_ptr()) }, 0);
+        // best effort: the kernel may already have reclaimed some of the pages
+        let resident = residency.iter().filter(|r| **r & 1 != 0).count();
+        assert!(resident > 0, "no page resident after MAP_POPULATE");
+        unsafe { deallocate_chunk(addr, size).unwrap() }
+    }
+
+    #[test]
+    fn test_no_reserve() {
+        let bits = native_map_flags(MapFlags::Anonymous | MapFlags::NoReserve);
+        assert_eq!(bits, libc::MAP_ANONYMOUS | libc::MAP_NORESERVE);
+        let size = get_page_size().unwrap() * 4;
+        let protection = Protection::Read | Protection::Write;
+        let addr = unsafe {
+            aligned_allocate_chunk(size, size, protection, MapFlags::NoReserve.into()).unwrap()
+        };
+        unsafe { (addr as *mut u8).write(42) };
+        unsafe { deallocate_chunk(addr, size).unwrap() }
+    }
+
+    #[test]
+    fn test_map_file() {
+        use super::{map_file, sync, MMapError};
+        use std::io::Read;
+        use std::os::unix::io::AsRawFd;
+        let page_size = get_page_size().unwrap();
+        let path = std::env::temp_dir().join(std::format!("mm-map-file-{}", std::process::id()));
+        let mut file = std::fs::OpenOptions::new()
+            .read(true).write(true).create(true).truncate(true)
+            .open(&path).unwrap();
+        file.set_len(page_size as u64 * 2).unwrap();
+        let protection = Protection::Read | Protection::Write;
+        let fd = file.as_raw_fd();
+        let res = unsafe { map_file(fd, 1, page_size, protection, true) };
+        assert_eq!(res, Err(MMapError::InvalidArguments));
+        let res = unsafe { map_file(-1, 0, page_size, protection, true) };
+        assert_eq!(res, Err(MMapError::BadFile));
+        let addr = unsafe { map_file(fd, page_size, page_size, protection, true).unwrap() };
+        let bytes = unsafe { core::slice::from_raw_parts_mut(addr as *mut u8, page_size) };
+        bytes[..5].copy_from_slice(b"hello");
+        unsafe { sync(addr, page_size).unwrap() };
+        unsafe { deallocate_chunk(addr, page_size).unwrap() };
+        let mut contents = std::vec::Vec::new();
+        file.read_to_end(&mut contents).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(&contents[page_size..page_size + 5], b"hello");
+    }
+
+    #[test]
+    fn test_aligned_map_file() {
+        use super::{aligned_map_file, MMapError};
+        use std::io::Read;
+        use std::os::unix::io::AsRawFd;
+        let alignment = 1 << 20;
+        let path = std::env::temp_dir()
+            .join(std::format!("mm-aligned-map-file-{}", std::process::id()));
+        let mut file = std::fs::OpenOptions::new()
+            .read(true).write(true).create(true).truncate(true)
+            .open(&path).unwrap();
+        file.set_len(alignment as u64).unwrap();
+        let protection = Protection::Read | Protection::Write;
+        let fd = file.as_raw_fd();
+        let res = unsafe { aligned_map_file(alignment, fd, 0, alignment / 2, protection, true) };
+        assert_eq!(res, Err(MMapError::InvalidArguments));
+        let addr = unsafe { aligned_map_file(alignment, fd, 0, alignment, protection, true) };
+        let addr = addr.unwrap();
+        assert_eq!(addr as usize & (alignment - 1), 0);
+        unsafe { (addr as *mut u8).add(alignment - 1).write(42) };
+        unsafe { deallocate_chunk(addr, alignment).unwrap() };
+        let mut contents = std::vec::Vec::new();
+        file.read_to_end(&mut contents).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(contents[alignment - 1], 42);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_huge_pages() {
+        use super::{aligned_allocate_huge_chunk, HugePageSize, MMapError};
+        let huge = HugePageSize::Size2MiB.bytes();
+        let protection = Protection::Read | Protection::Write;
+        let res = unsafe {
+            aligned_allocate_huge_chunk(huge, 4096, protection, HugePageSize::Size2MiB)
+        };
+        assert_eq!(res, Err(MMapError::InvalidArguments));
+        let res = unsafe {
+            aligned_allocate_huge_chunk(huge, huge, protection, HugePageSize::Size2MiB)
+        };
+        match res {
+            Ok(addr) => {
+                assert_eq!(addr as usize % huge, 0);
+                unsafe { *(addr as *mut u8) = 42; }
+                unsafe { deallocate_chunk(addr, huge).unwrap() }
+            }
+            // no huge pages configured on this machine
+            Err(MMapError::NoMemory) => {}
+            Err(e) => panic!("unexpected error: {:?}", e),
+        }
     }
 }
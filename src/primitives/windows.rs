@@ -20,23 +20,33 @@
 
 #![cfg(windows)]
 
-use winapi::um::winnt::{PVOID, HANDLE};
-use winapi::um::memoryapi::VirtualFree;
+use winapi::um::winnt::{PVOID, HANDLE, MEMORY_BASIC_INFORMATION};
+use winapi::um::memoryapi::{VirtualAlloc, VirtualFree, VirtualProtect, DiscardVirtualMemory};
+use winapi::um::memoryapi::{VirtualLock, VirtualUnlock, VirtualQuery};
 use winapi::um::sysinfoapi::{GetSystemInfo, SYSTEM_INFO};
 use winapi::um::errhandlingapi::GetLastError;
-use winapi::shared::basetsd::{DWORD64, SIZE_T};
-use winapi::shared::minwindef::{ULONG, DWORD};
+use winapi::um::processthreadsapi::GetCurrentProcess;
+use winapi::shared::basetsd::{DWORD64, SIZE_T, ULONG_PTR};
+use winapi::shared::minwindef::{ULONG, DWORD, BOOL};
 use winapi::shared::winerror::*;
 use winapi::ctypes::c_void;
 
 use super::MMapError;
 use super::Result;
+use super::Advice;
+use super::MapFlags;
+use super::registry;
 
 use enumflags2::BitFlags;
 
-#[link(name = "mincore")]
+use core::cell::UnsafeCell;
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicU8, Ordering};
+
+// `VirtualAlloc2` is exported by `kernelbase.dll`, via the `onecore` umbrella import library of
+// the Windows SDK. It is available since Windows 10, version 1803.
+#[link(name = "onecore")]
 extern "system" {
-    #[no_mangle]
     fn VirtualAlloc2(
         process: HANDLE, base_address: PVOID, size: SIZE_T,
         allocation_type: ULONG, page_protection: ULONG,
@@ -44,10 +54,24 @@ extern "system" {
     ) -> PVOID;
 }
 
+// `K32QueryWorkingSetEx` is what `QueryWorkingSetEx` of `psapi.dll` forwards to, since Windows 7.
+#[link(name = "kernel32")]
+extern "system" {
+    fn K32QueryWorkingSetEx(process: HANDLE, info: PVOID, size: DWORD) -> BOOL;
+}
+
+#[repr(C)]
+#[allow(non_camel_case_types)]
+#[derive(Copy, Clone)]
+struct PSAPI_WORKING_SET_EX_INFORMATION {
+    virtual_address: PVOID,
+    /// Bit fields, bit 0 being `Valid`: whether the page is in the working set.
+    virtual_attributes: ULONG_PTR,
+}
+
 const MEM_COMMIT: ULONG = 0x0000_1000;
 const MEM_RESERVE: ULONG = 0x0000_2000;
 
-#[allow(dead_code)]
 const MEM_DECOMMIT: ULONG = 0x0000_4000;
 const MEM_RELEASE: ULONG = 0x0000_8000;
 
@@ -62,7 +86,6 @@ const PAGE_EXECUTE_WRITECOPY: ULONG = 0x80;
 const PAGE_NOACCESS: ULONG = 0x01;
 const PAGE_READ: ULONG = 0x02;
 const PAGE_READWRITE: ULONG = 0x04;
-#[allow(dead_code)]
 const PAGE_WRITECOPY: ULONG = 0x08;
 
 /// Memory protection flags.
@@ -104,9 +127,29 @@ fn make_protection_flag(protection: BitFlags<Protection>) -> ULONG {
     if protection.contains(Protection::Exec) { rw << 4 } else { rw }
 }
 
+/// Inverse of `make_protection_flag`, modifiers like `PAGE_GUARD` are ignored.
+fn decode_protection_flag(flag: ULONG) -> BitFlags<Protection> {
+    let (exec, rw) = if flag & 0xF0 != 0 { (true, flag >> 4 & 0x0F) } else { (false, flag & 0x0F) };
+    let mut protection = match rw {
+        PAGE_READ => Protection::Read.into(),
+        PAGE_READWRITE | PAGE_WRITECOPY => Protection::Read | Protection::Write,
+        _ => Protection::NONE,
+    };
+    if exec { protection |= Protection::Exec; }
+    protection
+}
+
 impl Protection {
     /// Pages may not be accessed.
-    pub const NONE: BitFlags<Protection> = unsafe { core::mem::transmute(0) };
+    pub const NONE: BitFlags<Protection> = BitFlags::EMPTY;
+
+    /// The protection bits as understood by the host system, i.e. `PAGE_*` constants.
+    ///
+    /// On Windows the enum values are NOT the `PAGE_*` constants, this goes through the same
+    /// translation as the allocation functions.
+    pub fn native_bits(flags: BitFlags<Protection>) -> u32 {
+        make_protection_flag(flags)
+    }
 }
 
 #[repr(u8)]
@@ -158,7 +201,8 @@ impl MMapError {
     /// Get `MMapError` from an error code.
     pub fn from_errno(e: DWORD) -> MMapError {
         match e {
-            ERROR_INVALID_PARAMETER => MMapError::InvalidArguments,
+            ERROR_INVALID_PARAMETER | ERROR_INVALID_ADDRESS => MMapError::InvalidArguments,
+            ERROR_NOT_ENOUGH_MEMORY | ERROR_COMMITMENT_LIMIT => MMapError::NoMemory,
             ERROR_SUCCESS => MMapError::NoError,
             _ => MMapError::UnknownError(e),
         }
@@ -170,19 +214,31 @@ impl MMapError {
     }
 }
 
-static mut SYS_INFO: Option<SYSTEM_INFO> = None;
+/// Storage for the cached `SYSTEM_INFO`, written once under `SYS_INFO_STATE`.
+struct SysInfoCell(UnsafeCell<MaybeUninit<SYSTEM_INFO>>);
+
+unsafe impl Sync for SysInfoCell {}
+
+const UNINITIALIZED: u8 = 0;
+const INITIALIZING: u8 = 1;
+const INITIALIZED: u8 = 2;
 
+static SYS_INFO: SysInfoCell = SysInfoCell(UnsafeCell::new(MaybeUninit::uninit()));
+static SYS_INFO_STATE: AtomicU8 = AtomicU8::new(UNINITIALIZED);
+
+/// Query `SYSTEM_INFO` exactly once, concurrent first callers wait for the winner.
 fn get_sys_info() -> &'static SYSTEM_INFO {
-    unsafe {
-        if let Some(res) = &SYS_INFO {
-            res
-        } else {
-            let mut info = core::mem::zeroed();
-            GetSystemInfo(&mut info);
-            SYS_INFO = Some(info);
-            SYS_INFO.as_ref().unwrap()
+    let won = SYS_INFO_STATE.compare_exchange(
+        UNINITIALIZED, INITIALIZING, Ordering::Acquire, Ordering::Acquire);
+    if won.is_ok() {
+        unsafe { GetSystemInfo((*SYS_INFO.0.get()).as_mut_ptr()); }
+        SYS_INFO_STATE.store(INITIALIZED, Ordering::Release);
+    } else {
+        while SYS_INFO_STATE.load(Ordering::Acquire) != INITIALIZED {
+            core::hint::spin_loop();
         }
     }
+    unsafe { (*SYS_INFO.0.get()).assume_init_ref() }
 }
 
 /// Get the `PAGE_SIZE`.
@@ -206,13 +262,105 @@ fn to_void_p<T>(p: &mut T) -> *mut c_void {
     p as *mut T as *mut c_void
 }
 
-/// Allocate an aligned memory chunk with the given alignment, size and protection flags.
+/// Allocate an aligned memory chunk with the given alignment, size, protection and mapping flags.
+///
+/// See [`MapFlags`](enum.MapFlags.html) for how the flags are translated on Windows.
 ///
 /// Unlike on UNIX-like systems, aligned raw memory allocation is properly supported by an API
 /// named `VirtualAlloc2`. Thus we are not manually aligning the allocated memory. This means
 /// calling this function with a bad alignment will not panic, but will fail with `InvalidArguments`.
+///
+/// `VirtualAlloc2` requires Windows 10, version 1803 or later.
 pub unsafe fn aligned_allocate_chunk(
-    alignment: usize, size: usize, protection: BitFlags<Protection>) -> Result<*mut c_void> {
+    alignment: usize, size: usize, protection: BitFlags<Protection>,
+    flags: BitFlags<MapFlags>) -> Result<*mut c_void> {
+    let addr = aligned_virtual_alloc(alignment, size, allocation_type(flags)?, protection)?;
+    if flags.contains(MapFlags::Prefault) && !flags.contains(MapFlags::NoReserve) {
+        super::touch_pages(addr, size, get_page_size()?, protection);
+    }
+    Ok(addr)
+}
+
+/// Translate `MapFlags` to the allocation type understood by `VirtualAlloc2`.
+fn allocation_type(flags: BitFlags<MapFlags>) -> Result<ULONG> {
+    if flags.contains(MapFlags::Shared) {
+        Err(MMapError::InvalidArguments)
+    } else if flags.contains(MapFlags::NoReserve) {
+        Ok(MEM_RESERVE)
+    } else {
+        Ok(MEM_COMMIT | MEM_RESERVE)
+    }
+}
+
+/// Reserve an aligned range of address space without committing memory to it.
+///
+/// Sub-ranges are made usable with [`commit`](fn.commit.html). Like `aligned_allocate_chunk`,
+/// this function fails with `InvalidArguments` on a bad alignment.
+///
+/// # Safety
+///
+/// The returned memory must be deallocated with `deallocate_chunk`, with the same `size`.
+pub unsafe fn aligned_reserve_chunk(alignment: usize, size: usize) -> Result<*mut c_void> {
+    aligned_virtual_alloc(alignment, size, MEM_RESERVE, Protection::NONE)
+}
+
+/// Commit the pages in `[addr, addr + size)` of a reserved chunk with the given protection.
+///
+/// # Safety
+///
+/// The pages must belong to a chunk from `aligned_reserve_chunk`.
+pub unsafe fn commit(
+    addr: *mut c_void, size: usize, protection: BitFlags<Protection>) -> Result<()> {
+    let mem = VirtualAlloc(addr, size, MEM_COMMIT, Protection::native_bits(protection));
+    if mem != core::ptr::null_mut() {
+        Ok(())
+    } else {
+        Err(MMapError::get())
+    }
+}
+
+/// Decommit the pages in `[addr, addr + size)`, returning them to the reserved state.
+///
+/// # Safety
+///
+/// The pages must be reserved. Their contents are lost, and they become inaccessible.
+pub unsafe fn decommit(addr: *mut c_void, size: usize) -> Result<()> {
+    if 0 != VirtualFree(addr, size, MEM_DECOMMIT) {
+        Ok(())
+    } else {
+        Err(MMapError::get())
+    }
+}
+
+/// Allocate a memory chunk with the given size, protection and mapping flags.
+///
+/// The chunk is aligned to the allocation granularity (see [`get_minimum_alignment`]), which
+/// saves the address requirements of [`aligned_allocate_chunk`] when that is enough.
+///
+/// # Safety
+///
+/// The returned memory must be deallocated with `deallocate_chunk`.
+///
+/// [`get_minimum_alignment`]: fn.get_minimum_alignment.html
+/// [`aligned_allocate_chunk`]: fn.aligned_allocate_chunk.html
+pub unsafe fn allocate_chunk(
+    size: usize, protection: BitFlags<Protection>,
+    flags: BitFlags<MapFlags>) -> Result<*mut c_void> {
+    let mem = VirtualAlloc2(
+        core::ptr::null_mut(), core::ptr::null_mut(),
+        size, allocation_type(flags)?, Protection::native_bits(protection),
+        core::ptr::null_mut(), 0);
+    if mem.is_null() { return Err(MMapError::get()); }
+    registry::register(mem as usize);
+    if flags.contains(MapFlags::Prefault) && !flags.contains(MapFlags::NoReserve) {
+        super::touch_pages(mem, size, get_page_size()?, protection);
+    }
+    Ok(mem)
+}
+
+unsafe fn aligned_virtual_alloc(
+    alignment: usize, size: usize, allocation_type: ULONG,
+    protection: BitFlags<Protection>) -> Result<*mut c_void> {
     let mut address_reqs: MEM_ADDRESS_REQUIREMENTS = core::mem::zeroed();
     address_reqs.alignment = alignment;
     let mut param: MEM_EXTENDED_PARAMETER = core::mem::zeroed();
@@ -220,18 +368,50 @@ pub unsafe fn aligned_allocate_chunk(
     param.value.pointer = to_void_p(&mut address_reqs);
     let mem = VirtualAlloc2(
         core::ptr::null_mut(), core::ptr::null_mut(),
-        size, MEM_COMMIT | MEM_RESERVE, make_protection_flag(protection),
+        size, allocation_type, Protection::native_bits(protection),
         to_void_p(&mut param), 1);
     if mem != core::ptr::null_mut() {
+        registry::register(mem as usize);
         Ok(mem)
     } else {
         Err(MMapError::get())
     }
 }
 
+/// Allocate a memory chunk at exactly `addr`, e.g. for a heap at a fixed base address.
+///
+/// The `addr` must be a multiple of [`get_minimum_alignment`]. If any part of
+/// `[addr, addr + size)` is already in use, this function fails with `InvalidArguments`.
+///
+/// # Safety
+///
+/// The returned memory must be deallocated with `deallocate_chunk`.
+///
+/// [`get_minimum_alignment`]: fn.get_minimum_alignment.html
+pub unsafe fn allocate_chunk_at(
+    addr: *mut c_void, size: usize, protection: BitFlags<Protection>) -> Result<*mut c_void> {
+    let mem = VirtualAlloc2(
+        core::ptr::null_mut(), addr,
+        size, MEM_COMMIT | MEM_RESERVE, Protection::native_bits(protection),
+        core::ptr::null_mut(), 0);
+    if mem != core::ptr::null_mut() {
+        registry::register(mem as usize);
+        Ok(mem)
+    } else {
+        match GetLastError() {
+            ERROR_INVALID_ADDRESS => Err(MMapError::InvalidArguments),
+            e => Err(MMapError::from_errno(e)),
+        }
+    }
+}
+
 /// Deallocate a memory chunk. If some memory address other than those returned by
 /// `aligned_allocate_chunk` is passed to this function, it will fail with `InvalidArguments`.
+///
+/// With the `chunk-registry` feature, this function fails with `NotAllocated` instead if `addr`
+/// is not the address of a live chunk from this module.
 pub unsafe fn deallocate_chunk(addr: *mut c_void, _size: usize) -> Result<()> {
+    registry::unregister(addr as usize)?;
     if 0 != VirtualFree(addr, 0, MEM_RELEASE) {
         Ok(())
     } else {
@@ -239,8 +419,131 @@ pub unsafe fn deallocate_chunk(addr: *mut c_void, _size: usize) -> Result<()> {
     }
 }
 
+/// Change the protection of the pages in `[addr, addr + size)`.
+///
+/// # Safety
+///
+/// The pages must be committed, and no reference into them may be used against the new
+/// protection.
+pub unsafe fn protect(
+    addr: *mut c_void, size: usize, protection: BitFlags<Protection>) -> Result<()> {
+    let mut old_protection: DWORD = 0;
+    if 0 != VirtualProtect(addr, size, Protection::native_bits(protection), &mut old_protection) {
+        Ok(())
+    } else {
+        Err(MMapError::get())
+    }
+}
+
+/// Give the system a hint about how the pages in `[addr, addr + size)` will be accessed.
+///
+/// Only `Advice::DontNeed` has an effect, via `DiscardVirtualMemory`; the pages stay committed.
+///
+/// # Safety
+///
+/// The pages must be committed. After `Advice::DontNeed`, their contents are undefined.
+pub unsafe fn advise(addr: *mut c_void, size: usize, advice: Advice) -> Result<()> {
+    match advice {
+        Advice::DontNeed => match DiscardVirtualMemory(addr, size) {
+            ERROR_SUCCESS => Ok(()),
+            e => Err(MMapError::from_errno(e)),
+        },
+        _ => Ok(()),
+    }
+}
+
+/// Query the protection of the pages in `[addr, addr + len)` with `VirtualQuery`.
+///
+/// If the range spans pages with different protections, the most restrictive protection is
+/// returned, i.e. the accesses allowed on every page; reserved pages count as `Protection::NONE`.
+/// If any part of the range is free, or `len` is zero, this function fails with
+/// `InvalidArguments`.
+pub fn query(addr: *mut c_void, len: usize) -> Result<BitFlags<Protection>> {
+    let end = match (addr as usize).checked_add(len) {
+        Some(end) if len != 0 => end,
+        _ => return Err(MMapError::InvalidArguments),
+    };
+    let mut protection = BitFlags::all();
+    let mut cursor = addr as usize;
+    while cursor < end {
+        let mut info: MEMORY_BASIC_INFORMATION = unsafe { core::mem::zeroed() };
+        let info_size = core::mem::size_of::<MEMORY_BASIC_INFORMATION>();
+        if 0 == unsafe { VirtualQuery(cursor as _, &mut info, info_size) } {
+            return Err(unsafe { MMapError::get() });
+        }
+        protection &= match info.State {
+            MEM_COMMIT => decode_protection_flag(info.Protect),
+            MEM_RESERVE => Protection::NONE,
+            _ => return Err(MMapError::InvalidArguments),
+        };
+        cursor = info.BaseAddress as usize + info.RegionSize;
+    }
+    Ok(protection)
+}
+
+/// Count the pages in `[addr, addr + size)` currently in the working set of the process, with
+/// `QueryWorkingSetEx`.
+pub fn resident_pages(addr: *mut c_void, size: usize) -> Result<usize> {
+    let page_size = get_page_size()?;
+    let pages = (size + page_size - 1) / page_size;
+    let mut info = [PSAPI_WORKING_SET_EX_INFORMATION {
+        virtual_address: core::ptr::null_mut(),
+        virtual_attributes: 0,
+    }; 512];
+    let mut resident = 0;
+    for first in (0..pages).step_by(info.len()) {
+        let count = (pages - first).min(info.len());
+        for (i, entry) in info[..count].iter_mut().enumerate() {
+            entry.virtual_address = (addr as usize + (first + i) * page_size) as PVOID;
+        }
+        let bytes = count * core::mem::size_of::<PSAPI_WORKING_SET_EX_INFORMATION>();
+        if 0 == unsafe {
+            K32QueryWorkingSetEx(GetCurrentProcess(), info.as_mut_ptr() as _, bytes as DWORD)
+        } {
+            return Err(unsafe { MMapError::get() });
+        }
+        resident += info[..count].iter().filter(|e| e.virtual_attributes & 1 != 0).count();
+    }
+    Ok(resident)
+}
+
+/// Lock the pages in `[addr, addr + size)` into the working set of the process, so that they are
+/// never paged out.
+///
+/// Fails with `LockLimit` if the minimum working set size is too small to hold the pages, see
+/// `SetProcessWorkingSetSize`.
+///
+/// # Safety
+///
+/// The pages must be committed.
+pub unsafe fn lock(addr: *mut c_void, size: usize) -> Result<()> {
+    if 0 != VirtualLock(addr, size) {
+        Ok(())
+    } else {
+        match GetLastError() {
+            ERROR_WORKING_SET_QUOTA => Err(MMapError::LockLimit),
+            e => Err(MMapError::from_errno(e)),
+        }
+    }
+}
+
+/// Unlock the pages in `[addr, addr + size)`, so that they may be paged out again.
+///
+/// # Safety
+///
+/// The pages must be committed.
+pub unsafe fn unlock(addr: *mut c_void, size: usize) -> Result<()> {
+    if 0 != VirtualUnlock(addr, size) {
+        Ok(())
+    } else {
+        Err(MMapError::get())
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    extern crate std;
+
     use super::Protection;
     use super::make_protection_flag;
 
@@ -249,6 +552,12 @@ mod tests {
     use super::PAGE_EXECUTE_READ;
     use super::PAGE_EXECUTE_READWRITE;
 
+    use super::{MapFlags, MMapError, allocation_type};
+    use super::{MEM_COMMIT, MEM_RESERVE};
+    use super::{ERROR_COMMITMENT_LIMIT, ERROR_INVALID_ADDRESS, ERROR_INVALID_PARAMETER};
+    use super::{ERROR_NOT_ENOUGH_MEMORY, ERROR_SUCCESS};
+    use enumflags2::BitFlags;
+
     #[test]
     fn test_make_protection_flag() {
         assert_eq!(make_protection_flag(Protection::NONE), PAGE_NOACCESS);
@@ -258,4 +567,84 @@ mod tests {
             make_protection_flag(Protection::Read | Protection::Write | Protection::Exec),
             PAGE_EXECUTE_READWRITE);
     }
+
+    #[test]
+    fn test_allocation_type() {
+        assert_eq!(allocation_type(BitFlags::empty()), Ok(MEM_COMMIT | MEM_RESERVE));
+        assert_eq!(allocation_type(MapFlags::NoReserve.into()), Ok(MEM_RESERVE));
+        assert_eq!(allocation_type(MapFlags::Shared.into()), Err(MMapError::InvalidArguments));
+    }
+
+    #[test]
+    fn test_from_errno() {
+        let from = MMapError::from_errno;
+        assert_eq!(from(ERROR_INVALID_PARAMETER), MMapError::InvalidArguments);
+        assert_eq!(from(ERROR_INVALID_ADDRESS), MMapError::InvalidArguments);
+        assert_eq!(from(ERROR_NOT_ENOUGH_MEMORY), MMapError::NoMemory);
+        assert_eq!(from(ERROR_COMMITMENT_LIMIT), MMapError::NoMemory);
+        assert_eq!(from(ERROR_SUCCESS), MMapError::NoError);
+        assert_eq!(from(42), MMapError::UnknownError(42));
+    }
+
+    #[test]
+    fn test_allocate_deallocate() {
+        use super::{aligned_allocate_chunk, deallocate_chunk, get_minimum_alignment};
+        let alignment = get_minimum_alignment().unwrap();
+        let protection = Protection::Read | Protection::Write;
+        let addr = unsafe {
+            aligned_allocate_chunk(alignment, alignment, protection, BitFlags::empty()).unwrap()
+        };
+        assert_eq!(addr as usize % alignment, 0);
+        unsafe { (addr as *mut u8).write(42) };
+        assert_eq!(unsafe { (addr as *mut u8).read() }, 42);
+        assert_eq!(unsafe { deallocate_chunk(addr, alignment) }, Ok(()));
+    }
+
+    #[test]
+    fn test_allocate_chunk_at() {
+        use super::{allocate_chunk_at, aligned_reserve_chunk, deallocate_chunk};
+        use super::get_minimum_alignment;
+        let alignment = get_minimum_alignment().unwrap();
+        let size = alignment * 4;
+        let reserved = unsafe { aligned_reserve_chunk(alignment, size).unwrap() };
+        let overlap = unsafe { (reserved as *mut u8).add(alignment * 2) as *mut _ };
+        let result = unsafe { allocate_chunk_at(overlap, size, Protection::Read.into()) };
+        assert_eq!(result, Err(MMapError::InvalidArguments));
+        assert_eq!(unsafe { deallocate_chunk(reserved, size) }, Ok(()));
+    }
+
+    #[test]
+    fn test_query() {
+        use super::{aligned_allocate_chunk, deallocate_chunk, get_minimum_alignment, query};
+        use super::decode_protection_flag;
+        let read = Protection::Read.into();
+        let rx = Protection::Read | Protection::Exec;
+        let rwx = Protection::Read | Protection::Write | Protection::Exec;
+        for &flags in &[Protection::NONE, read, rx, rwx] {
+            assert_eq!(decode_protection_flag(make_protection_flag(flags)), flags);
+        }
+        let alignment = get_minimum_alignment().unwrap();
+        let rw = Protection::Read | Protection::Write;
+        let addr = unsafe {
+            aligned_allocate_chunk(alignment, alignment, rw, BitFlags::empty()).unwrap()
+        };
+        assert_eq!(query(addr, alignment), Ok(rw));
+        assert_eq!(query(addr, 0), Err(MMapError::InvalidArguments));
+        assert_eq!(unsafe { deallocate_chunk(addr, alignment) }, Ok(()));
+    }
+
+    #[test]
+    fn test_get_page_size_concurrently() {
+        use super::get_page_size;
+        use std::vec::Vec;
+        let threads: Vec<_> = (0..8)
+            .map(|_| std::thread::spawn(|| {
+                (0..1000).map(|_| get_page_size().unwrap()).collect::<Vec<_>>()
+            }))
+            .collect();
+        let expected = get_page_size().unwrap();
+        for thread in threads {
+            assert!(thread.join().unwrap().iter().all(|size| *size == expected));
+        }
+    }
 }